@@ -0,0 +1,196 @@
+//! A minimal OpenID Connect provider for trying out SSO locally.
+//!
+//! ```text
+//! cargo run --example mock_oidc -- 127.0.0.1:4000
+//! ```
+//!
+//! and point the `[oidc]` section of the config at it:
+//!
+//! ```toml
+//! [oidc]
+//! issuer_url = "http://127.0.0.1:4000"
+//! client_id = "online-code-checker"
+//! client_secret = "mock-secret"
+//! redirect_url = "http://localhost:3000/oidc/callback"
+//! groups_claim = "groups"
+//! ```
+//!
+//! The authorization endpoint shows a form to pick the claims of the user
+//! that logs in. Scripts can skip it and post the form directly. ID tokens
+//! are signed with HS256 using the client secret, PKCE is not checked.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const CLIENT_ID: &str = "online-code-checker";
+const CLIENT_SECRET: &str = "mock-secret";
+
+#[derive(Clone)]
+struct Mock {
+    issuer: String,
+    /// Authorization code -> claims of the ID token it is exchanged for.
+    codes: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeForm {
+    redirect_uri: String,
+    state: String,
+    nonce: String,
+    sub: String,
+    #[serde(default)]
+    preferred_username: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    name: String,
+    /// Comma separated.
+    #[serde(default)]
+    groups: String,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    code: String,
+}
+
+async fn discovery(State(mock): State<Mock>) -> Json<Value> {
+    Json(json!({
+        "issuer": mock.issuer,
+        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+        "token_endpoint": format!("{}/token", mock.issuer),
+        "jwks_uri": format!("{}/jwks", mock.issuer),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["HS256"],
+    }))
+}
+
+async fn jwks() -> Json<Value> {
+    Json(json!({ "keys": [] }))
+}
+
+async fn authorize_page(Query(query): Query<AuthorizeQuery>) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<form method="post">
+  <input type="hidden" name="redirect_uri" value="{}">
+  <input type="hidden" name="state" value="{}">
+  <input type="hidden" name="nonce" value="{}">
+  <p>sub <input name="sub" value="student-1"></p>
+  <p>preferred_username <input name="preferred_username" value="student1"></p>
+  <p>email <input name="email" value="student1@example.ac.jp"></p>
+  <p>name <input name="name" value="Student One"></p>
+  <p>groups <input name="groups" value=""></p>
+  <input type="submit" value="log in">
+</form>"#,
+        query.redirect_uri, query.state, query.nonce
+    ))
+}
+
+async fn authorize(State(mock): State<Mock>, Form(form): Form<AuthorizeForm>) -> Redirect {
+    let mut claims = json!({
+        "iss": mock.issuer,
+        "sub": form.sub,
+        "aud": CLIENT_ID,
+        "nonce": form.nonce,
+        "groups": form
+            .groups
+            .split(',')
+            .map(str::trim)
+            .filter(|group| !group.is_empty())
+            .collect::<Vec<_>>(),
+    });
+    for (name, value) in [
+        ("preferred_username", form.preferred_username),
+        ("email", form.email),
+        ("name", form.name),
+    ] {
+        if !value.is_empty() {
+            claims[name] = Value::String(value);
+        }
+    }
+    let code: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    mock.codes.lock().unwrap().insert(code.clone(), claims);
+    Redirect::to(&format!(
+        "{}?code={}&state={}",
+        form.redirect_uri, code, form.state
+    ))
+}
+
+async fn token(State(mock): State<Mock>, Form(form): Form<TokenForm>) -> impl IntoResponse {
+    let Some(mut claims) = mock.codes.lock().unwrap().remove(&form.code) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        );
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    claims["iat"] = json!(now);
+    claims["exp"] = json!(now + 300);
+    let id_token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+    )
+    .unwrap();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "access_token": "mock-access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token,
+        })),
+    )
+}
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:4000".to_string());
+    let mock = Mock {
+        issuer: format!("http://{}", address),
+        codes: Arc::default(),
+    };
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize_page))
+        .route("/authorize", post(authorize))
+        .route("/token", post(token))
+        .with_state(mock);
+    let listener = tokio::net::TcpListener::bind(&address).await.unwrap();
+    println!("mock OIDC provider at http://{}", address);
+    axum::serve(listener, app).await.unwrap();
+}
@@ -0,0 +1,27 @@
+-- Add migration script here
+ALTER TABLE user
+ADD COLUMN role TEXT NOT NULL DEFAULT 'student';
+UPDATE user SET role = 'admin' WHERE super_user = 1;
+
+CREATE TABLE groups (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    owner_id INTEGER NOT NULL,
+    created_at datetime,
+    FOREIGN KEY (owner_id) REFERENCES user(id)
+);
+
+CREATE TABLE group_members (
+    group_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    PRIMARY KEY (group_id, user_id),
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE
+);
+
+CREATE TABLE group_problems (
+    group_id INTEGER NOT NULL,
+    problem_id TEXT NOT NULL,
+    PRIMARY KEY (group_id, problem_id),
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+);
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE user
+ADD COLUMN display_name TEXT;
+ALTER TABLE user
+ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT 0;
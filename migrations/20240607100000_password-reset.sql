@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE password_reset_tokens (
+    token_hash TEXT NOT NULL PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    created_by INTEGER NOT NULL,
+    created_at datetime NOT NULL,
+    expires_at datetime NOT NULL,
+    used_at datetime,
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES user(id)
+);
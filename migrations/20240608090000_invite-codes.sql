@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE invite_codes (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    group_id INTEGER NOT NULL,
+    max_uses INTEGER NOT NULL,
+    uses INTEGER NOT NULL DEFAULT 0,
+    expires_at datetime,
+    created_by INTEGER NOT NULL,
+    created_at datetime,
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES user(id)
+);
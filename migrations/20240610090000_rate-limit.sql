@@ -0,0 +1,19 @@
+-- Add migration script here
+CREATE TABLE login_attempts (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    username TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    success BOOLEAN NOT NULL,
+    created_at datetime NOT NULL
+);
+CREATE INDEX login_attempts_username ON login_attempts (username, created_at);
+CREATE INDEX login_attempts_ip ON login_attempts (ip, created_at);
+
+CREATE TABLE audit_log (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    event TEXT NOT NULL,
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at datetime NOT NULL
+);
@@ -0,0 +1,9 @@
+-- The admin seeded by 20240514143758_user-info.sql has a password that is
+-- published with the source. An empty hash never verifies, so the account is
+-- locked until the server sets it up again on start, from
+-- server.admin_password or through a one-time setup link. Its next password
+-- has to be changed on the first login.
+UPDATE user
+SET password = '',
+    must_change_password = 1
+WHERE password = '$argon2id$v=19$m=19456,t=2,p=1$W37Sp/YgY00ICzwMBkby4w$HY7qML/igniHkTXawKWwzjkDJ00AzPzRljpG4eWoMNI';
@@ -0,0 +1,8 @@
+-- Which worker is judging a pending submission. A claim older than the
+-- lease is taken over by the next worker asking for a job.
+CREATE TABLE judge_claims (
+    solution_id INTEGER NOT NULL PRIMARY KEY,
+    worker TEXT NOT NULL,
+    claimed_at datetime NOT NULL,
+    FOREIGN KEY (solution_id) REFERENCES solutions(id) ON DELETE CASCADE
+);
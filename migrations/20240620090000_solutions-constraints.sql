@@ -0,0 +1,36 @@
+-- Statuses were once compared case-insensitively, so older rows may be
+-- spelled differently. Anything that is not a known verdict is judged again.
+UPDATE solutions
+SET status = CASE upper(trim(status))
+    WHEN 'AC' THEN 'AC'
+    WHEN 'WA' THEN 'WA'
+    ELSE 'Pending'
+END
+WHERE status IS NULL OR status NOT IN ('Pending', 'AC', 'WA');
+
+-- SQLite cannot add constraints to an existing table, so it is rebuilt.
+-- Dropping the old one also drops its judge claims, which only hold for a
+-- lease anyway. Submissions of users that no longer exist are not copied.
+CREATE TABLE solutions_new (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    content TEXT,
+    status TEXT NOT NULL DEFAULT 'Pending' CHECK (status IN ('Pending', 'AC', 'WA')),
+    userid INTEGER NOT NULL,
+    problem_id TEXT NOT NULL,
+    stdout TEXT,
+    stderr TEXT,
+    created_at datetime,
+    executed_at datetime,
+    FOREIGN KEY (userid) REFERENCES user(id) ON DELETE CASCADE
+);
+INSERT INTO solutions_new (id, content, status, userid, problem_id, stdout, stderr, created_at, executed_at)
+SELECT id, content, status, userid, problem_id, stdout, stderr, created_at, executed_at
+FROM solutions
+WHERE userid IN (SELECT id FROM user);
+DROP TABLE solutions;
+ALTER TABLE solutions_new RENAME TO solutions;
+
+-- The badge and status pages look up a user's submissions for a problem,
+-- the judge looks for pending ones.
+CREATE INDEX solutions_user_problem ON solutions (userid, problem_id);
+CREATE INDEX solutions_status ON solutions (status);
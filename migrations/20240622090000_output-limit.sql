@@ -0,0 +1,28 @@
+-- Output limit exceeded. The table is rebuilt for the new CHECK constraint
+-- like in 20240620090000_solutions-constraints.sql. Dropping it would take
+-- the judge claims along, so those are put back after.
+CREATE TEMP TABLE saved_judge_claims AS SELECT * FROM judge_claims;
+
+CREATE TABLE solutions_new (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    content TEXT,
+    status TEXT NOT NULL DEFAULT 'Pending' CHECK (status IN ('Pending', 'AC', 'WA', 'OLE')),
+    userid INTEGER NOT NULL,
+    problem_id TEXT NOT NULL,
+    stdout TEXT,
+    stderr TEXT,
+    created_at datetime,
+    executed_at datetime,
+    FOREIGN KEY (userid) REFERENCES user(id) ON DELETE CASCADE
+);
+INSERT INTO solutions_new (id, content, status, userid, problem_id, stdout, stderr, created_at, executed_at)
+SELECT id, content, status, userid, problem_id, stdout, stderr, created_at, executed_at
+FROM solutions;
+DROP TABLE solutions;
+ALTER TABLE solutions_new RENAME TO solutions;
+
+CREATE INDEX solutions_user_problem ON solutions (userid, problem_id);
+CREATE INDEX solutions_status ON solutions (status);
+
+INSERT INTO judge_claims SELECT * FROM saved_judge_claims;
+DROP TABLE saved_judge_claims;
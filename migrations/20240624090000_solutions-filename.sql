@@ -0,0 +1,3 @@
+-- Name of the uploaded file, if the source was not typed in.
+ALTER TABLE solutions
+ADD COLUMN filename TEXT;
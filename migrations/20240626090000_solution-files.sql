@@ -0,0 +1,9 @@
+-- Sources of submissions to problems with several files. Their
+-- `solutions.content` is NULL, single file submissions keep using it.
+CREATE TABLE solution_files (
+    solution_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (solution_id, name),
+    FOREIGN KEY (solution_id) REFERENCES solutions(id) ON DELETE CASCADE
+);
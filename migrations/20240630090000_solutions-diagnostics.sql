@@ -0,0 +1,3 @@
+-- Compiler warnings and sanitizer reports, shown apart from the verdict.
+ALTER TABLE solutions
+ADD COLUMN diagnostics TEXT;
@@ -0,0 +1,5 @@
+-- Platforms sharing an issuer are told apart by their client id.
+ALTER TABLE lti_nonces
+ADD COLUMN client_id TEXT;
+ALTER TABLE lti_line_items
+ADD COLUMN client_id TEXT;
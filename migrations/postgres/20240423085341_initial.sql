@@ -0,0 +1,8 @@
+-- PostgreSQL versions of the migrations one directory up, with the same
+-- versions so both databases end up with the same tables. SQLite INTEGER
+-- columns are BIGINT here, BOOLEAN columns are real booleans and datetime
+-- columns are timestamptz.
+CREATE TABLE tasks (
+    id BIGSERIAL PRIMARY KEY,
+    content TEXT
+);
@@ -0,0 +1,5 @@
+CREATE TABLE "user" (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    password TEXT NOT NULL
+);
@@ -0,0 +1,10 @@
+ALTER TABLE "user"
+ADD CONSTRAINT user_username_key UNIQUE (username);
+ALTER TABLE "user"
+ADD COLUMN super_user BOOLEAN DEFAULT false;
+INSERT INTO "user" (username, password, super_user)
+VALUES (
+        'admin',
+        '$argon2id$v=19$m=19456,t=2,p=1$W37Sp/YgY00ICzwMBkby4w$HY7qML/igniHkTXawKWwzjkDJ00AzPzRljpG4eWoMNI',
+        true
+    );
@@ -0,0 +1,7 @@
+CREATE TABLE solutions (
+    id BIGSERIAL PRIMARY KEY,
+    content TEXT,
+    status TEXT,
+    userid BIGINT NOT NULL,
+    FOREIGN KEY (userid) REFERENCES "user"(id)
+);
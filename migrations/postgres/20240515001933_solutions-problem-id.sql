@@ -0,0 +1,2 @@
+ALTER TABLE solutions
+ADD COLUMN problem_id TEXT NOT NULL;
@@ -0,0 +1,4 @@
+ALTER TABLE solutions
+ADD COLUMN stdout TEXT;
+ALTER TABLE solutions
+ADD COLUMN stderr TEXT;
@@ -0,0 +1,9 @@
+ALTER TABLE solutions
+ADD COLUMN created_at timestamptz;
+UPDATE solutions SET created_at = CURRENT_TIMESTAMP;
+ALTER TABLE solutions
+ADD COLUMN executed_at timestamptz;
+
+ALTER TABLE "user"
+ADD COLUMN created_at timestamptz;
+UPDATE "user" SET created_at = CURRENT_TIMESTAMP;
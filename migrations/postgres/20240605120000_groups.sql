@@ -0,0 +1,26 @@
+ALTER TABLE "user"
+ADD COLUMN role TEXT NOT NULL DEFAULT 'student';
+UPDATE "user" SET role = 'admin' WHERE super_user;
+
+CREATE TABLE groups (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    owner_id BIGINT NOT NULL,
+    created_at timestamptz,
+    FOREIGN KEY (owner_id) REFERENCES "user"(id)
+);
+
+CREATE TABLE group_members (
+    group_id BIGINT NOT NULL,
+    user_id BIGINT NOT NULL,
+    PRIMARY KEY (group_id, user_id),
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES "user"(id) ON DELETE CASCADE
+);
+
+CREATE TABLE group_problems (
+    group_id BIGINT NOT NULL,
+    problem_id TEXT NOT NULL,
+    PRIMARY KEY (group_id, problem_id),
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+);
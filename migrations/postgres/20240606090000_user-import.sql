@@ -0,0 +1,4 @@
+ALTER TABLE "user"
+ADD COLUMN display_name TEXT;
+ALTER TABLE "user"
+ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT false;
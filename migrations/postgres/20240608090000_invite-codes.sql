@@ -0,0 +1,12 @@
+CREATE TABLE invite_codes (
+    id BIGSERIAL PRIMARY KEY,
+    code TEXT NOT NULL UNIQUE,
+    group_id BIGINT NOT NULL,
+    max_uses BIGINT NOT NULL,
+    uses BIGINT NOT NULL DEFAULT 0,
+    expires_at timestamptz,
+    created_by BIGINT NOT NULL,
+    created_at timestamptz,
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE,
+    FOREIGN KEY (created_by) REFERENCES "user"(id)
+);
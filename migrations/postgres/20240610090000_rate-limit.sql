@@ -0,0 +1,18 @@
+CREATE TABLE login_attempts (
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip TEXT NOT NULL,
+    success BOOLEAN NOT NULL,
+    created_at timestamptz NOT NULL
+);
+CREATE INDEX login_attempts_username ON login_attempts (username, created_at);
+CREATE INDEX login_attempts_ip ON login_attempts (ip, created_at);
+
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    event TEXT NOT NULL,
+    username TEXT,
+    ip TEXT,
+    detail TEXT,
+    created_at timestamptz NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE user_identities (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id BIGINT NOT NULL,
+    email TEXT,
+    created_at timestamptz NOT NULL,
+    PRIMARY KEY (issuer, subject),
+    FOREIGN KEY (user_id) REFERENCES "user"(id) ON DELETE CASCADE
+);
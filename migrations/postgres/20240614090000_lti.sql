@@ -0,0 +1,24 @@
+CREATE TABLE lti_nonces (
+    state TEXT NOT NULL PRIMARY KEY,
+    nonce TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    created_at timestamptz NOT NULL
+);
+
+CREATE TABLE lti_contexts (
+    issuer TEXT NOT NULL,
+    context_id TEXT NOT NULL,
+    group_id BIGINT NOT NULL,
+    PRIMARY KEY (issuer, context_id),
+    FOREIGN KEY (group_id) REFERENCES groups(id) ON DELETE CASCADE
+);
+
+CREATE TABLE lti_line_items (
+    user_id BIGINT NOT NULL,
+    problem_id TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    lineitem TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    PRIMARY KEY (user_id, problem_id, lineitem),
+    FOREIGN KEY (user_id) REFERENCES "user"(id) ON DELETE CASCADE
+);
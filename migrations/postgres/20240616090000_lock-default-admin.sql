@@ -0,0 +1,5 @@
+-- See the SQLite version.
+UPDATE "user"
+SET password = '',
+    must_change_password = true
+WHERE password = '$argon2id$v=19$m=19456,t=2,p=1$W37Sp/YgY00ICzwMBkby4w$HY7qML/igniHkTXawKWwzjkDJ00AzPzRljpG4eWoMNI';
@@ -0,0 +1,6 @@
+CREATE TABLE judge_claims (
+    solution_id BIGINT NOT NULL PRIMARY KEY,
+    worker TEXT NOT NULL,
+    claimed_at timestamptz NOT NULL,
+    FOREIGN KEY (solution_id) REFERENCES solutions(id) ON DELETE CASCADE
+);
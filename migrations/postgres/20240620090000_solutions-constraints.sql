@@ -0,0 +1,17 @@
+UPDATE solutions
+SET status = CASE upper(trim(status))
+    WHEN 'AC' THEN 'AC'
+    WHEN 'WA' THEN 'WA'
+    ELSE 'Pending'
+END
+WHERE status IS NULL OR status NOT IN ('Pending', 'AC', 'WA');
+
+ALTER TABLE solutions
+ALTER COLUMN status SET DEFAULT 'Pending',
+ALTER COLUMN status SET NOT NULL,
+ADD CONSTRAINT solutions_status_check CHECK (status IN ('Pending', 'AC', 'WA')),
+DROP CONSTRAINT solutions_userid_fkey,
+ADD CONSTRAINT solutions_userid_fkey FOREIGN KEY (userid) REFERENCES "user"(id) ON DELETE CASCADE;
+
+CREATE INDEX solutions_user_problem ON solutions (userid, problem_id);
+CREATE INDEX solutions_status ON solutions (status);
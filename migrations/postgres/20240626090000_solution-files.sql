@@ -0,0 +1,7 @@
+CREATE TABLE solution_files (
+    solution_id BIGINT NOT NULL,
+    name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    PRIMARY KEY (solution_id, name),
+    FOREIGN KEY (solution_id) REFERENCES solutions(id) ON DELETE CASCADE
+);
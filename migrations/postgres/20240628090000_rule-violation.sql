@@ -0,0 +1,3 @@
+ALTER TABLE solutions
+DROP CONSTRAINT solutions_status_check,
+ADD CONSTRAINT solutions_status_check CHECK (status IN ('Pending', 'AC', 'WA', 'OLE', 'RV'));
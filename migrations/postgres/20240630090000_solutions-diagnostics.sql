@@ -0,0 +1,2 @@
+ALTER TABLE solutions
+ADD COLUMN diagnostics TEXT;
@@ -0,0 +1,4 @@
+ALTER TABLE lti_nonces
+ADD COLUMN client_id TEXT;
+ALTER TABLE lti_line_items
+ADD COLUMN client_id TEXT;
@@ -0,0 +1,353 @@
+use std::io;
+
+use axum::body::Bytes;
+use futures::{channel::mpsc, SinkExt, Stream, TryStreamExt};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::error;
+
+use crate::{
+    db::{self, with_pool, Db},
+    repository::Status,
+};
+
+/// Which submissions of a user for a problem end up in the archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    All,
+    Latest,
+    /// The latest accepted one, or the latest judged one if none was.
+    Best,
+}
+
+impl Selection {
+    pub fn parse(value: &str) -> Option<Selection> {
+        match value {
+            "all" => Some(Selection::All),
+            "latest" => Some(Selection::Latest),
+            "best" => Some(Selection::Best),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Selection::All => "all",
+            Selection::Latest => "latest",
+            Selection::Best => "best",
+        }
+    }
+}
+
+/// A submission of one file, or one file of a submission of several.
+#[derive(sqlx::FromRow)]
+struct ArchivedSolution {
+    id: i64,
+    problem_id: String,
+    status: Status,
+    content: Option<String>,
+    /// Set for the files of submissions of several files.
+    file_name: Option<String>,
+    created_at: Option<OffsetDateTime>,
+    executed_at: Option<OffsetDateTime>,
+    username: String,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    id: i64,
+    username: String,
+    problem_id: String,
+    status: Status,
+    created_at: Option<String>,
+    executed_at: Option<String>,
+    path: String,
+    size: usize,
+}
+
+/// Keeps user names and problem ids from escaping their directory.
+fn path_component(raw: &str) -> String {
+    let name: String = raw
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "_".to_string(),
+        name => name.to_string(),
+    }
+}
+
+fn mtime(timestamp: Option<OffsetDateTime>) -> u64 {
+    timestamp
+        .map(|timestamp| timestamp.unix_timestamp().max(0) as u64)
+        .unwrap_or(0)
+}
+
+/// A header, the data and the padding up to the next 512 byte block, with a
+/// GNU long name record first for paths over 100 bytes.
+fn tar_entry(path: &str, data: &[u8], mtime: u64) -> io::Result<Bytes> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(&mut header, path, data)?;
+    // Taken out before the builder is dropped, which ends the archive.
+    Ok(Bytes::from(std::mem::take(builder.get_mut())))
+}
+
+async fn write_archive(
+    db: &Db,
+    selection: Selection,
+    group_id: Option<i64>,
+    tx: &mut mpsc::Sender<io::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    let selection = selection.as_str();
+    let mut manifest: Vec<ManifestEntry> = Vec::new();
+    // Only the metadata stays around for the manifest, the code goes out as
+    // soon as it is read. The files of submissions come with them, as the
+    // rows are streamed over the only connection of in-memory databases.
+    let mut rows = with_pool!(db, pool => sqlx::query_as::<_, ArchivedSolution>(
+        r#"select s.id, s.problem_id, s.status, coalesce(s.content, f.content) as content, f.name as file_name, s.created_at, s.executed_at, u.username
+        from (select *, row_number() over (partition by userid, problem_id order by case when $1 = 'best' then case status when 'AC' then 0 when 'Pending' then 2 else 1 end else 0 end, id desc) as rank from solutions) s
+        join "user" u on u.id = s.userid
+        left join solution_files f on s.content is null and f.solution_id = s.id
+        where ($1 = 'all' or s.rank = 1) and ($2 is null or s.userid in (select user_id from group_members where group_id = $2))
+        order by s.id, f.name"#,
+    )
+    .bind(selection)
+    .bind(group_id)
+    .fetch(pool));
+    while let Some(row) = rows.try_next().await? {
+        let path = format!(
+            "{}/{}/{}_{}",
+            path_component(&row.username),
+            path_component(&row.problem_id),
+            row.id,
+            path_component(row.status.as_str())
+        );
+        let mtime = mtime(row.created_at);
+        if manifest.last().map(|entry| entry.id) != Some(row.id) {
+            manifest.push(ManifestEntry {
+                id: row.id,
+                username: row.username,
+                problem_id: row.problem_id,
+                status: row.status,
+                created_at: row.created_at.map(db::format_timestamp),
+                executed_at: row.executed_at.map(db::format_timestamp),
+                // Submissions of several files get a directory.
+                path: match (&row.file_name, &row.content) {
+                    (None, Some(_)) => format!("{}.c", path),
+                    _ => format!("{}/", path),
+                },
+                size: 0,
+            });
+        }
+        let entry = manifest.last_mut().expect("pushed above");
+        let Some(content) = row.content else {
+            continue;
+        };
+        let path = match row.file_name {
+            Some(name) => format!("{}/{}", path, path_component(&name)),
+            None => entry.path.clone(),
+        };
+        tx.send(tar_entry(&path, content.as_bytes(), mtime)).await?;
+        entry.size += content.len();
+    }
+
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp().max(0) as u64;
+    tx.send(tar_entry("manifest.json", &manifest, now)).await?;
+    // Two empty blocks end the archive.
+    tx.send(Ok(Bytes::from(vec![0; 1024]))).await?;
+    Ok(())
+}
+
+/// A tar archive of the selected submissions as
+/// `username/problem_id/<id>_<status>.c`, or a directory of that name
+/// without `.c` for submissions of several files, followed by
+/// `manifest.json`.
+/// Built while it is being sent, one submission at a time.
+pub fn submissions_tar(
+    db: Db,
+    selection: Selection,
+    group_id: Option<i64>,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    let (mut tx, rx) = mpsc::channel(8);
+    tokio::spawn(async move {
+        if let Err(e) = write_archive(&db, selection, group_id, &mut tx).await {
+            // The headers are already out, cutting the body short is all
+            // that is left to tell the client.
+            error!("building the submissions archive failed: {:#}", e);
+            let _ = tx.send(Err(io::Error::other(e.to_string()))).await;
+        }
+    });
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::{
+        db::tests::{each_database, insert_solution},
+        repository::{insert_solution_files, insert_user, NewUserRecord, SourceFile},
+    };
+
+    fn new_user(username: &str) -> NewUserRecord {
+        NewUserRecord {
+            username: username.to_string(),
+            password_hash: "hash".to_string(),
+            display_name: None,
+            must_change_password: false,
+        }
+    }
+
+    /// The paths and contents of the entries in `archive`.
+    fn entries(archive: &[u8]) -> Vec<(String, String)> {
+        let mut archive = tar::Archive::new(archive);
+        let mut entries = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = String::new();
+            entry.read_to_string(&mut data).unwrap();
+            entries.push((path, data));
+        }
+        entries
+    }
+
+    async fn read_archive(db: &Db, selection: Selection) -> Vec<(String, String)> {
+        let chunks: Vec<Bytes> = submissions_tar(db.clone(), selection, None)
+            .try_collect()
+            .await
+            .unwrap();
+        entries(&chunks.concat())
+    }
+
+    #[test]
+    fn long_paths() {
+        let long = format!(
+            "{}/{}/12_AC/list.c",
+            "student".repeat(10),
+            "problem".repeat(10)
+        );
+        assert!(long.len() > 100);
+        let mut archive = Vec::new();
+        for (path, data) in [(long.as_str(), "int x;\n"), ("manifest.json", "[]")] {
+            archive.extend_from_slice(&tar_entry(path, data.as_bytes(), 0).unwrap());
+        }
+        archive.extend_from_slice(&[0; 1024]);
+
+        assert_eq!(
+            entries(&archive),
+            [
+                (long, "int x;\n".to_string()),
+                ("manifest.json".to_string(), "[]".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn selections() {
+        each_database(|db| async move {
+            let alice = insert_user(&db, new_user("alice")).await.unwrap();
+            let bob = insert_user(&db, new_user("bob")).await.unwrap();
+            let accepted = insert_solution(&db, alice, "hello", "AC").await;
+            let failed = insert_solution(&db, alice, "hello", "WA").await;
+            let judged = insert_solution(&db, bob, "hello", "WA").await;
+            let pending = insert_solution(&db, bob, "hello", "Pending").await;
+
+            for (selection, ids) in [
+                (Selection::All, vec![accepted, failed, judged, pending]),
+                (Selection::Latest, vec![failed, pending]),
+                // Accepted over anything later, judged over pending.
+                (Selection::Best, vec![accepted, judged]),
+            ] {
+                let entries = read_archive(&db, selection).await;
+                let ((name, manifest), sources) = entries.split_last().unwrap();
+                assert_eq!(name, "manifest.json");
+                let manifest: Vec<serde_json::Value> = serde_json::from_str(manifest).unwrap();
+                let manifest_ids: Vec<i64> = manifest
+                    .iter()
+                    .map(|entry| entry["id"].as_i64().unwrap())
+                    .collect();
+                assert_eq!(manifest_ids, ids);
+                let paths: Vec<&str> = sources.iter().map(|(path, _)| path.as_str()).collect();
+                let manifest_paths: Vec<&str> = manifest
+                    .iter()
+                    .map(|entry| entry["path"].as_str().unwrap())
+                    .collect();
+                assert_eq!(paths, manifest_paths);
+            }
+
+            let entries = read_archive(&db, Selection::Best).await;
+            assert_eq!(
+                entries[0],
+                (
+                    format!("alice/hello/{}_AC.c", accepted),
+                    "int main(void) { return 0; }".to_string()
+                )
+            );
+            assert_eq!(entries[1].0, format!("bob/hello/{}_WA.c", judged));
+            let manifest: serde_json::Value = serde_json::from_str(&entries[2].1).unwrap();
+            assert_eq!(manifest[0]["username"], "alice");
+            assert_eq!(manifest[0]["problem_id"], "hello");
+            assert_eq!(manifest[0]["status"], "AC");
+            assert_eq!(manifest[0]["size"], 28);
+            assert!(manifest[0]["created_at"].is_string());
+            assert!(manifest[0]["executed_at"].is_null());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn several_files() {
+        each_database(|db| async move {
+            let alice = insert_user(&db, new_user("alice")).await.unwrap();
+            let single = insert_solution(&db, alice, "hello", "WA").await;
+            let files = [
+                SourceFile {
+                    name: "main.c".to_string(),
+                    content: "#include \"list.h\"\n".to_string(),
+                },
+                SourceFile {
+                    name: "list.h".to_string(),
+                    content: "struct list;\n".to_string(),
+                },
+            ];
+            let several = insert_solution_files(&db, alice, "list", &files, None)
+                .await
+                .unwrap();
+
+            let entries = read_archive(&db, Selection::All).await;
+            let paths: Vec<&str> = entries.iter().map(|(path, _)| path.as_str()).collect();
+            assert_eq!(
+                paths,
+                [
+                    format!("alice/hello/{}_WA.c", single),
+                    format!("alice/list/{}_Pending/list.h", several),
+                    format!("alice/list/{}_Pending/main.c", several),
+                    "manifest.json".to_string(),
+                ]
+            );
+            assert_eq!(entries[1].1, "struct list;\n");
+            let manifest: serde_json::Value = serde_json::from_str(&entries[3].1).unwrap();
+            assert_eq!(manifest.as_array().unwrap().len(), 2);
+            assert_eq!(manifest[1]["id"], several);
+            assert_eq!(
+                manifest[1]["path"],
+                format!("alice/list/{}_Pending/", several)
+            );
+            assert_eq!(manifest[1]["size"], 31);
+        })
+        .await;
+    }
+}
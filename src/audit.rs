@@ -0,0 +1,25 @@
+use tracing::warn;
+
+use crate::db::{with_pool, Db};
+
+/// Appends a security relevant event to the `audit_log` table.
+pub async fn record(
+    db: &Db,
+    event: &str,
+    username: Option<&str>,
+    ip: Option<&str>,
+    detail: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    warn!(event, username, ip, detail, "audit");
+    with_pool!(db, pool => sqlx::query(
+        "insert into audit_log (event,username,ip,detail,created_at) values ($1,$2,$3,$4,current_timestamp)",
+    )
+    .bind(event)
+    .bind(username)
+    .bind(ip)
+    .bind(detail)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    Ok(())
+}
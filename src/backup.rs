@@ -0,0 +1,308 @@
+//! Copies of the SQLite database, and pruning of output nobody looks at any
+//! more.
+
+use std::{
+    ffi::{c_int, CStr, CString},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr::{self, NonNull},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use libsqlite3_sys::{
+    sqlite3, sqlite3_backup_finish, sqlite3_backup_init, sqlite3_backup_step, sqlite3_close,
+    sqlite3_errmsg, sqlite3_open_v2, SQLITE_BUSY, SQLITE_DONE, SQLITE_LOCKED, SQLITE_OK,
+    SQLITE_OPEN_CREATE, SQLITE_OPEN_READONLY, SQLITE_OPEN_READWRITE,
+};
+use time::{macros::format_description, OffsetDateTime};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info};
+
+use crate::{
+    config::BackupConfiguration,
+    db::{self, with_pool, Db},
+    repository::Status,
+};
+
+/// Backups in `backup.dir` are named `database-<UTC time>.sqlite`, which
+/// sorts them by age.
+const BACKUP_PREFIX: &str = "database-";
+const BACKUP_SUFFIX: &str = ".sqlite";
+
+/// A database file opened apart from the pool, closed when dropped.
+struct RawDatabase(NonNull<sqlite3>);
+
+impl RawDatabase {
+    fn open(path: &Path, flags: c_int) -> Result<Self> {
+        let name = CString::new(path.as_os_str().as_bytes())?;
+        let mut handle = ptr::null_mut();
+        let result = unsafe { sqlite3_open_v2(name.as_ptr(), &mut handle, flags, ptr::null()) };
+        // A handle is returned even on failure, for the message.
+        let database = NonNull::new(handle).map(RawDatabase);
+        if result != SQLITE_OK {
+            let message = database.as_ref().map_or_else(
+                || "out of memory".to_string(),
+                |database| error_message(database.0),
+            );
+            bail!("open {}: {}", path.display(), message);
+        }
+        database.context("out of memory")
+    }
+}
+
+impl Drop for RawDatabase {
+    fn drop(&mut self) {
+        unsafe { sqlite3_close(self.0.as_ptr()) };
+    }
+}
+
+fn error_message(database: NonNull<sqlite3>) -> String {
+    unsafe { CStr::from_ptr(sqlite3_errmsg(database.as_ptr())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Copies `from` into `to` with SQLite's online backup API. Other
+/// connections may keep using `from`, the copy is consistent anyway.
+fn copy(from: NonNull<sqlite3>, to: NonNull<sqlite3>) -> Result<()> {
+    let main = c"main".as_ptr();
+    let backup = unsafe { sqlite3_backup_init(to.as_ptr(), main, from.as_ptr(), main) };
+    if backup.is_null() {
+        bail!("{}", error_message(to));
+    }
+    // Every page in one step, so writers in between cannot make it start
+    // over. A writer holding the database only makes it wait.
+    let mut step = SQLITE_BUSY;
+    for _ in 0..100 {
+        step = unsafe { sqlite3_backup_step(backup, -1) };
+        if step != SQLITE_BUSY && step != SQLITE_LOCKED {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let finish = unsafe { sqlite3_backup_finish(backup) };
+    match (step, finish) {
+        (SQLITE_DONE, SQLITE_OK) => Ok(()),
+        (SQLITE_BUSY | SQLITE_LOCKED, _) => bail!("the database stayed busy"),
+        _ => bail!("{}", error_message(to)),
+    }
+}
+
+fn sqlite_pool(db: &Db) -> Result<&sqlx::SqlitePool> {
+    match db {
+        Db::Sqlite(pool) => Ok(pool),
+        Db::Postgres(_) => bail!("only SQLite databases are backed up here, use pg_dump"),
+    }
+}
+
+/// Writes a copy of the database to `target`, safe while the server runs.
+pub async fn backup(db: &Db, target: &Path) -> Result<()> {
+    let pool = sqlite_pool(db)?;
+    // Written under another name first, so `target` is never half done.
+    let partial = target.with_extension("partial");
+    let mut connection = pool.acquire().await?;
+    {
+        let mut handle = connection.lock_handle().await?;
+        let source = handle.as_raw_handle();
+        tokio::task::block_in_place(|| {
+            let _ = std::fs::remove_file(&partial);
+            let target = RawDatabase::open(&partial, SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)?;
+            copy(source, target.0)
+        })
+        .with_context(|| format!("back up to {}", target.display()))?;
+    }
+    tokio::fs::rename(&partial, target)
+        .await
+        .with_context(|| format!("rename {}", partial.display()))?;
+    Ok(())
+}
+
+/// Replaces everything in the database with the contents of `source`, a file
+/// written by [`backup`]. The server should not be running.
+pub async fn restore(db: &Db, source: &Path) -> Result<()> {
+    let pool = sqlite_pool(db)?;
+    if !source.is_file() {
+        bail!("{} does not exist", source.display());
+    }
+    let mut connection = pool.acquire().await?;
+    let mut handle = connection.lock_handle().await?;
+    let target = handle.as_raw_handle();
+    tokio::task::block_in_place(|| {
+        let source = RawDatabase::open(source, SQLITE_OPEN_READONLY)?;
+        copy(source.0, target)
+    })
+    .with_context(|| format!("restore from {}", source.display()))
+}
+
+/// Backs up into `backup.dir` and deletes the backups beyond `backup.keep`.
+pub async fn backup_to_dir(db: &Db, config: &BackupConfiguration) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(&config.dir)
+        .await
+        .with_context(|| format!("create {}", config.dir.display()))?;
+    let time = OffsetDateTime::now_utc().format(format_description!(
+        "[year][month][day]-[hour][minute][second]"
+    ))?;
+    let path = config
+        .dir
+        .join(format!("{}{}{}", BACKUP_PREFIX, time, BACKUP_SUFFIX));
+    backup(db, &path).await?;
+
+    let mut backups = Vec::new();
+    let mut entries = tokio::fs::read_dir(&config.dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(BACKUP_PREFIX) && name.ends_with(BACKUP_SUFFIX) {
+            backups.push(entry.path());
+        }
+    }
+    backups.sort();
+    let excess = backups.len().saturating_sub(config.keep);
+    for old in &backups[..excess] {
+        tokio::fs::remove_file(old)
+            .await
+            .with_context(|| format!("delete {}", old.display()))?;
+        info!(path = %old.display(), "deleted old backup");
+    }
+    Ok(path)
+}
+
+/// Deletes stdout, stderr and diagnostics of submissions judged more than
+/// `days` ago, except for the latest and the latest accepted one of every
+/// user and problem. Returns how many submissions lost their output. Freed
+/// space is reused by new submissions.
+pub async fn prune_output(db: &Db, days: i64) -> Result<u64, sqlx::Error> {
+    let cutoff = db::utc_now() - time::Duration::days(days);
+    with_pool!(db, pool => sqlx::query(
+        "update solutions set stdout = null, stderr = null, diagnostics = null
+        where executed_at < $1 and (stdout is not null or stderr is not null or diagnostics is not null)
+        and id not in (select max(id) from solutions group by userid, problem_id)
+        and id not in (select max(id) from solutions where status = $2 group by userid, problem_id)",
+    )
+    .bind(cutoff)
+    .bind(Status::Accepted)
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected()))
+}
+
+/// Backs up on start and then every `backup.interval_hours`.
+pub async fn run_backups(db: Db, config: &'static BackupConfiguration) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_hours * 60 * 60));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match backup_to_dir(&db, config).await {
+            Ok(path) => info!(path = %path.display(), "backed up the database"),
+            Err(e) => error!(error = %e, "could not back up the database"),
+        }
+    }
+}
+
+/// Prunes output on start and then once a day.
+pub async fn run_pruning(db: Db, days: i64) {
+    let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match prune_output(&db, days).await {
+            Ok(pruned) => info!(pruned, "pruned old submission output"),
+            Err(e) => error!(error = %e, "could not prune submission output"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::tests::each_database,
+        repository::{self, NewUserRecord, Verdict},
+    };
+
+    async fn judged(db: &Db, user_id: i64, status: Status, days_ago: i64) -> i64 {
+        let id = repository::insert_solution(db, user_id, "hello", "", None)
+            .await
+            .unwrap();
+        let verdict = Verdict {
+            status,
+            stdout: "out".to_string(),
+            stderr: "err".to_string(),
+            diagnostics: "leak".to_string(),
+        };
+        repository::record_verdict(db, id, &verdict).await.unwrap();
+        with_pool!(db, pool => sqlx::query("update solutions set executed_at = $1 where id = $2")
+            .bind(db::utc_now() - time::Duration::days(days_ago))
+            .bind(id)
+            .execute(pool)
+            .await
+            .map(|_| ()))
+        .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn prunes_old_superseded_output() {
+        each_database(|db| async move {
+            let user = NewUserRecord {
+                username: "alice".to_string(),
+                password_hash: "hash".to_string(),
+                display_name: None,
+                must_change_password: false,
+            };
+            let user_id = repository::insert_user(&db, user).await.unwrap();
+            let old = judged(&db, user_id, Status::WrongAnswer, 40).await;
+            let accepted = judged(&db, user_id, Status::Accepted, 40).await;
+            let recent = judged(&db, user_id, Status::WrongAnswer, 5).await;
+            let latest = judged(&db, user_id, Status::WrongAnswer, 40).await;
+
+            assert_eq!(prune_output(&db, 30).await.unwrap(), 1);
+            for (id, kept) in [
+                (old, false),
+                (accepted, true),
+                (recent, true),
+                (latest, true),
+            ] {
+                let solution = repository::solution(&db, id).await.unwrap().unwrap();
+                assert_eq!(solution.stdout.is_some(), kept);
+                assert_eq!(solution.stderr.is_some(), kept);
+                assert_eq!(solution.diagnostics.is_some(), kept);
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backup_and_restore() {
+        let dir = tempdir::TempDir::new("backup").unwrap();
+        let url = format!("sqlite:{}", dir.path().join("live.sqlite").display());
+        let db = Db::connect(&url).await.unwrap();
+        db.migrate().await.unwrap();
+        let user = NewUserRecord {
+            username: "alice".to_string(),
+            password_hash: "hash".to_string(),
+            display_name: None,
+            must_change_password: false,
+        };
+        repository::insert_user(&db, user).await.unwrap();
+
+        let config = BackupConfiguration {
+            dir: dir.path().join("backups"),
+            interval_hours: 1,
+            keep: 1,
+        };
+        let first = backup_to_dir(&db, &config).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let second = backup_to_dir(&db, &config).await.unwrap();
+        assert!(!first.exists());
+        assert!(second.exists());
+
+        let restored = Db::connect("sqlite::memory:").await.unwrap();
+        restore(&restored, &second).await.unwrap();
+        assert!(repository::user_by_username(&restored, "alice")
+            .await
+            .unwrap()
+            .is_some());
+        assert!(restore(&restored, &first).await.is_err());
+    }
+}
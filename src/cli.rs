@@ -0,0 +1,410 @@
+use std::{env, path::PathBuf};
+
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use openidconnect::url::Url;
+
+use crate::{
+    audit, backup,
+    config::{config_path, get_cached_config, load_config, ENV_OVERRIDE_PREFIX},
+    db::{with_pool, Db},
+    problems::ProblemsInfo,
+    repository::Status,
+    users::{random_readable_string, Backend, NewUser, Role},
+};
+
+/// Seconds between two looks for pending submissions.
+pub const DEFAULT_POLL_INTERVAL: u64 = 5;
+
+/// Online code checker for C workshops.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file. Single keys can be overridden with
+    /// ONLINE_CODE_CHECKER__<SECTION>__<KEY> environment variables.
+    #[arg(
+        long,
+        global = true,
+        env = "ONLINE_CODE_CHECKER_CONFIG",
+        default_value = "online_code_checker_config.toml"
+    )]
+    pub config: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server and judge submissions (the default)
+    Serve(ServeArgs),
+    /// Only judge submissions, for running the judge apart from the server
+    Worker(WorkerArgs),
+    /// Create or upgrade the database
+    Migrate,
+    /// Create an account with a generated password
+    CreateUser(CreateUserArgs),
+    /// Judge submissions again
+    Rejudge(RejudgeArgs),
+    /// Check that every problem has its statement and test cases
+    ValidateProblems,
+    /// Check the config file and the environment overrides
+    CheckConfig,
+    /// Create accounts from a CSV and print their initial passwords
+    ImportUsers(ImportUsersArgs),
+    /// Print per-problem results of every user
+    ExportGrades(ExportGradesArgs),
+    /// Copy the SQLite database, safe while the server runs
+    Backup(BackupArgs),
+    /// Replace the SQLite database with a backup, with the server stopped
+    Restore(RestoreArgs),
+    /// Delete the output of old submissions, see retention.output_days
+    PruneOutput(PruneOutputArgs),
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Leave judging to a separate `worker`
+    #[arg(long)]
+    pub no_worker: bool,
+    /// Seconds between two looks for pending submissions
+    #[arg(long, default_value_t = DEFAULT_POLL_INTERVAL)]
+    pub poll_interval: u64,
+}
+
+impl Default for ServeArgs {
+    fn default() -> Self {
+        Self {
+            no_worker: false,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct WorkerArgs {
+    /// Seconds between two looks for pending submissions
+    #[arg(long, default_value_t = DEFAULT_POLL_INTERVAL)]
+    pub poll_interval: u64,
+    /// Get submissions from this server over HTTP instead of the database,
+    /// e.g. http://judge.example:3000/. Needs no config file.
+    #[arg(long, requires = "secret")]
+    pub server: Option<Url>,
+    /// judge.worker_secret of the server
+    #[arg(
+        long,
+        env = "ONLINE_CODE_CHECKER_WORKER_SECRET",
+        hide_env_values = true
+    )]
+    pub secret: Option<String>,
+    /// Submissions judged at the same time, with --server
+    #[arg(long, default_value_t = 1)]
+    pub concurrency: usize,
+    /// Where problem bundles are kept, with --server
+    #[arg(long, default_value = "judge-cache")]
+    pub cache_dir: PathBuf,
+    /// Name shown in the server log, a random one by default
+    #[arg(long)]
+    pub name: Option<String>,
+}
+
+#[derive(Args)]
+pub struct CreateUserArgs {
+    pub username: String,
+    /// Make the user an admin
+    #[arg(long, conflicts_with = "instructor")]
+    pub admin: bool,
+    /// Make the user an instructor
+    #[arg(long)]
+    pub instructor: bool,
+    #[arg(long)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Args)]
+pub struct BackupArgs {
+    /// Where the copy is written, a new file in backup.dir by default
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    /// A file written by `backup`
+    pub file: PathBuf,
+}
+
+#[derive(Args)]
+pub struct PruneOutputArgs {
+    /// Age in days instead of retention.output_days, at least 1 like there
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..))]
+    pub days: Option<i64>,
+}
+
+#[derive(Args)]
+pub struct RejudgeArgs {
+    /// Submission ids
+    pub ids: Vec<i64>,
+    /// Only submissions for this problem
+    #[arg(long)]
+    pub problem: Option<String>,
+    /// Only submissions of this user
+    #[arg(long)]
+    pub user: Option<String>,
+    /// Only submissions with this verdict, e.g. WA
+    #[arg(long)]
+    pub status: Option<Status>,
+    /// Every submission, if no other filter is given
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct ImportUsersArgs {
+    /// CSV file, or `-` for stdin
+    pub file: String,
+    /// Owner of groups created by the import
+    #[arg(long, default_value = "admin")]
+    pub owner: String,
+}
+
+#[derive(Args)]
+pub struct ExportGradesArgs {
+    /// Only members of this group
+    #[arg(long)]
+    pub group: Option<String>,
+    /// First day, YYYY-MM-DD
+    #[arg(long)]
+    pub from: Option<String>,
+    /// Last day, YYYY-MM-DD
+    #[arg(long)]
+    pub to: Option<String>,
+    /// csv or json
+    #[arg(long, default_value = "csv")]
+    pub format: String,
+}
+
+pub async fn create_user(db: Db, args: CreateUserArgs) -> Result<()> {
+    let backend = Backend::new(db);
+    let password = random_readable_string(12);
+    let user_id = backend
+        .create_user(NewUser {
+            username: args.username.clone(),
+            password: password.clone(),
+            display_name: args.display_name,
+            must_change_password: true,
+        })
+        .await?;
+    let role = if args.admin {
+        Role::Admin
+    } else if args.instructor {
+        Role::Instructor
+    } else {
+        Role::Student
+    };
+    if role != Role::Student {
+        backend.set_role(user_id, role).await?;
+    }
+    audit::record(
+        &backend.db,
+        "user_created",
+        Some(&args.username),
+        None,
+        Some(&format!("command line, {}", role.as_str())),
+    )
+    .await?;
+    println!("{} {}", args.username, password);
+    Ok(())
+}
+
+/// Puts the selected submissions back to pending, for the judge to pick up.
+pub async fn rejudge(db: Db, args: RejudgeArgs) -> Result<()> {
+    let nothing_selected = args.ids.is_empty()
+        && args.problem.is_none()
+        && args.user.is_none()
+        && args.status.is_none();
+    if nothing_selected && !args.all {
+        bail!("select submissions by id, --problem, --user or --status, or pass --all");
+    }
+    let rejudged = with_pool!(&db, pool => async {
+        let mut transaction = pool.begin().await?;
+        // Claims of the old run would keep the new one from being judged.
+        let mut claims = sqlx::QueryBuilder::new(
+            "delete from judge_claims where solution_id in (select id from solutions where true",
+        );
+        push_selection(&mut claims, &args);
+        claims.push(")").build().execute(&mut *transaction).await?;
+        let mut query = sqlx::QueryBuilder::new(
+            "update solutions set status = ",
+        );
+        query
+            .push_bind(Status::Pending)
+            .push(", stdout = null, stderr = null, diagnostics = null, executed_at = null where true");
+        push_selection(&mut query, &args);
+        let rejudged = query.build().execute(&mut *transaction).await?.rows_affected();
+        transaction.commit().await?;
+        Ok::<_, sqlx::Error>(rejudged)
+    }.await)?;
+    let detail = format!("{} submissions from the command line", rejudged);
+    audit::record(&db, "rejudge", None, None, Some(&detail)).await?;
+    println!(
+        "{} submissions queued, a running server or worker judges them within its poll interval",
+        rejudged
+    );
+    Ok(())
+}
+
+/// Appends the `and ...` conditions selecting the submissions to rejudge.
+fn push_selection<'a, DB>(query: &mut sqlx::QueryBuilder<'a, DB>, args: &'a RejudgeArgs)
+where
+    DB: sqlx::Database,
+    i64: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    String: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    Status: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+{
+    if !args.ids.is_empty() {
+        query.push(" and id in (");
+        let mut ids = query.separated(", ");
+        for id in &args.ids {
+            ids.push_bind(*id);
+        }
+        query.push(")");
+    }
+    if let Some(ref problem) = args.problem {
+        query.push(" and problem_id = ").push_bind(problem.clone());
+    }
+    if let Some(ref user) = args.user {
+        query
+            .push(r#" and userid = (select id from "user" where username = "#)
+            .push_bind(user.clone())
+            .push(")");
+    }
+    if let Some(status) = args.status {
+        query.push(" and status = ").push_bind(status);
+    }
+}
+
+/// Connects without migrating, so the copy is what is on disk.
+pub async fn backup(args: BackupArgs) -> Result<()> {
+    let config = get_cached_config().await?;
+    let db = Db::connect(&config.database.url).await?;
+    let path = match (args.file, &config.backup) {
+        (Some(file), _) => {
+            backup::backup(&db, &file).await?;
+            file
+        }
+        (None, Some(backup)) => backup::backup_to_dir(&db, backup).await?,
+        (None, None) => bail!("give a file or configure backup.dir"),
+    };
+    println!("backed up to {}", path.display());
+    Ok(())
+}
+
+/// Restores before migrating, so older backups are brought up to date.
+pub async fn restore(args: RestoreArgs) -> Result<()> {
+    let db = Db::connect(&get_cached_config().await?.database.url).await?;
+    backup::restore(&db, &args.file).await?;
+    db.migrate().await?;
+    let detail = format!("from {}", args.file.display());
+    audit::record(&db, "database_restored", None, None, Some(&detail)).await?;
+    println!("restored {}", args.file.display());
+    Ok(())
+}
+
+pub async fn prune_output(db: Db, args: PruneOutputArgs) -> Result<()> {
+    let retention = get_cached_config().await?.retention.as_ref();
+    let Some(days) = args
+        .days
+        .or(retention.map(|retention| retention.output_days))
+    else {
+        bail!("pass --days or configure retention.output_days");
+    };
+    let pruned = backup::prune_output(&db, days).await?;
+    println!("deleted the output of {} submissions", pruned);
+    Ok(())
+}
+
+pub async fn validate_problems() -> Result<()> {
+    let problems = ProblemsInfo::get_cached_problems_info().await?;
+    let errors = problems.validate().await?;
+    for error in &errors {
+        println!("{}", error);
+    }
+    if !errors.is_empty() {
+        bail!("{} problems found", errors.len());
+    }
+    println!("{} problems ok", problems.problem.len());
+    Ok(())
+}
+
+/// Loads the config like the server would, which reports every problem
+/// found in it.
+pub async fn check_config() -> Result<()> {
+    let path = config_path();
+    for (name, _) in env::vars().filter(|(name, _)| name.starts_with(ENV_OVERRIDE_PREFIX)) {
+        println!("overridden by {}", name);
+    }
+    load_config(path).await?;
+    println!("{} ok", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::tests::{each_database, insert_solution},
+        judge, repository,
+        repository::NewUserRecord,
+    };
+
+    #[tokio::test]
+    async fn rejudge_starts_over() {
+        each_database(|db| async move {
+            let user_id = repository::insert_user(
+                &db,
+                NewUserRecord {
+                    username: "alice".into(),
+                    password_hash: String::new(),
+                    display_name: None,
+                    must_change_password: false,
+                },
+            )
+            .await
+            .unwrap();
+            let failed = insert_solution(&db, user_id, "hello", "WA").await;
+            let accepted = insert_solution(&db, user_id, "hello", "AC").await;
+            for id in [failed, accepted] {
+                with_pool!(&db, pool => sqlx::query("update solutions set diagnostics = 'warning' where id = $1")
+                    .bind(id)
+                    .execute(pool)
+                    .await
+                    .map(|_| ()))
+                .unwrap();
+                assert!(judge::try_claim(&db, id, "old").await.unwrap());
+            }
+
+            let args = RejudgeArgs {
+                ids: Vec::new(),
+                problem: None,
+                user: None,
+                status: Some(Status::WrongAnswer),
+                all: false,
+            };
+            rejudge(db.clone(), args).await.unwrap();
+
+            for (id, rejudged) in [(failed, true), (accepted, false)] {
+                let (status, diagnostics): (Status, Option<String>) = with_pool!(&db, pool => sqlx::query_as(
+                    "select status, diagnostics from solutions where id = $1",
+                )
+                .bind(id)
+                .fetch_one(pool)
+                .await)
+                .unwrap();
+                assert_eq!(status == Status::Pending, rejudged);
+                assert_eq!(diagnostics.is_none(), rejudged);
+                // A fresh claim only succeeds once the old one is gone.
+                assert_eq!(judge::try_claim(&db, id, "new").await.unwrap(), rejudged);
+            }
+        })
+        .await;
+    }
+}
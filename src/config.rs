@@ -1,10 +1,51 @@
-use std::{env, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use bytesize::ByteSize;
+use rand::RngCore;
 use serde::Deserialize;
-use tokio::{fs::read_to_string, sync::OnceCell};
-use tracing::debug;
+use serde_path_to_error::{Path as KeyPath, Segment};
+use tokio::{
+    fs::{read_to_string, OpenOptions},
+    io::AsyncWriteExt,
+    sync::OnceCell,
+};
+use tracing::{debug, info};
+
+/// A value that must not end up in logs.
+#[derive(Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("********")
+    }
+}
+
+/// Secrets that were published with the example config. Anyone could sign
+/// session cookies with them.
+const KNOWN_DEFAULT_SECRETS: &[&str] = &[
+    "TeEuLqhkdBS-_ULp3-24IVbSXoLPvJtaupuTvufuo6Kbg3dsCuRg9KJ73z3WY29Jtx98n-MKyjCR1iianxfcCQ",
+    "change me",
+];
 
 #[derive(Debug, Deserialize)]
 pub struct DatabaseConfiguration {
@@ -16,12 +57,124 @@ pub struct DatabaseConfiguration {
 pub struct ExecutorConfiguration {
     pub concurrent_limit: usize,
     pub memory_limit: ByteSize,
+    /// What a submission may print for one test case before it fails with
+    /// OLE.
+    #[serde(default = "default_output_limit")]
+    pub output_limit: ByteSize,
+    /// How much of the judge's stdout and of its stderr is stored, each.
+    #[serde(default = "default_log_limit")]
+    pub log_limit: ByteSize,
 }
 
+fn default_output_limit() -> ByteSize {
+    ByteSize::mib(1)
+}
+
+fn default_log_limit() -> ByteSize {
+    ByteSize::kib(256)
+}
+
+/// Results of remote workers carry both logs and have to fit in a request.
+pub const MAX_LOG_LIMIT: ByteSize = ByteSize::mib(1);
+
 #[derive(Debug, Deserialize)]
 pub struct ServerConfiguration {
     pub address: String,
-    pub secret_key: String,
+    /// Signs the session cookies, at least 64 characters. Better set through
+    /// the environment or `secret_key_file` than in the config file.
+    #[serde(default)]
+    pub secret_key: Secret,
+    /// Where the secret key is read from if `secret_key` is not set. A random
+    /// key is written there on the first start.
+    #[serde(default = "default_secret_key_file")]
+    pub secret_key_file: PathBuf,
+    /// Password `admin` gets on start while no admin can log in. Has to be
+    /// changed on the first login.
+    #[serde(default)]
+    pub admin_password: Secret,
+    /// Bearer token `/metrics` asks for, at least 32 characters. The
+    /// metrics are not served without one.
+    #[serde(default)]
+    pub metrics_token: Secret,
+    #[serde(default)]
+    pub registration: RegistrationConfiguration,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfiguration,
+    /// Largest source code accepted, typed or uploaded.
+    #[serde(default = "default_max_source_size")]
+    pub max_source_size: ByteSize,
+}
+
+fn default_secret_key_file() -> PathBuf {
+    "session.key".into()
+}
+
+fn default_max_source_size() -> ByteSize {
+    ByteSize::kib(64)
+}
+
+/// Submissions are read whole from one request.
+pub const MAX_SOURCE_SIZE: ByteSize = ByteSize::mib(1);
+
+/// Who may create an account through `/register`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RegistrationMode {
+    #[default]
+    Open,
+    InviteCode,
+    Disabled,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationConfiguration {
+    #[serde(default)]
+    pub mode: RegistrationMode,
+    #[serde(default = "default_min_password_length")]
+    pub min_password_length: usize,
+}
+
+fn default_min_password_length() -> usize {
+    8
+}
+
+impl Default for RegistrationConfiguration {
+    fn default() -> Self {
+        Self {
+            mode: RegistrationMode::default(),
+            min_password_length: default_min_password_length(),
+        }
+    }
+}
+
+/// Limits against password guessing and against flooding the executor.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfiguration {
+    /// Failed logins for one account before it is locked.
+    pub login_failures_per_account: i64,
+    /// Failed logins from one address before it is locked.
+    pub login_failures_per_ip: i64,
+    /// How long failed logins are remembered, which is also the lockout time.
+    pub login_lockout_secs: i64,
+    /// Take the client address from `X-Forwarded-For`. Only enable this
+    /// behind a reverse proxy that sets the header.
+    pub behind_proxy: bool,
+    pub submissions_per_minute: i64,
+    pub max_pending_submissions: i64,
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        Self {
+            login_failures_per_account: 5,
+            login_failures_per_ip: 20,
+            login_lockout_secs: 15 * 60,
+            behind_proxy: false,
+            submissions_per_minute: 6,
+            max_pending_submissions: 2,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,32 +182,645 @@ pub struct ProblemsConfiguration {
     pub dir: PathBuf,
 }
 
+/// Single sign-on through an OpenID Connect provider. Form login keeps
+/// working next to it.
+#[derive(Debug, Deserialize)]
+pub struct OidcConfiguration {
+    pub issuer_url: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: Secret,
+    /// Read instead of `client_secret` if that is not set.
+    #[serde(default)]
+    pub client_secret_file: Option<PathBuf>,
+    /// Must point at `/oidc/callback` of this server and be registered at the
+    /// provider.
+    pub redirect_url: String,
+    #[serde(default = "default_oidc_button_label")]
+    pub button_label: String,
+    /// Claim the user name of new accounts is taken from. Falls back to the
+    /// local part of `email`.
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+    /// Create an account on the first login of an unknown subject.
+    #[serde(default = "default_true")]
+    pub auto_provision: bool,
+    /// Link the first login of an unknown subject to an existing account with
+    /// the same user name instead of creating a new one. Only enable this if
+    /// the provider controls the claim, e.g. imported student ids. Staff
+    /// accounts and accounts with a password of their own are never linked
+    /// this way; their owners link them from the password page.
+    #[serde(default)]
+    pub link_existing_users: bool,
+    /// Claim holding the provider's groups, e.g. `groups`.
+    #[serde(default)]
+    pub groups_claim: Option<String>,
+    /// Provider group -> name of a group here. Users are added to the mapped
+    /// groups on every login.
+    #[serde(default)]
+    pub group_mapping: HashMap<String, String>,
+}
+
+fn default_oidc_button_label() -> String {
+    "SSO でログイン".to_string()
+}
+
+fn default_oidc_username_claim() -> String {
+    "preferred_username".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Acting as an LTI 1.3 tool for learning management systems.
+#[derive(Debug, Deserialize)]
+pub struct LtiConfiguration {
+    /// Public base URL of this server, e.g. `https://code.example.ac.jp`.
+    /// Launches come back to `/lti/launch` below it.
+    pub tool_url: String,
+    /// PEM file with the RSA key deep linking responses and grade service
+    /// requests are signed with. A new key is written there if it is missing.
+    pub private_key_path: PathBuf,
+    #[serde(default)]
+    pub platforms: Vec<LtiPlatformConfiguration>,
+}
+
+/// One LMS registration. The URLs are shown by the LMS when the tool is
+/// registered there.
+#[derive(Debug, Deserialize)]
+pub struct LtiPlatformConfiguration {
+    pub issuer: String,
+    pub client_id: String,
+    /// Accepted deployment ids. Empty accepts every deployment.
+    #[serde(default)]
+    pub deployment_ids: Vec<String>,
+    pub auth_login_url: String,
+    pub auth_token_url: String,
+    pub jwks_url: String,
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+    /// See [`OidcConfiguration::link_existing_users`].
+    #[serde(default)]
+    pub link_existing_users: bool,
+}
+
+/// Judge workers on other machines, started with `worker --server`.
+#[derive(Debug, Deserialize)]
+pub struct JudgeConfiguration {
+    /// Shared with the workers, which send it as a bearer token.
+    pub worker_secret: Secret,
+    /// Seconds a worker has for a submission before another one may take it
+    /// over. Workers still judging renew their claim every half lease.
+    #[serde(default = "default_lease_secs")]
+    pub lease_secs: i64,
+    /// Seconds a worker asking for a job is kept waiting if there is none.
+    #[serde(default = "default_long_poll_secs")]
+    pub long_poll_secs: u64,
+}
+
+pub fn default_lease_secs() -> i64 {
+    600
+}
+
+fn default_long_poll_secs() -> u64 {
+    25
+}
+
+/// Copies of the SQLite database taken while the server runs.
+#[derive(Debug, Deserialize)]
+pub struct BackupConfiguration {
+    pub dir: PathBuf,
+    #[serde(default = "default_backup_interval_hours")]
+    pub interval_hours: u64,
+    /// Backups kept in `dir`, the oldest ones are deleted.
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+fn default_backup_interval_hours() -> u64 {
+    24
+}
+
+fn default_backup_keep() -> usize {
+    7
+}
+
+/// What old submissions keep.
+#[derive(Debug, Deserialize)]
+pub struct RetentionConfiguration {
+    /// Days after judging the output of a submission is deleted, unless it
+    /// is the latest or the latest accepted one of its user for the problem.
+    pub output_days: i64,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Configuration {
     pub database: DatabaseConfiguration,
     pub executor: ExecutorConfiguration,
     pub server: ServerConfiguration,
     pub problems: ProblemsConfiguration,
+    #[serde(default)]
+    pub oidc: Option<OidcConfiguration>,
+    #[serde(default)]
+    pub lti: Option<LtiConfiguration>,
+    #[serde(default)]
+    pub judge: Option<JudgeConfiguration>,
+    #[serde(default)]
+    pub backup: Option<BackupConfiguration>,
+    #[serde(default)]
+    pub retention: Option<RetentionConfiguration>,
 }
 #[derive(Debug)]
 pub struct Task;
 
+/// Environment variables starting with this override single keys of the
+/// config file, with `__` between the parts of the key, e.g.
+/// `ONLINE_CODE_CHECKER__SERVER__RATE_LIMIT__BEHIND_PROXY=true`. Values are
+/// read as TOML and fall back to plain strings, and keys that take a string
+/// get the value as written.
+pub const ENV_OVERRIDE_PREFIX: &str = "ONLINE_CODE_CHECKER__";
+
 static CONFIG: OnceCell<Configuration> = OnceCell::const_new();
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 
-pub async fn get_cached_config() -> Result<&'static Configuration> {
-    CONFIG
-        .get_or_try_init(|| async {
-            let config_filename = env::var("ONLINE_CODE_CHECKER_CONFIG")
-                .unwrap_or_else(|_| "online_code_checker_config.toml".to_string());
-            let config_file_constents = read_to_string(config_filename.clone())
-                .await
-                .with_context(|| format!("read in config file from {}", config_filename))
-                .unwrap();
-            let config: Configuration = toml::from_str(&config_file_constents)
-                .context("parse config")
-                .unwrap();
-            debug!(?config);
-            Ok(config)
-        })
+/// Where [`get_cached_config`] reads the config from, set by `--config`.
+/// Has to be called before the config is first used.
+pub fn set_config_path(path: PathBuf) {
+    let _ = CONFIG_PATH.set(path);
+}
+
+pub fn config_path() -> &'static Path {
+    CONFIG_PATH.get_or_init(|| {
+        env::var("ONLINE_CODE_CHECKER_CONFIG")
+            .unwrap_or_else(|_| "online_code_checker_config.toml".to_string())
+            .into()
+    })
+}
+
+fn override_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// An [`ENV_OVERRIDE_PREFIX`] variable that set a key.
+struct Override {
+    name: String,
+    raw: String,
+}
+
+/// Applies the [`ENV_OVERRIDE_PREFIX`] variables in `vars` to `config` and
+/// returns which variable set which dotted key.
+fn apply_env_overrides(
+    config: &mut toml::Table,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<HashMap<String, Override>> {
+    let mut overridden = HashMap::new();
+    for (name, raw) in vars {
+        let Some(key) = name.strip_prefix(ENV_OVERRIDE_PREFIX) else {
+            continue;
+        };
+        let parts: Vec<String> = key.split("__").map(str::to_lowercase).collect();
+        let Some((last, tables)) = parts.split_last() else {
+            continue;
+        };
+        let mut table = &mut *config;
+        for part in tables {
+            let entry = table
+                .entry(part.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let Some(inner) = entry.as_table_mut() else {
+                bail!("{} overrides {}, which is not a table", name, part);
+            };
+            table = inner;
+        }
+        debug!(key = name, "config overridden from the environment");
+        table.insert(last.clone(), override_value(&raw));
+        overridden.insert(parts.join("."), Override { name, raw });
+    }
+    Ok(overridden)
+}
+
+/// Reads a secret from `path`, or writes a new random one there if the file
+/// does not exist yet.
+async fn read_or_generate_secret(path: &Path) -> Result<Secret> {
+    match read_to_string(path).await {
+        Ok(secret) => return Ok(Secret(secret.trim().to_string())),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    }
+    let mut bytes = [0; 64];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let secret = URL_SAFE_NO_PAD.encode(bytes);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    match options.open(path).await {
+        Ok(mut file) => {
+            file.write_all(secret.as_bytes()).await?;
+            info!(path = %path.display(), "generated a new secret key");
+            Ok(Secret(secret))
+        }
+        // Another process started at the same time won.
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            Ok(Secret(read_to_string(path).await?.trim().to_string()))
+        }
+        Err(e) => Err(e).with_context(|| format!("write {}", path.display())),
+    }
+}
+
+impl Configuration {
+    /// Fills in secrets that are kept in separate files.
+    async fn resolve_secrets(&mut self) -> Result<()> {
+        if self.server.secret_key.is_empty() {
+            self.server.secret_key = read_or_generate_secret(&self.server.secret_key_file).await?;
+        }
+        if let Some(ref mut oidc) = self.oidc {
+            if let (true, Some(path)) = (oidc.client_secret.is_empty(), &oidc.client_secret_file) {
+                let secret = read_to_string(path)
+                    .await
+                    .with_context(|| format!("read oidc.client_secret_file {}", path.display()))?;
+                oidc.client_secret = Secret(secret.trim().to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Everything that keeps the server from working properly, as dotted key
+    /// and message.
+    fn validate(&self) -> Vec<(&'static str, String)> {
+        let mut problems = Vec::new();
+        let secret_key = self.server.secret_key.expose();
+        if KNOWN_DEFAULT_SECRETS.contains(&secret_key) {
+            problems.push((
+                "server.secret_key",
+                "is the published example key, remove it to have a random one generated"
+                    .to_string(),
+            ));
+        } else if secret_key.len() < 64 {
+            problems.push((
+                "server.secret_key",
+                format!(
+                    "must be at least 64 characters long, not {}",
+                    secret_key.len()
+                ),
+            ));
+        }
+        if self.server.address.is_empty() {
+            problems.push(("server.address", "must not be empty".to_string()));
+        }
+        let metrics_token = self.server.metrics_token.expose();
+        if KNOWN_DEFAULT_SECRETS.contains(&metrics_token) {
+            problems.push((
+                "server.metrics_token",
+                "is the example value from the documentation".to_string(),
+            ));
+        } else if !metrics_token.is_empty() && metrics_token.len() < 32 {
+            problems.push((
+                "server.metrics_token",
+                "must be at least 32 characters long".to_string(),
+            ));
+        }
+        if self.server.max_source_size.as_u64() == 0
+            || self.server.max_source_size > MAX_SOURCE_SIZE
+        {
+            problems.push((
+                "server.max_source_size",
+                "must be between 1 byte and 1 MiB".to_string(),
+            ));
+        }
+        if self.server.registration.min_password_length == 0 {
+            problems.push((
+                "server.registration.min_password_length",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if self.executor.concurrent_limit == 0 {
+            problems.push((
+                "executor.concurrent_limit",
+                "must be at least 1".to_string(),
+            ));
+        }
+        if self.executor.output_limit.as_u64() == 0 {
+            problems.push(("executor.output_limit", "must not be 0".to_string()));
+        }
+        if self.executor.log_limit < ByteSize::kib(1) || self.executor.log_limit > MAX_LOG_LIMIT {
+            problems.push((
+                "executor.log_limit",
+                "must be between 1 KiB and 1 MiB".to_string(),
+            ));
+        }
+        if !self.problems.dir.join("info.toml").is_file() {
+            problems.push((
+                "problems.dir",
+                format!("{} has no info.toml", self.problems.dir.display()),
+            ));
+        }
+        if let Some(ref oidc) = self.oidc {
+            if oidc.client_secret.is_empty() {
+                problems.push((
+                    "oidc.client_secret",
+                    "is missing, set it or oidc.client_secret_file".to_string(),
+                ));
+            } else if KNOWN_DEFAULT_SECRETS.contains(&oidc.client_secret.expose()) {
+                problems.push((
+                    "oidc.client_secret",
+                    "is the example value from the documentation".to_string(),
+                ));
+            }
+            if !oidc.redirect_url.ends_with("/oidc/callback") {
+                problems.push((
+                    "oidc.redirect_url",
+                    "must point at /oidc/callback of this server".to_string(),
+                ));
+            }
+        }
+        if let Some(ref lti) = self.lti {
+            if !lti.tool_url.starts_with("https://") && !lti.tool_url.starts_with("http://") {
+                problems.push(("lti.tool_url", "must be an http(s) URL".to_string()));
+            }
+            let key_dir = lti
+                .private_key_path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty());
+            if let Some(dir) = key_dir.filter(|dir| !dir.is_dir()) {
+                problems.push((
+                    "lti.private_key_path",
+                    format!("{} does not exist", dir.display()),
+                ));
+            }
+            if lti.platforms.is_empty() {
+                problems.push(("lti.platforms", "no platform is configured".to_string()));
+            }
+        }
+        if let Some(ref judge) = self.judge {
+            if KNOWN_DEFAULT_SECRETS.contains(&judge.worker_secret.expose()) {
+                problems.push((
+                    "judge.worker_secret",
+                    "is the example value from the documentation".to_string(),
+                ));
+            } else if judge.worker_secret.expose().len() < 32 {
+                problems.push((
+                    "judge.worker_secret",
+                    "must be at least 32 characters long".to_string(),
+                ));
+            }
+            if judge.lease_secs < 1 {
+                problems.push(("judge.lease_secs", "must be at least 1".to_string()));
+            }
+        }
+        if let Some(ref backup) = self.backup {
+            if self.database.url.starts_with("postgres") {
+                problems.push((
+                    "backup",
+                    "only works for SQLite, back up PostgreSQL with pg_dump".to_string(),
+                ));
+            }
+            if backup.interval_hours == 0 {
+                problems.push(("backup.interval_hours", "must be at least 1".to_string()));
+            }
+            if backup.keep == 0 {
+                problems.push(("backup.keep", "must be at least 1".to_string()));
+            }
+        }
+        if let Some(ref retention) = self.retention {
+            if retention.output_days < 1 {
+                problems.push(("retention.output_days", "must be at least 1".to_string()));
+            }
+        }
+        problems
+    }
+}
+
+/// The line `key` is set on in the config file, if it is. Keys of arrays of
+/// tables have the index in brackets, as in `lti.platforms[0].issuer`.
+fn line_of(contents: &str, key: &str) -> Option<usize> {
+    let document = toml_edit::ImDocument::parse(contents).ok()?;
+    let mut item = document.as_item();
+    for part in key.split('.') {
+        match part.strip_suffix(']').and_then(|part| part.split_once('[')) {
+            Some((name, index)) => item = item.get(name)?.get(index.parse::<usize>().ok()?)?,
+            None => item = item.get(part)?,
+        }
+    }
+    let span = item.span()?;
+    Some(contents[..span.start].lines().count().max(1))
+}
+
+/// The value at `path` in `config`, if there is one.
+fn value_at<'a>(
+    mut config: &'a mut toml::Value,
+    path: impl IntoIterator<Item = &'a Segment>,
+) -> Option<&'a mut toml::Value> {
+    for segment in path {
+        config = match segment {
+            Segment::Map { key } => config.get_mut(key.as_str())?,
+            Segment::Seq { index } => config.get_mut(*index)?,
+            _ => return None,
+        };
+    }
+    Some(config)
+}
+
+/// Removes the key at `path` from its table in `config`. Returns whether
+/// there was one.
+fn remove_at(config: &mut toml::Value, path: &KeyPath) -> bool {
+    let mut segments = path.iter();
+    let Some(Segment::Map { key }) = segments.next_back() else {
+        return false;
+    };
+    value_at(config, segments)
+        .and_then(toml::Value::as_table_mut)
+        .is_some_and(|table| table.remove(key).is_some())
+}
+
+/// Deserializes `config` and goes on past errors to find all of them: a key
+/// that does not fit is reported and left out, so the keys after it are
+/// checked too. Returns the config if what was left out has a default, and
+/// every problem found as dotted key and message.
+fn deserialize_config(
+    mut config: toml::Value,
+    overridden: &HashMap<String, Override>,
+) -> (Option<Configuration>, Vec<(String, String)>) {
+    let mut problems: Vec<(String, String)> = Vec::new();
+    loop {
+        let error = match serde_path_to_error::deserialize(config.clone()) {
+            Ok(parsed) => return (Some(parsed), problems),
+            Err(error) => error,
+        };
+        let path = error.path().clone();
+        let message = error.into_inner().message().to_string();
+        let key = path.to_string();
+        // Overrides are read as TOML, which turns a secret of digits into a
+        // number. Where that does not fit, the value is taken as written.
+        if let (Some(value), Some(Override { raw, .. })) =
+            (value_at(&mut config, &path), overridden.get(&key))
+        {
+            if !value.is_str() {
+                *value = toml::Value::String(raw.clone());
+                continue;
+            }
+        }
+        if let Some(field) = message
+            .strip_prefix("missing field `")
+            .and_then(|rest| rest.strip_suffix('`'))
+        {
+            let key = match path.iter().len() {
+                0 => field.to_string(),
+                _ => format!("{}.{}", key, field),
+            };
+            // Unless it was left out for a problem found already.
+            if !problems.iter().any(|(known, _)| *known == key) {
+                problems.push((key, "is missing".to_string()));
+            }
+            return (None, problems);
+        }
+        problems.push((key, message));
+        if !remove_at(&mut config, &path) {
+            return (None, problems);
+        }
+    }
+}
+
+pub async fn load_config(path: &Path) -> Result<Configuration> {
+    let config_file_contents = read_to_string(path)
         .await
+        .with_context(|| format!("read in config file from {}", path.display()))?;
+    let mut table: toml::Table = toml::from_str(&config_file_contents)
+        .with_context(|| format!("parse {}", path.display()))?;
+    let overridden = apply_env_overrides(&mut table, env::vars())?;
+    let (config, mut problems) = deserialize_config(toml::Value::Table(table), &overridden);
+    if let Some(mut config) = config {
+        config.resolve_secrets().await?;
+        // What was left out only has its default, which is not the problem.
+        for (key, problem) in config.validate() {
+            if !problems.iter().any(|(known, _)| known == key) {
+                problems.push((key.to_string(), problem));
+            }
+        }
+        if problems.is_empty() {
+            debug!(?config);
+            return Ok(config);
+        }
+    }
+
+    let mut message = format!("invalid configuration in {}:", path.display());
+    for (key, problem) in problems {
+        let place = match (overridden.get(&key), line_of(&config_file_contents, &key)) {
+            (Some(Override { name, .. }), _) => format!("{}: ", name),
+            (None, Some(line)) => format!("line {}: ", line),
+            (None, None) => String::new(),
+        };
+        message.push_str(&format!("\n  {}{}: {}", place, key, problem));
+    }
+    bail!(message)
+}
+
+pub async fn get_cached_config() -> Result<&'static Configuration> {
+    CONFIG.get_or_try_init(|| load_config(config_path())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[database]
+url = "sqlite::memory:"
+
+[problems]
+dir = "problems"
+
+[executor]
+concurrent_limit = 4
+memory_limit = "100M"
+
+[server]
+address = "127.0.0.1:3000"
+"#;
+
+    #[tokio::test]
+    async fn reports_every_problem() {
+        let dir = tempdir::TempDir::new("occ-config").unwrap();
+        tokio::fs::write(dir.path().join("info.toml"), "")
+            .await
+            .unwrap();
+        let contents = format!(
+            r#"[database]
+url = "sqlite::memory:"
+
+[problems]
+dir = "{dir}"
+
+[executor]
+concurrent_limit = 4
+memory_limit = "100M"
+log_limit = "1B"
+
+[server]
+address = "127.0.0.1:3000"
+secret_key_file = "{dir}/session.key"
+
+[server.registration]
+mode = "sometimes"
+min_password_length = "eight"
+
+[server.rate_limit]
+behind_proxy = "yes"
+"#,
+            dir = dir.path().display()
+        );
+        let path = dir.path().join("config.toml");
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        let message = load_config(&path).await.unwrap_err().to_string();
+        for expected in [
+            "line 10: executor.log_limit: must be between 1 KiB and 1 MiB",
+            "line 17: server.registration.mode: unknown variant `sometimes`",
+            "line 18: server.registration.min_password_length: invalid type",
+            "line 21: server.rate_limit.behind_proxy: invalid type",
+        ] {
+            assert!(message.contains(expected), "{} in {}", expected, message);
+        }
+    }
+
+    #[test]
+    fn stops_at_missing_keys() {
+        let mut table: toml::Table = toml::from_str(CONFIG).unwrap();
+        table["executor"]["concurrent_limit"] = toml::Value::from("four");
+        table["server"].as_table_mut().unwrap().remove("address");
+        let (config, problems) = deserialize_config(toml::Value::Table(table), &HashMap::new());
+        assert!(config.is_none());
+        let keys: Vec<&str> = problems.iter().map(|(key, _)| key.as_str()).collect();
+        // Not missing as well, after it was left out for its type.
+        assert_eq!(keys, ["executor.concurrent_limit"]);
+    }
+
+    #[test]
+    fn overrides_of_strings_stay_strings() {
+        let mut table: toml::Table = toml::from_str(CONFIG).unwrap();
+        let secret = "1234".repeat(16);
+        let vars = [
+            ("ONLINE_CODE_CHECKER__SERVER__SECRET_KEY", secret.as_str()),
+            ("ONLINE_CODE_CHECKER__SERVER__ADDRESS", "3000"),
+            ("ONLINE_CODE_CHECKER__EXECUTOR__CONCURRENT_LIMIT", "2"),
+            ("OTHER__EXECUTOR__CONCURRENT_LIMIT", "3"),
+        ];
+        let overridden = apply_env_overrides(
+            &mut table,
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+        .unwrap();
+        assert_eq!(overridden.len(), 3);
+        let (config, problems) = deserialize_config(toml::Value::Table(table), &overridden);
+        assert!(problems.is_empty());
+        let config = config.unwrap();
+        assert_eq!(config.server.secret_key.expose(), secret);
+        assert_eq!(config.server.address, "3000");
+        assert_eq!(config.executor.concurrent_limit, 2);
+    }
 }
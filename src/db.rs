@@ -0,0 +1,211 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use sqlx::{
+    migrate::Migrator,
+    postgres::{PgConnectOptions, PgPoolOptions},
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    PgPool, SqlitePool,
+};
+use time::{macros::format_description, OffsetDateTime, PrimitiveDateTime};
+
+static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
+static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/postgres");
+
+/// The database, SQLite or PostgreSQL depending on the scheme of
+/// `database.url`.
+#[derive(Debug, Clone)]
+pub enum Db {
+    Sqlite(SqlitePool),
+    Postgres(PgPool),
+}
+
+/// Runs `$body` with `$pool` bound to the pool of `$db`. The body is compiled
+/// once per database, so the SQL in it has to work on both: `$1` style
+/// parameters, `"user"` quoted, `true`/`false` for booleans, and times
+/// computed with [`utc_now`] rather than SQLite's `datetime()`.
+macro_rules! with_pool {
+    ($db:expr, $pool:ident => $body:expr) => {
+        match $db {
+            $crate::db::Db::Sqlite($pool) => $body,
+            $crate::db::Db::Postgres($pool) => $body,
+        }
+    };
+}
+pub(crate) use with_pool;
+
+impl Db {
+    /// `postgres://` and `postgresql://` URLs open PostgreSQL. Anything else
+    /// is SQLite, either a `sqlite:` URL or a file name, created if missing.
+    pub async fn connect(url: &str) -> Result<Db> {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            let pool = PgPoolOptions::new()
+                .connect_with(postgres_options(url)?)
+                .await
+                .context("open database")?;
+            return Ok(Db::Postgres(pool));
+        }
+        let options = if url.starts_with("sqlite:") {
+            SqliteConnectOptions::from_str(url)?
+        } else {
+            SqliteConnectOptions::new().filename(url)
+        };
+        let mut pool = SqlitePoolOptions::new();
+        // Every connection to an in-memory database gets its own.
+        if url.contains(":memory:") {
+            pool = pool.max_connections(1);
+        }
+        let pool = pool
+            .connect_with(options.create_if_missing(true))
+            .await
+            .context("open database")?;
+        Ok(Db::Sqlite(pool))
+    }
+
+    pub fn migrator(&self) -> &'static Migrator {
+        match self {
+            Db::Sqlite(_) => &SQLITE_MIGRATIONS,
+            Db::Postgres(_) => &POSTGRES_MIGRATIONS,
+        }
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        with_pool!(self, pool => self.migrator().run(pool).await).context("database migration")?;
+        Ok(())
+    }
+
+    /// Open connections, and how many of them are idle.
+    pub fn connections(&self) -> (u32, usize) {
+        with_pool!(self, pool => (pool.size(), pool.num_idle()))
+    }
+
+    pub fn max_connections(&self) -> u32 {
+        with_pool!(self, pool => pool.options().get_max_connections())
+    }
+}
+
+/// Options for `url` with the session time zone set to UTC, whatever the
+/// server's default is, for [`utc_now`].
+fn postgres_options(url: &str) -> Result<PgConnectOptions> {
+    Ok(PgConnectOptions::from_str(url)?.options([("timezone", "UTC")]))
+}
+
+/// The current time, for comparing with and storing in timestamp columns.
+/// SQLite keeps them as UTC text and PostgreSQL sessions are set to UTC by
+/// [`Db::connect`], so a time without offset means the same on both.
+pub fn utc_now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_utc();
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// A timestamp read from the database the way SQLite writes
+/// `current_timestamp`, for exports.
+pub fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    timestamp
+        .to_offset(time::UtcOffset::UTC)
+        .format(format_description!(
+            "[year]-[month]-[day] [hour]:[minute]:[second]"
+        ))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{future::Future, panic::AssertUnwindSafe};
+
+    use super::*;
+    use futures::FutureExt;
+    use rand::{distributions::Alphanumeric, Rng};
+
+    /// Runs `test` on a fresh in-memory SQLite database, and on a fresh
+    /// PostgreSQL database if `TEST_POSTGRES_URL` points at a server. The
+    /// PostgreSQL database is dropped afterwards.
+    pub async fn each_database<F, Fut>(test: F)
+    where
+        F: Fn(Db) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let db = Db::connect("sqlite::memory:").await.unwrap();
+        db.migrate().await.unwrap();
+        test(db).await;
+
+        let Ok(url) = std::env::var("TEST_POSTGRES_URL") else {
+            return;
+        };
+        let server = PgPoolOptions::new().connect(&url).await.unwrap();
+        let name: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(12)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        let name = format!("occ_test_{}", name);
+        sqlx::query(&format!("create database {}", name))
+            .execute(&server)
+            .await
+            .unwrap();
+        // A server default other than UTC, which connections have to override.
+        sqlx::query(&format!(
+            "alter database {} set timezone to 'Asia/Tokyo'",
+            name
+        ))
+        .execute(&server)
+        .await
+        .unwrap();
+        let options = postgres_options(&url).unwrap().database(&name);
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        let db = Db::Postgres(pool.clone());
+        let result = AssertUnwindSafe(async {
+            db.migrate().await.unwrap();
+            test(db).await;
+        })
+        .catch_unwind()
+        .await;
+        pool.close().await;
+        sqlx::query(&format!("drop database {} with (force)", name))
+            .execute(&server)
+            .await
+            .unwrap();
+        if let Err(panic) = result {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    /// A submission by `user_id` for `problem_id`, made just now.
+    pub async fn insert_solution(db: &Db, user_id: i64, problem_id: &str, status: &str) -> i64 {
+        with_pool!(db, pool => sqlx::query_scalar(
+            "insert into solutions (content,status,userid,problem_id,created_at) values ('int main(void) { return 0; }',$1,$2,$3,current_timestamp) returning id",
+        )
+        .bind(status)
+        .bind(user_id)
+        .bind(problem_id)
+        .fetch_all(pool)
+        .await)
+        .unwrap()
+        .pop()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn times_are_utc() {
+        each_database(|db| async move {
+            with_pool!(&db, pool => sqlx::query(
+                "insert into lti_nonces (state,nonce,issuer,created_at) values ('state','nonce','issuer',current_timestamp)",
+            )
+            .execute(pool)
+            .await
+            .map(|_| ()))
+            .unwrap();
+            let minute = time::Duration::minutes(1);
+            let recent: i64 = with_pool!(&db, pool => sqlx::query_scalar(
+                "select count(*) from lti_nonces where created_at > $1 and created_at < $2",
+            )
+            .bind(utc_now() - minute)
+            .bind(utc_now() + minute)
+            .fetch_one(pool)
+            .await)
+            .unwrap();
+            assert_eq!(recent, 1);
+        })
+        .await;
+    }
+}
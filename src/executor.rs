@@ -1,121 +1,276 @@
-use std::{process::Stdio, sync::Arc};
+use std::{
+    collections::HashSet,
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use sqlx::{Pool, Sqlite};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     process::Command,
-    sync::{mpsc::Receiver, Semaphore},
+    sync::{
+        mpsc::{Receiver, Sender},
+        Semaphore,
+    },
 };
 use tracing::{debug, error, info, info_span, Instrument};
 
-use crate::config::{get_cached_config, Task};
+use crate::{
+    config::{get_cached_config, Task},
+    db::Db,
+    judge, metrics,
+    problems::Problem,
+    repository::{self, SourceFile, Status, Verdict},
+    rules, submission,
+};
 
 const NIX_FILE: &str = include_str!("embedded-default.nix");
 
-pub async fn executor_task(mut queue: Receiver<Task>, db: Pool<Sqlite>) -> anyhow::Result<()> {
+/// How single file problems are built, and submissions from servers that
+/// do not send a build command.
+pub const DEFAULT_BUILD: &str = "cc -o main main.c";
+
+/// Appended where a log was cut off.
+const TRUNCATION_MARKER: &str = "\n[長すぎるため以降を省略しました]\n";
+
+/// Sizes from the `executor` section, sent along to remote workers.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OutputLimits {
+    /// Bytes a submission may print for one test case.
+    pub output: u64,
+    /// Bytes kept of stdout and of stderr each.
+    pub log: usize,
+}
+
+impl OutputLimits {
+    pub async fn configured() -> Result<Self> {
+        let executor = &get_cached_config().await?.executor;
+        Ok(OutputLimits {
+            output: executor.output_limit.as_u64(),
+            log: executor.log_limit.as_u64() as usize,
+        })
+    }
+}
+
+impl Default for OutputLimits {
+    /// What servers that do not send limits use.
+    fn default() -> Self {
+        OutputLimits {
+            output: 1024 * 1024,
+            log: 256 * 1024,
+        }
+    }
+}
+
+/// Checks a problem asks for besides the test cases, written as
+/// `[problem.diagnostics]`. What they find is shown apart from the build log
+/// and only fails a submission with `strict`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Diagnostics {
+    /// Compile once more with `-Wall -Wextra` for the warnings.
+    pub warnings: bool,
+    /// Run the test cases once more built with AddressSanitizer and UBSan.
+    pub sanitizers: bool,
+    /// Run the sanitizer pass with LeakSanitizer too.
+    pub leaks: bool,
+    /// Fail submissions that pass the test cases but have findings.
+    pub strict: bool,
+}
+
+impl Diagnostics {
+    pub fn enabled(&self) -> bool {
+        self.warnings || self.sanitizers || self.leaks
+    }
+
+    /// Build phase lines checking the `.c` files in `files`, with the
+    /// findings printed between two lines of `marker`, at most `limit`
+    /// bytes for each check. Built apart from the submission's own build,
+    /// so they work with any build command.
+    fn script(&self, files: &[SourceFile], limit: usize, marker: &str) -> String {
+        if !self.enabled() {
+            return String::new();
+        }
+        let sources: Vec<&str> = files
+            .iter()
+            .map(|file| file.name.as_str())
+            .filter(|name| name.ends_with(".c"))
+            .collect();
+        let sources = sources.join(" ");
+        let mut lines = vec!["set +x".to_string(), format!("echo {}", marker)];
+        if self.warnings {
+            lines.push(format!(
+                "cc -fsyntax-only -O1 -Wall -Wextra {} 2> warnings.txt || true",
+                sources
+            ));
+            lines.push(format!(
+                "if [ -s warnings.txt ]; then echo '== 警告 (-Wall -Wextra) =='; head -c {} warnings.txt; echo; fi",
+                limit
+            ));
+        }
+        if self.sanitizers || self.leaks {
+            lines.push(format!(
+                "if cc -g -O1 -fsanitize=address,undefined -fno-omit-frame-pointer -o main-sanitized {} 2> /dev/null; then",
+                sources
+            ));
+            lines.push("  for i in $(cat pairs.txt); do".to_string());
+            // The reports go to their own files, apart from what the
+            // program itself prints.
+            lines.push(format!(
+                "    ASAN_OPTIONS=detect_leaks={}:log_path=$PWD/sanitizer$i UBSAN_OPTIONS=print_stacktrace=1:log_path=$PWD/sanitizer$i timeout 10 ./main-sanitized < input$i.txt > /dev/null 2>&1 || true",
+                u8::from(self.leaks)
+            ));
+            lines.push(format!(
+                "    if cat sanitizer$i.* > report$i.txt 2> /dev/null; then echo \"== サニタイザ (input$i.txt) ==\"; head -c {} report$i.txt; echo; fi",
+                limit
+            ));
+            lines.push("  done".to_string());
+            lines.push("fi".to_string());
+        }
+        lines.push(format!("echo {}", marker));
+        lines.push("set -x".to_string());
+        lines.join("\n        ")
+    }
+}
+
+/// Shortens `log` to at most `limit` bytes, marker included.
+pub fn truncate_log(log: &mut String, limit: usize) {
+    if log.len() <= limit {
+        return;
+    }
+    let mut end = limit.saturating_sub(TRUNCATION_MARKER.len());
+    while !log.is_char_boundary(end) {
+        end -= 1;
+    }
+    log.truncate(end);
+    log.push_str(TRUNCATION_MARKER);
+}
+
+/// A build log, cut off at the log limit.
+struct Log {
+    text: String,
+    /// What was printed between the diagnostics markers, left out of `text`
+    /// and cut off at the same limit.
+    diagnostics: String,
+    /// Whether the output limit marker was printed, even after the cut.
+    output_limit_exceeded: bool,
+}
+
+/// Lines longer than this are kept in pieces, none of them a marker.
+const LINE_LIMIT: usize = 4096;
+
+/// Sorts the lines of a build log into the log and the diagnostics.
+struct LogLines<'a> {
+    diagnostics_marker: &'a [u8],
+    limit: usize,
+    text: Vec<u8>,
+    diagnostics: Vec<u8>,
+    in_diagnostics: bool,
+}
+
+impl LogLines<'_> {
+    fn push(&mut self, line: &[u8]) {
+        if line.trim_ascii() == self.diagnostics_marker {
+            self.in_diagnostics = !self.in_diagnostics;
+            return;
+        }
+        let kept = if self.in_diagnostics {
+            &mut self.diagnostics
+        } else {
+            &mut self.text
+        };
+        let room = (self.limit + 1).saturating_sub(kept.len());
+        kept.extend_from_slice(&line[..line.len().min(room)]);
+    }
+}
+
+/// Reads `reader` to the end, keeping at most `limit` bytes of the log and
+/// of the diagnostics between lines of `diagnostics_marker`, and looking for
+/// `output_limit_marker` anywhere. The rest is still read, so the build does
+/// not block on a full pipe.
+async fn read_log(
+    mut reader: impl AsyncRead + Unpin,
+    limit: usize,
+    diagnostics_marker: &str,
+    output_limit_marker: &str,
+) -> Result<Log> {
+    let marker = output_limit_marker.as_bytes();
+    let mut lines = LogLines {
+        diagnostics_marker: diagnostics_marker.as_bytes(),
+        limit,
+        text: Vec::new(),
+        diagnostics: Vec::new(),
+        in_diagnostics: false,
+    };
+    let mut line = Vec::new();
+    let mut output_limit_exceeded = false;
+    // End of what was read so far, for a marker split between two reads.
+    let mut tail = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+        for piece in chunk.split_inclusive(|&byte| byte == b'\n') {
+            line.extend_from_slice(piece);
+            if line.ends_with(b"\n") || line.len() >= LINE_LIMIT {
+                lines.push(&line);
+                line.clear();
+            }
+        }
+        tail.extend_from_slice(chunk);
+        output_limit_exceeded |= tail.windows(marker.len()).any(|window| window == marker);
+        tail.drain(..tail.len().saturating_sub(marker.len() - 1));
+    }
+    lines.push(&line);
+    let mut text = String::from_utf8_lossy(&lines.text).into_owned();
+    truncate_log(&mut text, limit);
+    let mut diagnostics = String::from_utf8_lossy(&lines.diagnostics).into_owned();
+    truncate_log(&mut diagnostics, limit);
+    Ok(Log {
+        text,
+        diagnostics,
+        output_limit_exceeded,
+    })
+}
+
+pub async fn executor_task(mut queue: Receiver<Task>, db: Db) -> anyhow::Result<()> {
     debug!("setup executor");
 
-    check_docker().await;
+    match check_docker().await {
+        Ok(()) => debug!("Found docker"),
+        Err(e) => error!(error = %e, "docker is not usable, judging will fail until it is"),
+    }
 
     let semaphore = Arc::new(Semaphore::new(
         get_cached_config().await?.executor.concurrent_limit,
     ));
+    // Submissions stay pending while they are judged, this keeps them from
+    // being picked up a second time in the meantime.
+    let running = Arc::new(Mutex::new(HashSet::new()));
     while queue.recv().await.is_some() {
-        let tasks = sqlx::query!("select id from solutions where status = \"Pending\"")
-            .fetch_all(&db)
-            .await?;
-        if tasks.is_empty() {
-            break;
-        }
+        let tasks = repository::pending_solution_ids(&db).await?;
         for task in tasks {
+            if !running.lock().unwrap().insert(task) {
+                continue;
+            }
             let semaphore = semaphore.clone();
+            let running = running.clone();
             tokio::spawn({
                 let span = info_span!("execute task");
                 let db = db.clone();
                 async move {
+                    let _running = Running(running, task);
                     let _s = semaphore.acquire().await.unwrap();
-                    debug!(task = ?task);
-                    let tmp = tempdir::TempDir::new("OCC").unwrap();
-
-                    let record = sqlx::query!(
-                        "select problem_id,content from solutions where id = ?",
-                        task.id
-                    )
-                    .fetch_one(&db)
-                    .await
-                    .unwrap();
-                    tokio::fs::File::create(tmp.path().join("default.nix"))
-                        .await
-                        .unwrap()
-                        .write_all(NIX_FILE.as_bytes())
-                        .await
-                        .unwrap();
-                    tokio::fs::File::create(tmp.path().join("main.c"))
-                        .await
-                        .unwrap()
-                        .write_all(record.content.unwrap().as_bytes())
-                        .await
-                        .unwrap();
-                    let mut readdir = tokio::fs::read_dir(
-                        get_cached_config()
-                            .await
-                            .unwrap()
-                            .problems
-                            .dir
-                            .join(record.problem_id),
-                    )
-                    .await
-                    .unwrap();
-                    while let Ok(Some(entry)) = readdir.next_entry().await {
-                        tokio::fs::copy(entry.path(), tmp.path().join(entry.file_name()))
-                            .await
-                            .unwrap();
-                    }
-
-                    let output = Command::new("timeout")
-                        .args(["300", "docker", "run", "--init"])
-                        .args(["-v", &format!("{}:/check", tmp.path().to_str().unwrap())])
-                        .args(["--rm", "ghcr.io/nixos/nix"])
-                        .args(["nix-build", "check"])
-                        .output()
-                        .await
-                        .unwrap();
-
-                    let stdout = String::from_utf8_lossy(&output.stdout);
-                    let stderr = String::from_utf8_lossy(&output.stderr);
-
-                    if output.status.success() {
-                        info!(
-                            stdout = %stdout,
-                            stderr = %stderr,
-                            "Success!"
-                        );
-                        sqlx::query!(
-                            "update solutions set status = ?, stdout = ?, stderr = ?, executed_at = current_timestamp where id = ?",
-                            "AC",
-                            stdout,
-                            stderr,
-                            task.id
-                        )
-                        .execute(&db)
-                        .await
-                        .unwrap();
-                    } else {
-                        info!(
-                            stdout = %stdout,
-                            stderr = %stderr,
-                            "Fail"
-                        );
-                        sqlx::query!(
-                            "update solutions set status = ?, stdout = ?, stderr = ?, executed_at = current_timestamp where id = ?",
-                            "WA",
-                            stdout,
-                            stderr,
-                            task.id
-                        )
-                        .execute(&db)
-                        .await
-                        .unwrap();
+                    debug!(task);
+                    if let Err(e) = judge_locally(&db, task).await {
+                        error!(solution_id = task, error = %e, "judging failed");
+                        let _ = judge::release(&db, task, &judge::LOCAL_WORKER).await;
                     }
                 }
                 .instrument(span)
@@ -125,19 +280,289 @@ pub async fn executor_task(mut queue: Receiver<Task>, db: Pool<Sqlite>) -> anyho
     Ok(())
 }
 
-async fn check_docker() {
-    match Command::new("docker")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stdin(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
+/// Escapes `text` for a Nix `''` string.
+fn nix_escape(text: &str) -> String {
+    text.replace("''", "'''").replace("${", "''${")
+}
+
+/// Builds `files` with `build` against the test cases in `problem_dir`
+/// inside docker, with the checks in `diagnostics` on the side.
+pub async fn check(
+    files: &[SourceFile],
+    build: &str,
+    diagnostics: Diagnostics,
+    problem_dir: &Path,
+    limits: OutputLimits,
+) -> Result<Verdict> {
+    let tmp = tempdir::TempDir::new("OCC")?;
+    // Random, so a submission cannot print them to pass off its own output
+    // as findings or as having hit the output limit.
+    let diagnostics_marker = format!("OCC_DIAGNOSTICS_{:016x}", rand::random::<u64>());
+    let output_limit_marker = format!("OCC_OUTPUT_LIMIT_{:016x}", rand::random::<u64>());
+    for file in files {
+        // Remote workers get the names from the server.
+        if !submission::valid_name(&file.name) {
+            bail!("invalid file name {:?}", file.name);
+        }
+        tokio::fs::File::create(tmp.path().join(&file.name))
+            .await?
+            .write_all(file.content.as_bytes())
+            .await?;
+    }
+    let nix_file = NIX_FILE
+        .replace("@OUTPUT_LIMIT@", &limits.output.to_string())
+        .replace("@OUTPUT_LIMIT_MARKER@", &output_limit_marker)
+        .replace("@BUILD@", &nix_escape(build))
+        .replace(
+            "@DIAGNOSTICS@",
+            &nix_escape(&diagnostics.script(files, limits.log, &diagnostics_marker)),
+        );
+    tokio::fs::File::create(tmp.path().join("default.nix"))
+        .await?
+        .write_all(nix_file.as_bytes())
+        .await?;
+    let mut readdir = tokio::fs::read_dir(problem_dir)
         .await
-    {
-        Ok(_) => debug!("Found docker"),
-        Err(e) => {
-            error!( error = ?e,"could not find docker");
-            panic!("could not find docker");
+        .with_context(|| format!("open {}", problem_dir.display()))?;
+    while let Some(entry) = readdir.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            tokio::fs::copy(entry.path(), tmp.path().join(entry.file_name())).await?;
+        }
+    }
+
+    let mut child = Command::new("timeout")
+        .args(["300", "docker", "run", "--init"])
+        .args([
+            "-v",
+            &format!("{}:/check", tmp.path().to_str().context("temp dir path")?),
+        ])
+        .args(["--rm", "ghcr.io/nixos/nix"])
+        .args(["nix-build", "check"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (stdout, stderr) = tokio::try_join!(
+        read_log(
+            child.stdout.take().context("stdout")?,
+            limits.log,
+            &diagnostics_marker,
+            &output_limit_marker,
+        ),
+        read_log(
+            child.stderr.take().context("stderr")?,
+            limits.log,
+            &diagnostics_marker,
+            &output_limit_marker,
+        ),
+    )?;
+    let success = child.wait().await?.success();
+    let output_limit_exceeded = stdout.output_limit_exceeded || stderr.output_limit_exceeded;
+    // nix-build prints the build on stderr.
+    let mut findings = stderr.diagnostics;
+    findings.push_str(&stdout.diagnostics);
+    let (stdout, stderr) = (stdout.text, stderr.text);
+
+    let status = if success && diagnostics.strict && !findings.is_empty() {
+        info!(findings = %findings, "Passed with findings");
+        Status::WrongAnswer
+    } else if success {
+        info!(stdout = %stdout, stderr = %stderr, "Success!");
+        Status::Accepted
+    } else {
+        info!(stdout = %stdout, stderr = %stderr, "Fail");
+        if output_limit_exceeded {
+            Status::OutputLimitExceeded
+        } else {
+            Status::WrongAnswer
         }
     };
+    Ok(Verdict {
+        status,
+        stdout,
+        stderr,
+        diagnostics: findings,
+    })
+}
+
+/// Judges a pending submission here, unless a remote worker holds it.
+async fn judge_locally(db: &Db, solution_id: i64) -> Result<()> {
+    if !judge::try_claim(db, solution_id, &judge::LOCAL_WORKER).await? {
+        return Ok(());
+    }
+    let solution = repository::solution(db, solution_id)
+        .await?
+        .context("submission does not exist")?;
+    // Judged since the pending ones were listed.
+    if solution.status != Status::Pending {
+        judge::release(db, solution_id, &judge::LOCAL_WORKER).await?;
+        return Ok(());
+    }
+    let problem_dir = get_cached_config()
+        .await?
+        .problems
+        .dir
+        .join(&solution.problem_id);
+    let files = repository::source_files(db, &solution).await?;
+    let started = Instant::now();
+    if let Some(verdict) = rules::verdict(&solution.problem_id, &files).await? {
+        judge::finish(db, solution_id, &verdict, started.elapsed()).await?;
+        return Ok(());
+    }
+    let build = build_command(&solution.problem_id).await;
+    let diagnostics = problem_diagnostics(&solution.problem_id).await;
+    let _running = metrics::RunningJudge::start();
+    let limits = OutputLimits::configured().await?;
+    let renewal = tokio::spawn(judge::keep_claim(
+        db.clone(),
+        solution_id,
+        judge::LOCAL_WORKER.as_str(),
+    ));
+    let verdict = check(&files, &build, diagnostics, &problem_dir, limits).await;
+    renewal.abort();
+    judge::finish(db, solution_id, &verdict?, started.elapsed()).await?;
+    Ok(())
+}
+
+/// How submissions to `problem_id` are built. Problems no longer listed in
+/// `info.toml` are still judged as single files.
+pub async fn build_command(problem_id: &str) -> String {
+    match Problem::by_id(problem_id).await {
+        Ok(problem) => problem.build_command(),
+        Err(_) => DEFAULT_BUILD.to_string(),
+    }
+}
+
+/// The checks besides the test cases `problem_id` asks for.
+pub async fn problem_diagnostics(problem_id: &str) -> Diagnostics {
+    match Problem::by_id(problem_id).await {
+        Ok(problem) => problem.diagnostics,
+        Err(_) => Diagnostics::default(),
+    }
+}
+
+/// Forgets a running submission once its task ends, even if it panicked.
+struct Running(Arc<Mutex<HashSet<i64>>>, i64);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.0.lock() {
+            running.remove(&self.1);
+        }
+    }
+}
+
+/// Wakes the executor every `interval`, so submissions queued by another
+/// process, e.g. `rejudge`, get judged too.
+pub async fn poll(tx: Sender<Task>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if tx.send(Task).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Whether docker can run containers, which needs its daemon and not only
+/// the command.
+pub async fn check_docker() -> Result<()> {
+    let status = tokio::time::timeout(
+        Duration::from_secs(5),
+        Command::new("docker")
+            .args(["info", "--format", "{{.ServerVersion}}"])
+            .stdout(Stdio::null())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status(),
+    )
+    .await
+    .context("docker did not answer within 5 seconds")?
+    .context("could not run docker")?;
+    if !status.success() {
+        bail!("the docker daemon is not reachable");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_at_character_boundaries() {
+        let mut log = "あ".repeat(100);
+        truncate_log(&mut log, 200);
+        assert!(log.len() <= 200);
+        assert!(log.ends_with(TRUNCATION_MARKER));
+
+        let mut short = "short".to_string();
+        truncate_log(&mut short, 200);
+        assert_eq!(short, "short");
+    }
+
+    #[test]
+    fn escapes_the_build_command() {
+        assert_eq!(nix_escape(DEFAULT_BUILD), DEFAULT_BUILD);
+        assert_eq!(
+            nix_escape("cc -o main ${SOURCES} -D'X=\"''\"'"),
+            "cc -o main ''${SOURCES} -D'X=\"'''\"'"
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_the_marker_after_the_cut() {
+        let build = format!("{}\nLIMIT input3.txt\n", "x".repeat(100_000));
+        let log = read_log(build.as_bytes(), 1000, "DIAGNOSTICS", "LIMIT")
+            .await
+            .unwrap();
+        assert!(log.text.len() <= 1000);
+        assert!(log.output_limit_exceeded);
+
+        let log = read_log("diff output".as_bytes(), 1000, "DIAGNOSTICS", "LIMIT")
+            .await
+            .unwrap();
+        assert_eq!(log.text, "diff output");
+        assert!(!log.output_limit_exceeded);
+
+        // Any other marker is the submission's own output.
+        let log = read_log(build.as_bytes(), 1000, "DIAGNOSTICS", "OTHER")
+            .await
+            .unwrap();
+        assert!(!log.output_limit_exceeded);
+    }
+
+    #[tokio::test]
+    async fn separates_the_diagnostics() {
+        let build = "+ cc\nM\nmain.c:3: warning: unused variable\nM\n+ ./main\nDIAGNOSTICS is not alone M\n";
+        let log = read_log(build.as_bytes(), 1000, "M", "LIMIT")
+            .await
+            .unwrap();
+        assert_eq!(log.text, "+ cc\n+ ./main\nDIAGNOSTICS is not alone M\n");
+        assert_eq!(log.diagnostics, "main.c:3: warning: unused variable\n");
+
+        let none = Diagnostics::default();
+        assert_eq!(none.script(&[], 1000, "M"), "");
+        let files = [
+            SourceFile {
+                name: "main.c".to_string(),
+                content: String::new(),
+            },
+            SourceFile {
+                name: "list.h".to_string(),
+                content: String::new(),
+            },
+        ];
+        let leaks = Diagnostics {
+            leaks: true,
+            ..none
+        };
+        let script = leaks.script(&files, 1000, "M");
+        assert!(script.contains(
+            "-fsanitize=address,undefined -fno-omit-frame-pointer -o main-sanitized main.c 2>"
+        ));
+        assert!(script.contains("detect_leaks=1"));
+        assert!(!script.contains("-Wall"));
+        assert_eq!(script.matches("echo M").count(), 2);
+    }
 }
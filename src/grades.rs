@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use futures::TryStreamExt;
+use serde::Serialize;
+use time::{macros::format_description, Date, OffsetDateTime};
+
+use crate::{
+    db::{self, with_pool, Db},
+    groups::Group,
+    problems::ProblemsInfo,
+    repository::Status,
+};
+
+/// Which users and submissions an export covers.
+#[derive(Debug, Default)]
+pub struct GradeFilter {
+    /// Only members of this group, and only its assigned problems if it has
+    /// any.
+    pub group_id: Option<i64>,
+    /// First day of submissions counted.
+    pub from: Option<Date>,
+    /// Last day of submissions counted.
+    pub to: Option<Date>,
+}
+
+/// `YYYY-MM-DD`
+pub fn parse_date(date: &str) -> Result<Date> {
+    Date::parse(date, format_description!("[year]-[month]-[day]"))
+        .with_context(|| format!("invalid date {}, expected YYYY-MM-DD", date))
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ProblemGrade {
+    pub best_verdict: Option<Status>,
+    /// 1 once the problem was accepted, 0 otherwise.
+    pub best_score: i64,
+    pub first_ac_at: Option<String>,
+    pub attempts: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserGrades {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub problems: BTreeMap<String, ProblemGrade>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GradeReport {
+    pub problem_ids: Vec<String>,
+    pub users: Vec<UserGrades>,
+}
+
+/// Lower is better. A pending submission never hides a judged one.
+fn verdict_rank(status: Status) -> u8 {
+    match status {
+        Status::Accepted => 0,
+        Status::WrongAnswer | Status::OutputLimitExceeded | Status::RuleViolation => 1,
+        Status::Pending => 2,
+    }
+}
+
+impl ProblemGrade {
+    fn add(&mut self, status: Status, created_at: Option<String>) {
+        self.attempts += 1;
+        // Submissions come in order, so among equally good verdicts the
+        // latest one is kept.
+        let better = match self.best_verdict {
+            Some(best) => verdict_rank(status) <= verdict_rank(best),
+            None => true,
+        };
+        if better {
+            self.best_verdict = Some(status);
+        }
+        if status == Status::Accepted {
+            self.best_score = 1;
+            if self.first_ac_at.is_none() {
+                self.first_ac_at = created_at;
+            }
+        }
+    }
+}
+
+/// One row per user with the best result for every problem, computed from
+/// `solutions`.
+pub async fn grade_report(db: &Db, filter: &GradeFilter) -> Result<GradeReport> {
+    let mut problem_ids: Vec<String> = ProblemsInfo::get_cached_problems_info()
+        .await?
+        .problem
+        .iter()
+        .map(|p| p.id.clone())
+        .collect();
+    if let Some(group_id) = filter.group_id {
+        let group = Group::by_id(db, group_id)
+            .await?
+            .context("group does not exist")?;
+        let assigned = group.problem_ids(db).await?;
+        if !assigned.is_empty() {
+            problem_ids.retain(|id| assigned.contains(id));
+        }
+    }
+
+    let users: Vec<(i64, String, Option<String>)> = with_pool!(db, pool => sqlx::query_as(
+        r#"select id,username,display_name from "user" where $1 is null or id in (select user_id from group_members where group_id = $1) order by username"#,
+    )
+    .bind(filter.group_id)
+    .fetch_all(pool)
+    .await)?;
+    let mut grades: BTreeMap<i64, BTreeMap<String, ProblemGrade>> = BTreeMap::new();
+
+    let until = match filter.to {
+        Some(date) => Some(date.next_day().context("date out of range")?),
+        None => None,
+    };
+    type Row = (i64, String, Status, Option<OffsetDateTime>);
+    let mut solutions = with_pool!(db, pool => sqlx::query_as::<_, Row>(
+        "select userid,problem_id,status,created_at from solutions where ($1 is null or created_at >= $1) and ($2 is null or created_at < $2) and ($3 is null or userid in (select user_id from group_members where group_id = $3)) order by id",
+    )
+    .bind(filter.from)
+    .bind(until)
+    .bind(filter.group_id)
+    .fetch(pool));
+    while let Some((userid, problem_id, status, created_at)) = solutions.try_next().await? {
+        if !problem_ids.contains(&problem_id) {
+            continue;
+        }
+        grades
+            .entry(userid)
+            .or_default()
+            .entry(problem_id)
+            .or_default()
+            .add(status, created_at.map(db::format_timestamp));
+    }
+
+    let users = users
+        .into_iter()
+        .map(|(id, username, display_name)| {
+            let mut problems = grades.remove(&id).unwrap_or_default();
+            for id in &problem_ids {
+                problems.entry(id.clone()).or_default();
+            }
+            UserGrades {
+                username,
+                display_name,
+                problems,
+            }
+        })
+        .collect();
+    Ok(GradeReport { problem_ids, users })
+}
+
+impl GradeReport {
+    /// Four columns per problem, in the order of `info.toml`.
+    pub fn to_csv(&self) -> Result<Vec<u8>> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let mut header = vec!["username".to_string(), "display_name".to_string()];
+        for id in &self.problem_ids {
+            header.push(format!("{} verdict", id));
+            header.push(format!("{} score", id));
+            header.push(format!("{} first_ac_at", id));
+            header.push(format!("{} attempts", id));
+        }
+        writer.write_record(&header)?;
+        for user in &self.users {
+            let mut record = vec![
+                user.username.clone(),
+                user.display_name.clone().unwrap_or_default(),
+            ];
+            for id in &self.problem_ids {
+                let grade = user.problems.get(id).cloned().unwrap_or_default();
+                record.push(
+                    grade
+                        .best_verdict
+                        .map_or_else(String::new, |status| status.to_string()),
+                );
+                record.push(grade.best_score.to_string());
+                record.push(grade.first_ac_at.unwrap_or_default());
+                record.push(grade.attempts.to_string());
+            }
+            writer.write_record(&record)?;
+        }
+        Ok(writer.into_inner()?)
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// `export-grades [--group <name>] [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--format csv|json]`
+///
+/// Prints the report to stdout.
+pub async fn cli(
+    db: Db,
+    group: Option<&str>,
+    from: Option<&str>,
+    to: Option<&str>,
+    format: &str,
+) -> Result<()> {
+    let mut filter = GradeFilter {
+        group_id: None,
+        from: from.map(parse_date).transpose()?,
+        to: to.map(parse_date).transpose()?,
+    };
+    if let Some(name) = group {
+        let group = Group::by_name(&db, name)
+            .await?
+            .with_context(|| format!("group {} does not exist", name))?;
+        filter.group_id = Some(group.id);
+    }
+
+    let report = grade_report(&db, &filter).await?;
+    let output = match format {
+        "csv" => report.to_csv()?,
+        "json" => report.to_json()?,
+        _ => bail!("unknown format {}, use csv or json", format),
+    };
+    print!("{}", String::from_utf8(output)?);
+    Ok(())
+}
@@ -0,0 +1,523 @@
+use serde::Serialize;
+use sqlx::FromRow;
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+use crate::{
+    db::{utc_now, with_pool, Db},
+    repository::NewUserRecord,
+    users::{random_readable_string, User},
+};
+
+const INVITE_CODE_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Group {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub created_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Member {
+    pub id: i64,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct MemberSolution {
+    pub id: i64,
+    pub username: String,
+    pub problem_id: String,
+    pub status: Option<String>,
+    pub created_at: Option<OffsetDateTime>,
+}
+
+/// A code that lets people register on their own and puts them into
+/// `group_id`.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct InviteCode {
+    pub id: i64,
+    pub code: String,
+    pub group_id: i64,
+    pub max_uses: i64,
+    pub uses: i64,
+    pub expires_at: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("A group with the same name already exists")]
+    NameAlreadyExists,
+    #[error("The user {0} does not exist")]
+    UserNotFound(String),
+    #[error("Database access error")]
+    Database(#[from] sqlx::Error),
+}
+
+impl Group {
+    pub async fn create(db: &Db, name: &str, owner: &User) -> Result<Group, GroupError> {
+        let existing: Option<i64> = with_pool!(db, pool => sqlx::query_scalar(
+            "select id from groups where name = $1",
+        )
+        .bind(name)
+        .fetch_optional(pool)
+        .await)?;
+        if existing.is_some() {
+            return Err(GroupError::NameAlreadyExists);
+        }
+        // `fetch_all` waits for the statement to be committed, see
+        // `repository::insert_user`.
+        let group = with_pool!(db, pool => sqlx::query_as(
+            "insert into groups (name,owner_id,created_at) values ($1,$2,current_timestamp) returning id,name,owner_id,created_at",
+        )
+        .bind(name)
+        .bind(owner.id)
+        .fetch_all(pool)
+        .await)?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(group)
+    }
+
+    pub async fn by_id(db: &Db, id: i64) -> Result<Option<Group>, GroupError> {
+        let group = with_pool!(db, pool => sqlx::query_as(
+            "select id,name,owner_id,created_at from groups where id = $1",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await)?;
+        Ok(group)
+    }
+
+    pub async fn by_name(db: &Db, name: &str) -> Result<Option<Group>, GroupError> {
+        let group = with_pool!(db, pool => sqlx::query_as(
+            "select id,name,owner_id,created_at from groups where name = $1",
+        )
+        .bind(name)
+        .fetch_optional(pool)
+        .await)?;
+        Ok(group)
+    }
+
+    /// Groups visible to `user`: all of them for admins, owned ones otherwise.
+    pub async fn list_for(db: &Db, user: &User) -> Result<Vec<Group>, GroupError> {
+        let groups = if user.is_admin() {
+            with_pool!(db, pool => sqlx::query_as(
+                "select id,name,owner_id,created_at from groups order by id",
+            )
+            .fetch_all(pool)
+            .await)?
+        } else {
+            with_pool!(db, pool => sqlx::query_as(
+                "select id,name,owner_id,created_at from groups where owner_id = $1 order by id",
+            )
+            .bind(user.id)
+            .fetch_all(pool)
+            .await)?
+        };
+        Ok(groups)
+    }
+
+    pub fn can_manage(&self, user: &User) -> bool {
+        user.is_admin() || (user.is_staff() && self.owner_id == user.id)
+    }
+
+    pub async fn members(&self, db: &Db) -> Result<Vec<Member>, GroupError> {
+        let members = with_pool!(db, pool => sqlx::query_as(
+            r#"select "user".id,"user".username from "user" join group_members on group_members.user_id = "user".id where group_members.group_id = $1 order by "user".username"#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await)?;
+        Ok(members)
+    }
+
+    pub async fn add_member(&self, db: &Db, username: &str) -> Result<(), GroupError> {
+        let user_id: i64 = with_pool!(db, pool => sqlx::query_scalar(
+            r#"select id from "user" where username = $1"#,
+        )
+        .bind(username)
+        .fetch_optional(pool)
+        .await)?
+        .ok_or_else(|| GroupError::UserNotFound(username.to_string()))?;
+        self.add_user(db, user_id).await
+    }
+
+    pub async fn add_user(&self, db: &Db, user_id: i64) -> Result<(), GroupError> {
+        with_pool!(db, pool => sqlx::query(
+            "insert into group_members (group_id,user_id) values ($1,$2) on conflict do nothing",
+        )
+        .bind(self.id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        Ok(())
+    }
+
+    pub async fn remove_member(&self, db: &Db, user_id: i64) -> Result<(), GroupError> {
+        with_pool!(db, pool => sqlx::query(
+            "delete from group_members where group_id = $1 and user_id = $2",
+        )
+        .bind(self.id)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        Ok(())
+    }
+
+    pub async fn problem_ids(&self, db: &Db) -> Result<Vec<String>, GroupError> {
+        let problem_ids = with_pool!(db, pool => sqlx::query_scalar(
+            "select problem_id from group_problems where group_id = $1 order by problem_id",
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await)?;
+        Ok(problem_ids)
+    }
+
+    pub async fn assign_problem(&self, db: &Db, problem_id: &str) -> Result<(), GroupError> {
+        with_pool!(db, pool => sqlx::query(
+            "insert into group_problems (group_id,problem_id) values ($1,$2) on conflict do nothing",
+        )
+        .bind(self.id)
+        .bind(problem_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        Ok(())
+    }
+
+    pub async fn unassign_problem(&self, db: &Db, problem_id: &str) -> Result<(), GroupError> {
+        with_pool!(db, pool => sqlx::query(
+            "delete from group_problems where group_id = $1 and problem_id = $2",
+        )
+        .bind(self.id)
+        .bind(problem_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        Ok(())
+    }
+
+    pub async fn solutions(&self, db: &Db) -> Result<Vec<MemberSolution>, GroupError> {
+        let solutions = with_pool!(db, pool => sqlx::query_as(
+            r#"select solutions.id,"user".username,solutions.problem_id,solutions.status,solutions.created_at from solutions join "user" on "user".id = solutions.userid join group_members on group_members.user_id = solutions.userid where group_members.group_id = $1 order by solutions.id desc"#,
+        )
+        .bind(self.id)
+        .fetch_all(pool)
+        .await)?;
+        Ok(solutions)
+    }
+}
+
+/// Problems assigned to any group `user_id` belongs to. An empty list means
+/// the user is not restricted to a problem set.
+pub async fn assigned_problem_ids(db: &Db, user_id: i64) -> Result<Vec<String>, GroupError> {
+    let problem_ids = with_pool!(db, pool => sqlx::query_scalar(
+        "select distinct group_problems.problem_id from group_problems join group_members on group_members.group_id = group_problems.group_id where group_members.user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await)?;
+    Ok(problem_ids)
+}
+
+/// Whether `user_id` may open, submit to and see the status of `problem_id`:
+/// any problem unless a group of theirs has problems assigned.
+pub async fn can_solve(db: &Db, user_id: i64, problem_id: &str) -> Result<bool, GroupError> {
+    let assigned = assigned_problem_ids(db, user_id).await?;
+    Ok(assigned.is_empty() || assigned.iter().any(|id| id == problem_id))
+}
+
+/// Whether `viewer` may look at submissions made by `owner_id`: their own,
+/// everything for admins, and members of owned groups for instructors.
+pub async fn can_view_submissions_of(
+    db: &Db,
+    viewer: &User,
+    owner_id: i64,
+) -> Result<bool, GroupError> {
+    if viewer.id == owner_id || viewer.is_admin() {
+        return Ok(true);
+    }
+    if !viewer.is_staff() {
+        return Ok(false);
+    }
+    let record: Option<i64> = with_pool!(db, pool => sqlx::query_scalar(
+        "select groups.id from groups join group_members on group_members.group_id = groups.id where groups.owner_id = $1 and group_members.user_id = $2 limit 1",
+    )
+    .bind(viewer.id)
+    .bind(owner_id)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(record.is_some())
+}
+
+impl InviteCode {
+    pub async fn create(
+        db: &Db,
+        group: &Group,
+        creator: &User,
+        max_uses: i64,
+        valid_days: Option<i64>,
+    ) -> Result<InviteCode, GroupError> {
+        let code = random_readable_string(INVITE_CODE_LENGTH);
+        let expires_at = valid_days.map(|days| utc_now() + Duration::days(days));
+        let invite = with_pool!(db, pool => sqlx::query_as(
+            "insert into invite_codes (code,group_id,max_uses,expires_at,created_by,created_at) values ($1,$2,$3,$4,$5,current_timestamp) returning id,code,group_id,max_uses,uses,expires_at",
+        )
+        .bind(code)
+        .bind(group.id)
+        .bind(max_uses)
+        .bind(expires_at)
+        .bind(creator.id)
+        .fetch_all(pool)
+        .await)?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)?;
+        Ok(invite)
+    }
+
+    pub async fn list_for_group(db: &Db, group: &Group) -> Result<Vec<InviteCode>, GroupError> {
+        let invites = with_pool!(db, pool => sqlx::query_as(
+            "select id,code,group_id,max_uses,uses,expires_at from invite_codes where group_id = $1 order by id",
+        )
+        .bind(group.id)
+        .fetch_all(pool)
+        .await)?;
+        Ok(invites)
+    }
+
+    pub async fn delete(db: &Db, group: &Group, id: i64) -> Result<(), GroupError> {
+        with_pool!(db, pool => sqlx::query(
+            "delete from invite_codes where group_id = $1 and id = $2",
+        )
+        .bind(group.id)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+        Ok(())
+    }
+
+    /// Uses up one registration of `code` to insert `user` into its group,
+    /// all or nothing: a failed insert leaves the use. Returns `None` if the
+    /// code does not exist, has expired or has no uses left.
+    pub async fn register(
+        db: &Db,
+        code: &str,
+        user: NewUserRecord,
+    ) -> Result<Option<i64>, GroupError> {
+        let user_id = with_pool!(db, pool => async {
+            let mut transaction = pool.begin().await?;
+            let group_id: Option<i64> = sqlx::query_scalar(
+                "update invite_codes set uses = uses + 1 where code = $1 and uses < max_uses and (expires_at is null or expires_at > $2) returning group_id",
+            )
+            .bind(code)
+            .bind(utc_now())
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let Some(group_id) = group_id else {
+                return Ok(None);
+            };
+            let user_id: i64 = sqlx::query_scalar(
+                r#"insert into "user" (username,password,display_name,must_change_password,created_at) values ($1,$2,$3,$4,current_timestamp) returning id"#,
+            )
+            .bind(user.username)
+            .bind(user.password_hash)
+            .bind(user.display_name)
+            .bind(user.must_change_password)
+            .fetch_one(&mut *transaction)
+            .await?;
+            sqlx::query("insert into group_members (group_id,user_id) values ($1,$2)")
+                .bind(group_id)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+            Ok::<_, sqlx::Error>(Some(user_id))
+        }
+        .await)?;
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum_login::AuthnBackend;
+
+    use super::*;
+    use crate::{
+        db::tests::{each_database, insert_solution},
+        users::{Backend, NewUser, Role},
+    };
+
+    async fn user(backend: &Backend, username: &str, role: Role) -> User {
+        let id = backend
+            .create_user(NewUser {
+                username: username.to_string(),
+                password: "correct horse".to_string(),
+                display_name: None,
+                must_change_password: false,
+            })
+            .await
+            .unwrap();
+        backend.set_role(id, role).await.unwrap();
+        backend.get_user(&id).await.unwrap().unwrap()
+    }
+
+    fn record(username: &str) -> NewUserRecord {
+        NewUserRecord {
+            username: username.to_string(),
+            password_hash: "hash".to_string(),
+            display_name: None,
+            must_change_password: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn members_and_problems() {
+        each_database(|db| async move {
+            let backend = Backend::new(db.clone());
+            let teacher = user(&backend, "teacher", Role::Instructor).await;
+            let student = user(&backend, "student", Role::Student).await;
+            let other = user(&backend, "other", Role::Instructor).await;
+
+            let group = Group::create(&db, "class a", &teacher).await.unwrap();
+            assert!(matches!(
+                Group::create(&db, "class a", &teacher).await,
+                Err(GroupError::NameAlreadyExists)
+            ));
+            assert_eq!(
+                Group::by_name(&db, "class a").await.unwrap().unwrap().id,
+                group.id
+            );
+            assert_eq!(Group::list_for(&db, &teacher).await.unwrap().len(), 1);
+            assert!(Group::list_for(&db, &other).await.unwrap().is_empty());
+
+            group.add_member(&db, "student").await.unwrap();
+            group.add_user(&db, student.id).await.unwrap();
+            assert!(matches!(
+                group.add_member(&db, "nobody").await,
+                Err(GroupError::UserNotFound(_))
+            ));
+            let members = group.members(&db).await.unwrap();
+            assert_eq!(members.len(), 1);
+            assert_eq!(members[0].username, "student");
+
+            group.assign_problem(&db, "hello").await.unwrap();
+            group.assign_problem(&db, "hello").await.unwrap();
+            group.assign_problem(&db, "loop").await.unwrap();
+            group.unassign_problem(&db, "loop").await.unwrap();
+            assert_eq!(group.problem_ids(&db).await.unwrap(), ["hello"]);
+            assert_eq!(
+                assigned_problem_ids(&db, student.id).await.unwrap(),
+                ["hello"]
+            );
+            assert!(can_solve(&db, student.id, "hello").await.unwrap());
+            assert!(!can_solve(&db, student.id, "loop").await.unwrap());
+            assert!(can_solve(&db, teacher.id, "loop").await.unwrap());
+
+            let solution = insert_solution(&db, student.id, "hello", "AC").await;
+            let solutions = group.solutions(&db).await.unwrap();
+            assert_eq!(solutions.len(), 1);
+            assert_eq!(solutions[0].id, solution);
+            assert!(can_view_submissions_of(&db, &teacher, student.id)
+                .await
+                .unwrap());
+            assert!(!can_view_submissions_of(&db, &other, student.id)
+                .await
+                .unwrap());
+
+            group.remove_member(&db, student.id).await.unwrap();
+            assert!(group.members(&db).await.unwrap().is_empty());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn unassigned_problems_stay_hidden() {
+        each_database(|db| async move {
+            let backend = Backend::new(db.clone());
+            let teacher = user(&backend, "teacher", Role::Instructor).await;
+            let student = user(&backend, "student", Role::Student).await;
+            let group = Group::create(&db, "class a", &teacher).await.unwrap();
+            group.add_user(&db, student.id).await.unwrap();
+            group.assign_problem(&db, "hello").await.unwrap();
+            insert_solution(&db, student.id, "hello", "AC").await;
+
+            // Submissions made before the set changed do not open the
+            // status and badge pages of the problem again.
+            group.assign_problem(&db, "loop").await.unwrap();
+            group.unassign_problem(&db, "hello").await.unwrap();
+            assert!(!can_solve(&db, student.id, "hello").await.unwrap());
+            assert!(can_solve(&db, student.id, "loop").await.unwrap());
+            assert_eq!(
+                crate::repository::solutions_for(&db, student.id, "hello")
+                    .await
+                    .unwrap()
+                    .len(),
+                1
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn invite_codes() {
+        each_database(|db| async move {
+            let backend = Backend::new(db.clone());
+            let teacher = user(&backend, "teacher", Role::Instructor).await;
+            let student = user(&backend, "student", Role::Student).await;
+            let group = Group::create(&db, "class b", &teacher).await.unwrap();
+
+            let invite = InviteCode::create(&db, &group, &teacher, 1, Some(7))
+                .await
+                .unwrap();
+            assert!(invite.expires_at.unwrap() > OffsetDateTime::now_utc());
+            let expired = InviteCode::create(&db, &group, &teacher, 5, Some(-1))
+                .await
+                .unwrap();
+            let no_expiry = InviteCode::create(&db, &group, &teacher, 5, None)
+                .await
+                .unwrap();
+
+            // A taken username fails the whole registration and keeps the use.
+            assert!(InviteCode::register(&db, &invite.code, record("student"))
+                .await
+                .is_err());
+            let joined = InviteCode::register(&db, &invite.code, record("joined"))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(InviteCode::register(&db, &invite.code, record("late"))
+                .await
+                .unwrap()
+                .is_none());
+            assert!(InviteCode::register(&db, &expired.code, record("late"))
+                .await
+                .unwrap()
+                .is_none());
+            let members = group.members(&db).await.unwrap();
+            assert_eq!(members.len(), 1);
+            assert_eq!(members[0].id, joined);
+            assert_ne!(joined, student.id);
+            let invites = InviteCode::list_for_group(&db, &group).await.unwrap();
+            assert_eq!(invites[0].uses, 1);
+
+            InviteCode::delete(&db, &group, no_expiry.id).await.unwrap();
+            assert!(InviteCode::register(&db, &no_expiry.code, record("late"))
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(
+                InviteCode::list_for_group(&db, &group).await.unwrap().len(),
+                2
+            );
+        })
+        .await;
+    }
+}
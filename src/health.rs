@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    db::{with_pool, Db},
+    executor,
+    problems::ProblemsInfo,
+};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Component {
+    /// `ok`, `error`, or `skipped` for parts this process does not use.
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Component {
+    fn check(result: Result<String, String>) -> Self {
+        match result {
+            Ok(detail) => Component {
+                status: "ok",
+                detail: Some(detail),
+            },
+            Err(detail) => Component {
+                status: "error",
+                detail: Some(detail),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub components: BTreeMap<&'static str, Component>,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+async fn database(db: &Db) -> Result<String, String> {
+    with_pool!(db, pool => sqlx::query_scalar::<_, i32>("select 1").fetch_one(pool).await)
+        .map(|_| "reachable".to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Every migration built into this binary has been applied.
+async fn migrations(db: &Db) -> Result<String, String> {
+    let applied: Vec<i64> = with_pool!(db, pool => sqlx::query_scalar(
+        "select version from _sqlx_migrations where success = true",
+    )
+    .fetch_all(pool)
+    .await)
+    .map_err(|e| e.to_string())?;
+    let missing: Vec<String> = db
+        .migrator()
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+        .map(|migration| migration.version.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("not applied: {}", missing.join(", ")));
+    }
+    Ok(format!("{} applied", applied.len()))
+}
+
+async fn problems() -> Result<String, String> {
+    let problems = ProblemsInfo::get_cached_problems_info()
+        .await
+        .map_err(|e| format!("{:#}", e))?;
+    if problems.problem.is_empty() {
+        return Err("info.toml lists no problems".to_string());
+    }
+    Ok(format!("{} loaded", problems.problem.len()))
+}
+
+/// Checks everything the server needs to take submissions. The executor is
+/// only checked if this process judges.
+pub async fn readiness(db: &Db, local_executor: bool) -> Readiness {
+    let executor = async {
+        if !local_executor {
+            return Component {
+                status: "skipped",
+                detail: Some("judged by separate workers".to_string()),
+            };
+        }
+        Component::check(
+            executor::check_docker()
+                .await
+                .map(|()| "docker is available".to_string())
+                .map_err(|e| format!("{:#}", e)),
+        )
+    };
+    let (database, migrations, problems, executor) =
+        tokio::join!(database(db), migrations(db), problems(), executor);
+
+    let components = BTreeMap::from([
+        ("database", Component::check(database)),
+        ("migrations", Component::check(migrations)),
+        ("problems", Component::check(problems)),
+        ("executor", executor),
+    ]);
+    let ready = components.values().all(|c| c.status != "error");
+    Readiness {
+        status: if ready { "ok" } else { "error" },
+        components,
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    groups::Group,
+    users::{random_readable_string, Backend, NewUser, User},
+};
+
+const PASSWORD_LENGTH: usize = 10;
+
+/// One line of the uploaded CSV. `groups` may list several group names
+/// separated by `;`.
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    username: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    groups: Option<String>,
+}
+
+/// One line of the credentials CSV handed back to the instructor.
+#[derive(Serialize)]
+pub struct ImportedCredential {
+    pub username: String,
+    pub display_name: String,
+    pub password: String,
+    pub groups: String,
+    pub status: String,
+}
+
+fn parse_rows(reader: impl io::Read) -> Result<Vec<ImportRow>> {
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    let mut csv = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    for (index, row) in csv.deserialize::<ImportRow>().enumerate() {
+        match row {
+            Ok(row) if row.username.is_empty() => {
+                errors.push(format!("row {}: username is empty", index + 1))
+            }
+            Ok(row) => rows.push(row),
+            Err(e) => errors.push(format!("row {}: {}", index + 1, e)),
+        }
+    }
+    if !errors.is_empty() {
+        bail!("invalid CSV\n{}", errors.join("\n"));
+    }
+    Ok(rows)
+}
+
+/// Creates an account for every row of `reader` with a random one-time
+/// password and puts it into the listed groups, creating missing groups owned
+/// by `group_owner`. Rows that fail (e.g. a taken username) are reported in
+/// the `status` column instead of aborting the whole import.
+pub async fn import_users(
+    backend: &Backend,
+    reader: impl io::Read,
+    group_owner: &User,
+) -> Result<Vec<ImportedCredential>> {
+    let rows = parse_rows(reader)?;
+    let mut credentials = Vec::with_capacity(rows.len());
+    for row in rows {
+        let group_names: Vec<&str> = row
+            .groups
+            .as_deref()
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .collect();
+        let mut credential = ImportedCredential {
+            username: row.username.clone(),
+            display_name: row.display_name.clone().unwrap_or_default(),
+            password: String::new(),
+            groups: group_names.join(";"),
+            status: String::new(),
+        };
+        let password = random_readable_string(PASSWORD_LENGTH);
+        let user_id = match backend
+            .create_user(NewUser {
+                username: row.username,
+                password: password.clone(),
+                display_name: row.display_name.filter(|s| !s.is_empty()),
+                must_change_password: true,
+            })
+            .await
+        {
+            Ok(id) => id,
+            Err(e) => {
+                credential.status = e.to_string();
+                credentials.push(credential);
+                continue;
+            }
+        };
+        credential.password = password;
+        credential.status = "created".to_string();
+        for name in group_names {
+            let group = match Group::by_name(&backend.db, name).await? {
+                Some(group) => group,
+                None => Group::create(&backend.db, name, group_owner).await?,
+            };
+            group.add_user(&backend.db, user_id).await?;
+        }
+        credentials.push(credential);
+    }
+    Ok(credentials)
+}
+
+pub fn credentials_csv(credentials: &[ImportedCredential]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for credential in credentials {
+        writer.serialize(credential)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// `import-users <file.csv|-> [--owner <username>]`
+///
+/// Prints the credentials CSV to stdout.
+pub async fn cli(backend: Backend, path: &str, owner: &str) -> Result<()> {
+    let mut input = Vec::new();
+    if path == "-" {
+        tokio::io::stdin().read_to_end(&mut input).await?;
+    } else {
+        input = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("read {}", path))?;
+    }
+    let owner = backend
+        .get_user_by_username(owner)
+        .await?
+        .with_context(|| format!("group owner {} does not exist", owner))?;
+
+    let credentials = import_users(&backend, input.as_slice(), &owner).await?;
+    print!("{}", String::from_utf8(credentials_csv(&credentials)?)?);
+    Ok(())
+}
@@ -0,0 +1,526 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use openidconnect::{reqwest, url::Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{default_lease_secs, get_cached_config},
+    db::{self, with_pool, Db},
+    executor::{self, Diagnostics, OutputLimits},
+    lti, metrics,
+    repository::{self, SourceFile, Status, Verdict, MAIN_SOURCE},
+    rules,
+};
+
+/// Name of the judge running inside this process in `judge_claims`.
+pub static LOCAL_WORKER: Lazy<String> = Lazy::new(|| format!("local-{}", std::process::id()));
+
+/// A pending submission handed to a remote worker.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub solution_id: i64,
+    pub problem_id: String,
+    /// `main.c`, all that workers before `files` read.
+    pub source: String,
+    /// Every file of the submission, `main.c` included.
+    #[serde(default)]
+    pub files: Vec<SourceFile>,
+    #[serde(default)]
+    pub build: Option<String>,
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+    /// Hash of the problem bundle, so workers only download it once.
+    pub bundle_hash: String,
+    #[serde(default)]
+    pub limits: OutputLimits,
+    /// Seconds until the claim runs out unless it is renewed, not sent by
+    /// servers that cannot renew claims.
+    #[serde(default)]
+    pub lease_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimRequest {
+    pub worker: String,
+}
+
+/// Asks to keep the claim on a submission that is still being judged.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RenewRequest {
+    pub solution_id: i64,
+    pub worker: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobResult {
+    pub solution_id: i64,
+    pub worker: String,
+    #[serde(flatten)]
+    pub verdict: Verdict,
+    /// Time the worker took to judge, for the metrics.
+    #[serde(default)]
+    pub duration_secs: f64,
+}
+
+/// Claims taken before this may be taken over.
+async fn lease_secs() -> i64 {
+    match get_cached_config().await {
+        Ok(config) => config
+            .judge
+            .as_ref()
+            .map_or(default_lease_secs(), |judge| judge.lease_secs),
+        Err(_) => default_lease_secs(),
+    }
+}
+
+async fn lease_cutoff() -> time::PrimitiveDateTime {
+    db::utc_now() - time::Duration::seconds(lease_secs().await)
+}
+
+/// Marks the submission as taken by `worker`, unless another worker holds it
+/// and its lease has not run out.
+pub async fn try_claim(db: &Db, solution_id: i64, worker: &str) -> Result<bool> {
+    let expired = lease_cutoff().await;
+    let result = with_pool!(db, pool => sqlx::query(
+        "insert into judge_claims (solution_id, worker, claimed_at) values ($1, $2, current_timestamp)
+        on conflict (solution_id) do update set worker = excluded.worker, claimed_at = excluded.claimed_at
+        where judge_claims.claimed_at < $3",
+    )
+    .bind(solution_id)
+    .bind(worker)
+    .bind(expired)
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected()))?;
+    Ok(result == 1)
+}
+
+pub async fn release(db: &Db, solution_id: i64, worker: &str) -> Result<()> {
+    with_pool!(db, pool => sqlx::query(
+        "delete from judge_claims where solution_id = $1 and worker = $2",
+    )
+    .bind(solution_id)
+    .bind(worker)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    Ok(())
+}
+
+/// Starts the lease of `worker` on `solution_id` over. `false` if it does
+/// not hold the claim any more.
+pub async fn renew_claim(db: &Db, solution_id: i64, worker: &str) -> Result<bool> {
+    let renewed = with_pool!(db, pool => sqlx::query(
+        "update judge_claims set claimed_at = current_timestamp where solution_id = $1 and worker = $2",
+    )
+    .bind(solution_id)
+    .bind(worker)
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected()))?;
+    Ok(renewed == 1)
+}
+
+/// How often a claim held for `lease_secs` is renewed.
+fn renewal_period(lease_secs: i64) -> Duration {
+    Duration::from_secs((lease_secs / 2).max(1) as u64)
+}
+
+/// Renews the claim of `worker` on `solution_id` every half lease until the
+/// task is aborted, so that judging longer than a lease is not taken over
+/// by another process on the same database.
+pub async fn keep_claim(db: Db, solution_id: i64, worker: &'static str) {
+    let period = renewal_period(lease_secs().await);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match renew_claim(&db, solution_id, worker).await {
+            Ok(true) => {}
+            Ok(false) => warn!(solution_id, "lost the judge claim"),
+            Err(e) => warn!(solution_id, error = %e, "could not renew the judge claim"),
+        }
+    }
+}
+
+/// Whether `worker` still holds the claim on a pending submission.
+pub async fn holds_claim(db: &Db, solution_id: i64, worker: &str) -> Result<bool> {
+    let claim: Option<i64> = with_pool!(db, pool => sqlx::query_scalar(
+        "select c.solution_id from judge_claims c join solutions s on s.id = c.solution_id where c.solution_id = $1 and c.worker = $2 and s.status = $3",
+    )
+    .bind(solution_id)
+    .bind(worker)
+    .bind(Status::Pending)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(claim.is_some())
+}
+
+/// Claims the oldest pending submission nobody is working on.
+pub async fn claim_next(db: &Db, worker: &str) -> Result<Option<Job>> {
+    let expired = lease_cutoff().await;
+    let candidates: Vec<i64> = with_pool!(db, pool => sqlx::query_scalar(
+        "select id from solutions where status = $1 and id not in (select solution_id from judge_claims where claimed_at >= $2) order by id limit 10",
+    )
+    .bind(Status::Pending)
+    .bind(expired)
+    .fetch_all(pool)
+    .await)?;
+    for id in candidates {
+        // Another worker may have been faster.
+        if !try_claim(db, id, worker).await? {
+            continue;
+        }
+        let solution = repository::solution(db, id)
+            .await?
+            .context("submission does not exist")?;
+        let files = repository::source_files(db, &solution).await?;
+        // Broken rules need no worker. Rules that stopped compiling since
+        // the start keep only this submission waiting, not the ones after.
+        match rules::verdict(&solution.problem_id, &files).await {
+            Ok(Some(verdict)) => {
+                finish(db, id, &verdict, Duration::ZERO).await?;
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(solution_id = id, error = %e, "could not check the rules");
+                release(db, id, worker).await?;
+                continue;
+            }
+        }
+        let problems_dir = &get_cached_config().await?.problems.dir;
+        let bundle = bundle(&problems_dir.join(&solution.problem_id)).await?;
+        return Ok(Some(Job {
+            solution_id: id,
+            source: files
+                .iter()
+                .find(|file| file.name == MAIN_SOURCE)
+                .map(|file| file.content.clone())
+                .unwrap_or_default(),
+            files,
+            build: Some(executor::build_command(&solution.problem_id).await),
+            diagnostics: executor::problem_diagnostics(&solution.problem_id).await,
+            problem_id: solution.problem_id,
+            bundle_hash: bundle.hash,
+            limits: OutputLimits::configured().await?,
+            lease_secs: Some(lease_secs().await),
+        }));
+    }
+    Ok(None)
+}
+
+/// Stores the verdict and lets go of the claim.
+pub async fn finish(
+    db: &Db,
+    solution_id: i64,
+    verdict: &Verdict,
+    duration: Duration,
+) -> Result<()> {
+    let solution = repository::solution(db, solution_id)
+        .await?
+        .context("submission does not exist")?;
+    repository::record_verdict(db, solution_id, verdict).await?;
+    with_pool!(db, pool => sqlx::query("delete from judge_claims where solution_id = $1")
+        .bind(solution_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+    metrics::record_verdict(&solution.problem_id, verdict.status.as_str(), duration);
+    tokio::spawn(lti::post_score(db.clone(), solution_id));
+    Ok(())
+}
+
+/// The test case files of a problem as a tar archive.
+pub struct Bundle {
+    /// SHA-256 of `tar`, in hex.
+    pub hash: String,
+    pub tar: Vec<u8>,
+}
+
+/// Packs the files directly in `problem_dir`, the same ones the local judge
+/// copies. Names are sorted and times zeroed, so the hash only changes with
+/// the content.
+pub async fn bundle(problem_dir: &Path) -> Result<Bundle> {
+    let mut files = Vec::new();
+    let mut readdir = tokio::fs::read_dir(problem_dir)
+        .await
+        .with_context(|| format!("open {}", problem_dir.display()))?;
+    while let Some(entry) = readdir.next_entry().await? {
+        if entry.file_type().await?.is_file() {
+            files.push(entry.file_name());
+        }
+    }
+    files.sort();
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for name in files {
+        let data = tokio::fs::read(problem_dir.join(&name)).await?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder.append_data(&mut header, &name, data.as_slice())?;
+    }
+    let tar = builder.into_inner()?;
+    Ok(Bundle {
+        hash: format!("{:x}", Sha256::digest(&tar)),
+        tar,
+    })
+}
+
+/// A judge on another machine, talking to the server over HTTP.
+pub struct RemoteWorker {
+    pub server: Url,
+    pub secret: String,
+    pub name: String,
+    /// Unpacked problem bundles, one directory per hash.
+    pub cache_dir: PathBuf,
+    client: reqwest::Client,
+}
+
+impl RemoteWorker {
+    pub fn new(server: Url, secret: String, name: String, cache_dir: PathBuf) -> Result<Self> {
+        // Longer than any long poll the server may be configured with.
+        let client = reqwest::ClientBuilder::new()
+            .timeout(Duration::from_secs(300))
+            .build()?;
+        Ok(RemoteWorker {
+            server,
+            secret,
+            name,
+            cache_dir,
+            client,
+        })
+    }
+
+    async fn claim(&self) -> Result<Option<Job>> {
+        let body = serde_json::to_vec(&ClaimRequest {
+            worker: self.name.clone(),
+        })?;
+        let response = self
+            .client
+            .post(self.server.join("judge/claim")?)
+            .bearer_auth(&self.secret)
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        if response.status() == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&response.bytes().await?)?))
+    }
+
+    /// The unpacked bundle for `job`, downloaded unless it is cached.
+    async fn bundle_dir(&self, job: &Job) -> Result<PathBuf> {
+        if job.bundle_hash.len() != 64 || !job.bundle_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid bundle hash {}", job.bundle_hash);
+        }
+        let dir = self.cache_dir.join(&job.bundle_hash);
+        if tokio::fs::try_exists(&dir).await? {
+            return Ok(dir);
+        }
+
+        let tar = self
+            .client
+            .get(
+                self.server
+                    .join(&format!("judge/bundle/{}", job.problem_id))?,
+            )
+            .bearer_auth(&self.secret)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        // The problem may have changed since the job was handed out, the
+        // bundle is filed under what actually arrived.
+        let hash = format!("{:x}", Sha256::digest(&tar));
+        let dir = self.cache_dir.join(&hash);
+        if tokio::fs::try_exists(&dir).await? {
+            return Ok(dir);
+        }
+        info!(
+            problem_id = job.problem_id,
+            hash, "downloaded problem bundle"
+        );
+        tokio::fs::create_dir_all(&self.cache_dir).await?;
+        let unpacking = tempdir::TempDir::new_in(&self.cache_dir, "unpack")?;
+        let target = unpacking.path().to_path_buf();
+        tokio::task::spawn_blocking(move || tar::Archive::new(tar.as_ref()).unpack(target))
+            .await??;
+        // Another job may have unpacked the same bundle in the meantime.
+        if let Err(e) = tokio::fs::rename(unpacking.into_path(), &dir).await {
+            if !tokio::fs::try_exists(&dir).await? {
+                return Err(e.into());
+            }
+        }
+        Ok(dir)
+    }
+
+    async fn renew(&self, solution_id: i64) -> Result<()> {
+        let body = serde_json::to_vec(&RenewRequest {
+            solution_id,
+            worker: self.name.clone(),
+        })?;
+        self.client
+            .post(self.server.join("judge/renew")?)
+            .bearer_auth(&self.secret)
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Renews the claim on `job` every half lease for as long as it is
+    /// polled, so that judging longer than a lease is not handed out again.
+    async fn keep_claim(&self, job: &Job) {
+        let Some(lease_secs) = job.lease_secs else {
+            return std::future::pending().await;
+        };
+        let period = renewal_period(lease_secs);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.renew(job.solution_id).await {
+                warn!(solution_id = job.solution_id, error = %e, "could not renew the judge claim");
+            }
+        }
+    }
+
+    async fn report(&self, result: &JobResult) -> Result<()> {
+        self.client
+            .post(self.server.join("judge/result")?)
+            .bearer_auth(&self.secret)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(result)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Claims, judges and reports one submission. `false` if there was none.
+    async fn run_once(&self) -> Result<bool> {
+        let Some(job) = self.claim().await? else {
+            return Ok(false);
+        };
+        debug!(solution_id = job.solution_id, "claimed");
+        let started = Instant::now();
+        let judging = async {
+            let dir = self.bundle_dir(&job).await?;
+            // Servers before `files` only send `main.c`.
+            let files = if job.files.is_empty() {
+                vec![SourceFile {
+                    name: MAIN_SOURCE.to_string(),
+                    content: job.source.clone(),
+                }]
+            } else {
+                job.files.clone()
+            };
+            let build = job.build.as_deref().unwrap_or(executor::DEFAULT_BUILD);
+            executor::check(&files, build, job.diagnostics, &dir, job.limits).await
+        };
+        let verdict = tokio::select! {
+            verdict = judging => verdict?,
+            () = self.keep_claim(&job) => unreachable!("claims are kept until judging ends"),
+        };
+        info!(
+            solution_id = job.solution_id,
+            status = verdict.status.as_str(),
+            "judged"
+        );
+        self.report(&JobResult {
+            solution_id: job.solution_id,
+            worker: self.name.clone(),
+            verdict,
+            duration_secs: started.elapsed().as_secs_f64(),
+        })
+        .await?;
+        Ok(true)
+    }
+
+    /// Judges submissions from the server until the process is stopped, with
+    /// up to `concurrency` at a time. A submission that fails here is handed
+    /// out again once its lease runs out.
+    pub async fn run(self, concurrency: usize) -> Result<()> {
+        let worker = Arc::new(self);
+        let mut loops = tokio::task::JoinSet::new();
+        for _ in 0..concurrency.max(1) {
+            let worker = worker.clone();
+            loops.spawn(async move {
+                loop {
+                    if let Err(e) = worker.run_once().await {
+                        warn!(error = %e, "remote judging failed, retrying");
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                }
+            });
+        }
+        while loops.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::tests::{each_database, insert_solution},
+        repository::NewUserRecord,
+    };
+
+    #[tokio::test]
+    async fn one_claim_per_submission() {
+        each_database(|db| async move {
+            let user_id = repository::insert_user(
+                &db,
+                NewUserRecord {
+                    username: "alice".to_string(),
+                    password_hash: "hash".to_string(),
+                    display_name: None,
+                    must_change_password: false,
+                },
+            )
+            .await
+            .unwrap();
+            let id = insert_solution(&db, user_id, "hello", "Pending").await;
+            let expire = |db: Db| async move {
+                with_pool!(&db, pool => sqlx::query("update judge_claims set claimed_at = $1")
+                    .bind(db::utc_now() - time::Duration::days(1))
+                    .execute(pool)
+                    .await
+                    .map(|_| ()))
+                .unwrap();
+            };
+
+            // A server and a worker on the same database.
+            assert!(try_claim(&db, id, "local-1").await.unwrap());
+            assert!(!try_claim(&db, id, "local-2").await.unwrap());
+            assert!(holds_claim(&db, id, "local-1").await.unwrap());
+
+            expire(db.clone()).await;
+            assert!(renew_claim(&db, id, "local-1").await.unwrap());
+            assert!(!try_claim(&db, id, "local-2").await.unwrap());
+
+            expire(db.clone()).await;
+            assert!(try_claim(&db, id, "local-2").await.unwrap());
+            assert!(!holds_claim(&db, id, "local-1").await.unwrap());
+            // The worker that lost it cannot renew it either.
+            assert!(!renew_claim(&db, id, "local-1").await.unwrap());
+            assert!(renew_claim(&db, id, "local-2").await.unwrap());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,677 @@
+use std::path::Path;
+
+use axum_login::AuthnBackend;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode, jwk::JwkSet, Algorithm, DecodingKey, EncodingKey, Header,
+    Validation,
+};
+use once_cell::sync::Lazy;
+use openidconnect::{reqwest, url::Url};
+use rand::{distributions::Alphanumeric, Rng};
+use rsa::{
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding},
+    traits::PublicKeyParts,
+    RsaPrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::{
+    config::{get_cached_config, LtiConfiguration, LtiPlatformConfiguration},
+    db::{self, with_pool, Db},
+    groups::{Group, GroupError},
+    oidc::{self, string_claim, OidcError, Provisioning},
+    problems::Problem,
+    repository::{self, Status},
+    users::{self, Backend, Role, User},
+};
+
+const CLAIM_MESSAGE_TYPE: &str = "https://purl.imsglobal.org/spec/lti/claim/message_type";
+const CLAIM_VERSION: &str = "https://purl.imsglobal.org/spec/lti/claim/version";
+const CLAIM_DEPLOYMENT_ID: &str = "https://purl.imsglobal.org/spec/lti/claim/deployment_id";
+const CLAIM_TARGET_LINK_URI: &str = "https://purl.imsglobal.org/spec/lti/claim/target_link_uri";
+const CLAIM_ROLES: &str = "https://purl.imsglobal.org/spec/lti/claim/roles";
+const CLAIM_CONTEXT: &str = "https://purl.imsglobal.org/spec/lti/claim/context";
+const CLAIM_CUSTOM: &str = "https://purl.imsglobal.org/spec/lti/claim/custom";
+const CLAIM_AGS_ENDPOINT: &str = "https://purl.imsglobal.org/spec/lti-ags/claim/endpoint";
+const CLAIM_DEEP_LINKING_SETTINGS: &str =
+    "https://purl.imsglobal.org/spec/lti-dl/claim/deep_linking_settings";
+const CLAIM_CONTENT_ITEMS: &str = "https://purl.imsglobal.org/spec/lti-dl/claim/content_items";
+const CLAIM_DEEP_LINKING_DATA: &str = "https://purl.imsglobal.org/spec/lti-dl/claim/data";
+const SCOPE_SCORE: &str = "https://purl.imsglobal.org/spec/lti-ags/scope/score";
+
+/// How long a login started at `/lti/login` may take to come back.
+const LAUNCH_TIMEOUT: time::Duration = time::Duration::minutes(10);
+
+#[derive(Debug, Error)]
+pub enum LtiError {
+    #[error("The platform {0} is not registered")]
+    UnknownPlatform(String),
+    #[error("The deployment {0} is not registered")]
+    UnknownDeployment(String),
+    #[error("The launch expired or was already used, please start it again from the LMS")]
+    InvalidState,
+    #[error("The launch token is invalid: {0}")]
+    InvalidToken(String),
+    #[error("Unsupported LTI message: {0}")]
+    UnsupportedMessage(String),
+    #[error("The problem {0} does not exist")]
+    UnknownProblem(String),
+    #[error("Only instructors can add problems to a course")]
+    NotInstructor,
+    #[error("The tool key could not be loaded: {0}")]
+    Key(String),
+    #[error("Request to the platform failed: {0}")]
+    Http(String),
+    #[error(transparent)]
+    Account(#[from] OidcError),
+    #[error("Database access error")]
+    Database(#[from] sqlx::Error),
+    #[error("Database access error")]
+    Group(#[from] GroupError),
+    #[error("Database access error")]
+    Backend(#[from] users::Error),
+}
+
+impl From<reqwest::Error> for LtiError {
+    fn from(e: reqwest::Error) -> Self {
+        LtiError::Http(e.to_string())
+    }
+}
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap()
+});
+
+fn platform<'a>(
+    config: &'a LtiConfiguration,
+    issuer: &str,
+    client_id: Option<&str>,
+) -> Result<&'a LtiPlatformConfiguration, LtiError> {
+    config
+        .platforms
+        .iter()
+        .find(|p| p.issuer == issuer && client_id.is_none_or(|id| p.client_id == id))
+        .ok_or_else(|| LtiError::UnknownPlatform(issuer.to_string()))
+}
+
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// The RSA key of this tool. Platforms fetch the public half from
+/// `/lti/jwks` to check deep linking responses and grade service requests.
+pub struct ToolKey {
+    kid: String,
+    encoding: EncodingKey,
+    jwk: Value,
+}
+
+static TOOL_KEY: OnceCell<ToolKey> = OnceCell::const_new();
+
+impl ToolKey {
+    pub async fn get(config: &LtiConfiguration) -> Result<&'static ToolKey, LtiError> {
+        TOOL_KEY
+            .get_or_try_init(|| ToolKey::load(&config.private_key_path))
+            .await
+    }
+
+    async fn load(path: &Path) -> Result<ToolKey, LtiError> {
+        let pem = match tokio::fs::read_to_string(path).await {
+            Ok(pem) => pem,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(?path, "generating a new LTI tool key");
+                let pem = tokio::task::spawn_blocking(|| {
+                    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048)?;
+                    Ok::<_, rsa::Error>(key.to_pkcs8_pem(LineEnding::LF)?.to_string())
+                })
+                .await
+                .map_err(|e| LtiError::Key(e.to_string()))?
+                .map_err(|e| LtiError::Key(e.to_string()))?;
+                tokio::fs::write(path, &pem)
+                    .await
+                    .map_err(|e| LtiError::Key(e.to_string()))?;
+                pem
+            }
+            Err(e) => return Err(LtiError::Key(e.to_string())),
+        };
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem).map_err(|e| LtiError::Key(e.to_string()))?;
+        let n = key.n().to_bytes_be();
+        let e = key.e().to_bytes_be();
+        let kid = format!("{:.16x}", Sha256::digest(&n));
+        let encoding =
+            EncodingKey::from_rsa_pem(pem.as_bytes()).map_err(|e| LtiError::Key(e.to_string()))?;
+        let jwk = json!({
+            "kty": "RSA",
+            "alg": "RS256",
+            "use": "sig",
+            "kid": kid,
+            "n": URL_SAFE_NO_PAD.encode(n),
+            "e": URL_SAFE_NO_PAD.encode(e),
+        });
+        Ok(ToolKey { kid, encoding, jwk })
+    }
+
+    pub fn jwks(&self) -> Value {
+        json!({ "keys": [self.jwk] })
+    }
+
+    fn sign(&self, claims: &Value) -> Result<String, LtiError> {
+        let header = Header {
+            kid: Some(self.kid.clone()),
+            ..Header::new(Algorithm::RS256)
+        };
+        encode(&header, claims, &self.encoding).map_err(|e| LtiError::Key(e.to_string()))
+    }
+}
+
+/// Third party initiated login, the first step of every launch.
+#[derive(Debug, Deserialize)]
+pub struct LoginInitiation {
+    pub iss: String,
+    pub login_hint: String,
+    pub target_link_uri: String,
+    pub lti_message_hint: Option<String>,
+    pub client_id: Option<String>,
+}
+
+/// Remembers state and nonce of the launch and returns the platform URL the
+/// browser has to go to. They are kept in the database rather than the
+/// session since the launch comes back as a cross-site POST, which does not
+/// carry the session cookie.
+pub async fn begin_launch(
+    db: &Db,
+    config: &LtiConfiguration,
+    initiation: LoginInitiation,
+) -> Result<Url, LtiError> {
+    let platform = platform(config, &initiation.iss, initiation.client_id.as_deref())?;
+    if !initiation.target_link_uri.starts_with(&config.tool_url) {
+        return Err(LtiError::UnsupportedMessage(format!(
+            "target {} is not part of this tool",
+            initiation.target_link_uri
+        )));
+    }
+    let state = random_token();
+    let nonce = random_token();
+    with_pool!(db, pool => sqlx::query("delete from lti_nonces where created_at < $1")
+        .bind(db::utc_now() - LAUNCH_TIMEOUT)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+    with_pool!(db, pool => sqlx::query(
+        "insert into lti_nonces (state,nonce,issuer,client_id,created_at) values ($1,$2,$3,$4,current_timestamp)",
+    )
+    .bind(&state)
+    .bind(&nonce)
+    .bind(&platform.issuer)
+    .bind(&platform.client_id)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+
+    let redirect_uri = format!("{}/lti/launch", config.tool_url.trim_end_matches('/'));
+    let mut params = vec![
+        ("scope", "openid"),
+        ("response_type", "id_token"),
+        ("response_mode", "form_post"),
+        ("prompt", "none"),
+        ("client_id", platform.client_id.as_str()),
+        ("redirect_uri", redirect_uri.as_str()),
+        ("login_hint", initiation.login_hint.as_str()),
+        ("state", state.as_str()),
+        ("nonce", nonce.as_str()),
+    ];
+    if let Some(ref hint) = initiation.lti_message_hint {
+        params.push(("lti_message_hint", hint));
+    }
+    Url::parse_with_params(&platform.auth_login_url, params)
+        .map_err(|e| LtiError::UnknownPlatform(e.to_string()))
+}
+
+/// Checks the ID token the platform posted to `/lti/launch` and returns its
+/// claims.
+pub async fn verify_launch<'a>(
+    db: &Db,
+    config: &'a LtiConfiguration,
+    id_token: &str,
+    state: &str,
+) -> Result<(&'a LtiPlatformConfiguration, Map<String, Value>), LtiError> {
+    let (nonce, issuer, client_id): (String, String, Option<String>) = with_pool!(db, pool => sqlx::query_as(
+        "delete from lti_nonces where state = $1 and created_at >= $2 returning nonce, issuer, client_id",
+    )
+    .bind(state)
+    .bind(db::utc_now() - LAUNCH_TIMEOUT)
+    .fetch_all(pool)
+    .await)?
+    .pop()
+    .ok_or(LtiError::InvalidState)?;
+    // Several platforms can share an issuer, one per client id.
+    let platform = platform(config, &issuer, client_id.as_deref())?;
+
+    let header = decode_header(id_token).map_err(|e| LtiError::InvalidToken(e.to_string()))?;
+    if !matches!(
+        header.alg,
+        Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+    ) {
+        return Err(LtiError::InvalidToken(format!(
+            "unsupported algorithm {:?}",
+            header.alg
+        )));
+    }
+    let jwks: JwkSet = serde_json::from_slice(
+        &HTTP
+            .get(&platform.jwks_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?,
+    )
+    .map_err(|e| LtiError::Http(e.to_string()))?;
+    let jwk = match header.kid {
+        Some(ref kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or_else(|| LtiError::InvalidToken("unknown signing key".to_string()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|e| LtiError::InvalidToken(e.to_string()))?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&platform.issuer]);
+    validation.set_audience(&[&platform.client_id]);
+    let claims = decode::<Map<String, Value>>(id_token, &key, &validation)
+        .map_err(|e| LtiError::InvalidToken(e.to_string()))?
+        .claims;
+
+    if string_claim(&claims, "nonce") != Some(nonce.as_str()) {
+        return Err(LtiError::InvalidToken("nonce mismatch".to_string()));
+    }
+    if string_claim(&claims, CLAIM_VERSION) != Some("1.3.0") {
+        return Err(LtiError::UnsupportedMessage("LTI version".to_string()));
+    }
+    let deployment_id = string_claim(&claims, CLAIM_DEPLOYMENT_ID).unwrap_or_default();
+    if !platform.deployment_ids.is_empty()
+        && !platform.deployment_ids.iter().any(|id| id == deployment_id)
+    {
+        return Err(LtiError::UnknownDeployment(deployment_id.to_string()));
+    }
+    Ok((platform, claims))
+}
+
+/// What an instructor is asked to pick problems for. Kept in the session
+/// until the selection is posted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeepLinkRequest {
+    pub issuer: String,
+    pub client_id: String,
+    pub deployment_id: String,
+    pub return_url: String,
+    pub data: Option<String>,
+    /// The group of the course, which the picked problem is assigned to.
+    #[serde(default)]
+    pub group_id: Option<i64>,
+}
+
+pub enum LaunchTarget {
+    Problem(String),
+    DeepLinking(DeepLinkRequest),
+}
+
+fn is_instructor(claims: &Map<String, Value>) -> bool {
+    claims
+        .get(CLAIM_ROLES)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .any(|role| role.ends_with("#Instructor") || role.ends_with("#Administrator"))
+}
+
+/// The problem a resource link points at: the `problem_id` custom parameter,
+/// or `/problem/<id>/solve` in the target link.
+fn linked_problem_id(claims: &Map<String, Value>) -> Option<String> {
+    if let Some(id) = claims
+        .get(CLAIM_CUSTOM)
+        .and_then(|custom| custom.get("problem_id"))
+        .and_then(Value::as_str)
+    {
+        return Some(id.to_string());
+    }
+    let target = Url::parse(string_claim(claims, CLAIM_TARGET_LINK_URI)?).ok()?;
+    let mut segments = target.path_segments()?;
+    segments.find(|segment| *segment == "problem")?;
+    segments.next().map(str::to_string)
+}
+
+/// The group standing for the course of the launch, created on the first
+/// launch from it. Instructors creating it become its owner, otherwise the
+/// first admin does.
+async fn context_group(
+    backend: &Backend,
+    platform: &LtiPlatformConfiguration,
+    context: &Map<String, Value>,
+    user: &User,
+    instructor: bool,
+) -> Result<Option<Group>, LtiError> {
+    let Some(context_id) = string_claim(context, "id") else {
+        return Ok(None);
+    };
+    let link: Option<i64> = with_pool!(&backend.db, pool => sqlx::query_scalar(
+        "select group_id from lti_contexts where issuer = $1 and context_id = $2",
+    )
+    .bind(&platform.issuer)
+    .bind(context_id)
+    .fetch_optional(pool)
+    .await)?;
+    if let Some(group_id) = link {
+        if let Some(group) = Group::by_id(&backend.db, group_id).await? {
+            return Ok(Some(group));
+        }
+    }
+
+    let owner = if instructor {
+        user.clone()
+    } else {
+        let admin: Option<i64> = with_pool!(&backend.db, pool => sqlx::query_scalar(
+            "select id from \"user\" where role = 'admin' order by id limit 1",
+        )
+        .fetch_optional(pool)
+        .await)?;
+        match admin {
+            Some(admin) => backend.get_user(&admin).await?.unwrap_or(user.clone()),
+            None => user.clone(),
+        }
+    };
+    let base = string_claim(context, "title")
+        .or_else(|| string_claim(context, "label"))
+        .unwrap_or(context_id)
+        .to_string();
+    let mut name = base.clone();
+    let mut suffix = 2;
+    while Group::by_name(&backend.db, &name).await?.is_some() {
+        name = format!("{} ({})", base, suffix);
+        suffix += 1;
+    }
+    let group = Group::create(&backend.db, &name, &owner).await?;
+    with_pool!(&backend.db, pool => sqlx::query(
+        "insert into lti_contexts (issuer,context_id,group_id) values ($1,$2,$3)
+        on conflict (issuer,context_id) do update set group_id = excluded.group_id",
+    )
+    .bind(&platform.issuer)
+    .bind(context_id)
+    .bind(group.id)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    Ok(Some(group))
+}
+
+/// Maps the launching LMS user and course to an account and group here and
+/// works out where the launch should end up.
+pub async fn handle_launch(
+    backend: &Backend,
+    platform: &LtiPlatformConfiguration,
+    claims: &Map<String, Value>,
+) -> Result<(User, LaunchTarget), LtiError> {
+    let message_type = string_claim(claims, CLAIM_MESSAGE_TYPE).unwrap_or_default();
+    if !matches!(
+        message_type,
+        "LtiResourceLinkRequest" | "LtiDeepLinkingRequest"
+    ) {
+        return Err(LtiError::UnsupportedMessage(message_type.to_string()));
+    }
+
+    let provisioning = Provisioning {
+        issuer: &platform.issuer,
+        username_claim: &platform.username_claim,
+        auto_provision: true,
+        link_existing_users: platform.link_existing_users,
+    };
+    let mut user = oidc::identity_user(backend, &provisioning, claims).await?;
+    let instructor = is_instructor(claims);
+    if instructor && user.role == Role::Student {
+        backend.set_role(user.id, Role::Instructor).await?;
+        user.role = Role::Instructor;
+    }
+
+    let group = match claims.get(CLAIM_CONTEXT).and_then(Value::as_object) {
+        Some(context) => context_group(backend, platform, context, &user, instructor).await?,
+        None => None,
+    };
+    if let Some(ref group) = group {
+        group.add_user(&backend.db, user.id).await?;
+    }
+
+    if message_type == "LtiDeepLinkingRequest" {
+        if !instructor {
+            return Err(LtiError::NotInstructor);
+        }
+        let settings = claims
+            .get(CLAIM_DEEP_LINKING_SETTINGS)
+            .ok_or_else(|| LtiError::UnsupportedMessage("no deep linking settings".to_string()))?;
+        let return_url = settings
+            .get("deep_link_return_url")
+            .and_then(Value::as_str)
+            .ok_or_else(|| LtiError::UnsupportedMessage("no deep link return URL".to_string()))?;
+        let request = DeepLinkRequest {
+            issuer: platform.issuer.clone(),
+            client_id: platform.client_id.clone(),
+            deployment_id: string_claim(claims, CLAIM_DEPLOYMENT_ID)
+                .unwrap_or_default()
+                .to_string(),
+            return_url: return_url.to_string(),
+            data: settings
+                .get("data")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            group_id: group.as_ref().map(|group| group.id),
+        };
+        return Ok((user, LaunchTarget::DeepLinking(request)));
+    }
+
+    let problem_id = linked_problem_id(claims)
+        .ok_or_else(|| LtiError::UnsupportedMessage("the link names no problem".to_string()))?;
+    Problem::by_id(&problem_id)
+        .await
+        .map_err(|_| LtiError::UnknownProblem(problem_id.clone()))?;
+    // Assigning hides every other problem from the group, so only the
+    // instructor's launches pick problems, never a student's.
+    if let (true, Some(group)) = (instructor, &group) {
+        group.assign_problem(&backend.db, &problem_id).await?;
+    }
+
+    let endpoint = claims.get(CLAIM_AGS_ENDPOINT);
+    let can_post_scores = endpoint
+        .and_then(|endpoint| endpoint.get("scope"))
+        .and_then(Value::as_array)
+        .is_some_and(|scopes| scopes.iter().any(|scope| scope == SCOPE_SCORE));
+    let lineitem = endpoint
+        .and_then(|endpoint| endpoint.get("lineitem"))
+        .and_then(Value::as_str);
+    let subject = string_claim(claims, "sub").unwrap_or_default();
+    if let (true, Some(lineitem)) = (can_post_scores, lineitem) {
+        with_pool!(&backend.db, pool => sqlx::query(
+            "insert into lti_line_items (user_id,problem_id,issuer,lineitem,subject,client_id) values ($1,$2,$3,$4,$5,$6)
+            on conflict (user_id,problem_id,lineitem) do update set issuer = excluded.issuer, subject = excluded.subject, client_id = excluded.client_id",
+        )
+        .bind(user.id)
+        .bind(&problem_id)
+        .bind(&platform.issuer)
+        .bind(lineitem)
+        .bind(subject)
+        .bind(&platform.client_id)
+        .execute(pool)
+        .await
+        .map(|_| ()))?;
+    }
+    Ok((user, LaunchTarget::Problem(problem_id)))
+}
+
+/// The signed message sending the picked problem back to the platform.
+pub fn deep_link_response(
+    config: &LtiConfiguration,
+    key: &ToolKey,
+    request: &DeepLinkRequest,
+    problem: &Problem,
+) -> Result<String, LtiError> {
+    let title = problem.name.clone().unwrap_or_else(|| problem.id.clone());
+    let url = format!(
+        "{}/problem/{}/solve",
+        config.tool_url.trim_end_matches('/'),
+        problem.id
+    );
+    let mut claims = json!({
+        "iss": request.client_id,
+        "aud": request.issuer,
+        "iat": now(),
+        "exp": now() + 600,
+        "nonce": random_token(),
+        CLAIM_MESSAGE_TYPE: "LtiDeepLinkingResponse",
+        CLAIM_VERSION: "1.3.0",
+        CLAIM_DEPLOYMENT_ID: request.deployment_id,
+        CLAIM_CONTENT_ITEMS: [{
+            "type": "ltiResourceLink",
+            "title": title,
+            "url": url,
+            "custom": { "problem_id": problem.id },
+            "lineItem": {
+                "scoreMaximum": 1,
+                "label": title,
+                "resourceId": problem.id,
+            },
+        }],
+    });
+    if let Some(ref data) = request.data {
+        claims[CLAIM_DEEP_LINKING_DATA] = json!(data);
+    }
+    key.sign(&claims)
+}
+
+/// Fetches a token for the grade service with a client assertion signed by
+/// the tool key.
+async fn access_token(
+    key: &ToolKey,
+    platform: &LtiPlatformConfiguration,
+) -> Result<String, LtiError> {
+    let assertion = key.sign(&json!({
+        "iss": platform.client_id,
+        "sub": platform.client_id,
+        "aud": platform.auth_token_url,
+        "iat": now(),
+        "exp": now() + 300,
+        "jti": random_token(),
+    }))?;
+    let response: Value = serde_json::from_slice(
+        &HTTP
+            .post(&platform.auth_token_url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                (
+                    "client_assertion_type",
+                    "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+                ),
+                ("client_assertion", &assertion),
+                ("scope", SCOPE_SCORE),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?,
+    )
+    .map_err(|e| LtiError::Http(e.to_string()))?;
+    response
+        .get("access_token")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| LtiError::Http("no access token in the response".to_string()))
+}
+
+/// `<lineitem>/scores`, keeping the query string of the line item URL.
+fn scores_url(lineitem: &str) -> Result<Url, LtiError> {
+    let mut url = Url::parse(lineitem).map_err(|e| LtiError::Http(e.to_string()))?;
+    let path = format!("{}/scores", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    Ok(url)
+}
+
+async fn try_post_score(db: &Db, solution_id: i64) -> Result<(), LtiError> {
+    let Some(ref config) = get_cached_config().await.unwrap().lti else {
+        return Ok(());
+    };
+    let solution = repository::solution(db, solution_id)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+    let (userid, problem_id) = (solution.user_id, solution.problem_id.clone());
+    let line_items: Vec<(String, String, String, Option<String>)> = with_pool!(db, pool => sqlx::query_as(
+        "select issuer,lineitem,subject,client_id from lti_line_items where user_id = $1 and problem_id = $2",
+    )
+    .bind(userid)
+    .bind(&problem_id)
+    .fetch_all(pool)
+    .await)?;
+    if line_items.is_empty() {
+        return Ok(());
+    }
+    // The gradebook gets the best result, so a later failing attempt does
+    // not take back a passed problem.
+    let accepted: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from solutions where userid = $1 and problem_id = $2 and status = $3",
+    )
+    .bind(userid)
+    .bind(&problem_id)
+    .bind(Status::Accepted)
+    .fetch_one(pool)
+    .await)?;
+    let score_given = if accepted > 0 { 1 } else { 0 };
+    let timestamp = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .map_err(|e| LtiError::Http(e.to_string()))?;
+
+    let key = ToolKey::get(config).await?;
+    for (issuer, lineitem, subject, client_id) in line_items {
+        let platform = platform(config, &issuer, client_id.as_deref())?;
+        let token = access_token(key, platform).await?;
+        let score = json!({
+            "userId": subject,
+            "scoreGiven": score_given,
+            "scoreMaximum": 1,
+            "comment": solution.status.as_str(),
+            "timestamp": timestamp,
+            "activityProgress": "Completed",
+            "gradingProgress": "FullyGraded",
+        });
+        HTTP.post(scores_url(&lineitem)?)
+            .bearer_auth(token)
+            .header("content-type", "application/vnd.ims.lis.v1.score+json")
+            .body(score.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+        info!(solution_id, lineitem, "posted LTI score");
+    }
+    Ok(())
+}
+
+/// Sends the result of a judged solution to the gradebooks of the LMS
+/// courses it was launched from. Failures are only logged.
+pub async fn post_score(db: Db, solution_id: i64) {
+    if let Err(e) = try_post_score(&db, solution_id).await {
+        warn!(solution_id, error = %e, "could not post LTI score");
+    }
+}
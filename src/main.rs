@@ -1,46 +1,149 @@
-use anyhow::{Context, Result};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use cli::{Cli, Command, ServeArgs, WorkerArgs};
 use config::get_cached_config;
-use sqlx::{sqlite::SqliteConnectOptions, Pool, Sqlite, SqlitePool};
 use tokio::sync::mpsc::channel;
-use tracing::{debug, instrument};
+use tracing::{debug, info, instrument, warn};
 
+mod archive;
+mod audit;
+mod backup;
+mod cli;
 mod config;
+mod db;
 mod executor;
+mod grades;
+mod groups;
+mod health;
+mod import;
+mod judge;
+mod lti;
+mod metrics;
+mod oidc;
 mod problems;
+mod rate_limit;
+mod repository;
+mod rules;
+mod setup;
+mod similarity;
+mod submission;
 mod users;
 mod web;
 
 #[instrument]
-async fn database_init() -> Result<Pool<Sqlite>> {
-    let connection_options = SqliteConnectOptions::new()
-        .filename(&get_cached_config().await?.database.url)
-        .create_if_missing(true);
-    let pool = SqlitePool::connect_with(connection_options)
-        .await
-        .context("open databse")?;
-    sqlx::migrate!()
-        .run(&pool)
-        .await
-        .context("database migration")?;
-    Ok(pool)
+async fn database_init() -> Result<db::Db> {
+    let db = db::Db::connect(&get_cached_config().await?.database.url).await?;
+    db.migrate().await?;
+    Ok(db)
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Refuses to judge with rules that do not compile, which would keep their
+/// submissions pending.
+async fn check_rules() -> Result<()> {
+    let problems = problems::ProblemsInfo::get_cached_problems_info().await?;
+    let errors = problems.rule_errors().await?;
+    if !errors.is_empty() {
+        bail!("invalid problem rules:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
 
+async fn serve(args: ServeArgs) -> Result<()> {
     let problems = problems::ProblemsInfo::get_cached_problems_info().await?;
     for p in problems.problem.iter() {
         debug!(?p, "loaded program")
     }
+    check_rules().await?;
 
     let pool = database_init().await?;
+    setup::bootstrap(&users::Backend::new(pool.clone())).await?;
+
+    let config = get_cached_config().await?;
+    if let Some(ref backup) = config.backup {
+        tokio::spawn(backup::run_backups(pool.clone(), backup));
+    }
+    if let Some(ref retention) = config.retention {
+        tokio::spawn(backup::run_pruning(pool.clone(), retention.output_days));
+    }
 
-    let (tx, rx) = channel(100);
+    let (tx, mut rx) = channel(100);
 
-    let server_task = web::App::new(pool.clone(), tx).await?;
+    let server_task = web::App::new(pool.clone(), tx.clone(), !args.no_worker).await?;
+    if args.no_worker {
+        // Submissions are only stored, a `worker` picks them up.
+        tokio::spawn(async move { while rx.recv().await.is_some() {} });
+        return server_task.serve().await;
+    }
+    tokio::spawn(executor::poll(
+        tx,
+        Duration::from_secs(args.poll_interval.max(1)),
+    ));
     let executor_task = executor::executor_task(rx, pool);
 
     tokio::try_join!(server_task.serve(), executor_task)?;
     Ok(())
 }
+
+async fn worker(args: WorkerArgs) -> Result<()> {
+    if let (Some(server), Some(secret)) = (args.server, args.secret) {
+        let name = args
+            .name
+            .unwrap_or_else(|| format!("worker-{}", users::random_readable_string(6)));
+        info!(%server, name, "judging for a remote server");
+        if let Err(e) = executor::check_docker().await {
+            warn!(error = %e, "docker is not usable, judging will fail until it is");
+        }
+        return judge::RemoteWorker::new(server, secret, name, args.cache_dir)?
+            .run(args.concurrency)
+            .await;
+    }
+    check_rules().await?;
+    let pool = database_init().await?;
+    let (tx, rx) = channel(1);
+    tokio::spawn(executor::poll(
+        tx,
+        Duration::from_secs(args.poll_interval.max(1)),
+    ));
+    executor::executor_task(rx, pool).await
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let cli = Cli::parse();
+    config::set_config_path(cli.config);
+
+    match cli.command.unwrap_or(Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => serve(args).await,
+        Command::Worker(args) => worker(args).await,
+        Command::Migrate => {
+            database_init().await?;
+            println!("database is up to date");
+            Ok(())
+        }
+        Command::CreateUser(args) => cli::create_user(database_init().await?, args).await,
+        Command::Rejudge(args) => cli::rejudge(database_init().await?, args).await,
+        Command::ValidateProblems => cli::validate_problems().await,
+        Command::CheckConfig => cli::check_config().await,
+        Command::ImportUsers(args) => {
+            let backend = users::Backend::new(database_init().await?);
+            import::cli(backend, &args.file, &args.owner).await
+        }
+        Command::ExportGrades(args) => {
+            grades::cli(
+                database_init().await?,
+                args.group.as_deref(),
+                args.from.as_deref(),
+                args.to.as_deref(),
+                &args.format,
+            )
+            .await
+        }
+        Command::Backup(args) => cli::backup(args).await,
+        Command::Restore(args) => cli::restore(args).await,
+        Command::PruneOutput(args) => cli::prune_output(database_init().await?, args).await,
+    }
+}
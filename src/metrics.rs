@@ -0,0 +1,231 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::Response,
+    middleware::Next,
+    response::IntoResponse,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec_with_registry,
+    register_int_counter_vec_with_registry, register_int_gauge_vec_with_registry,
+    register_int_gauge_with_registry, Encoder, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+    Registry, TextEncoder,
+};
+use tower_http::trace::{DefaultOnResponse, OnResponse};
+use tracing::{Level, Span};
+
+use crate::{
+    config::get_cached_config,
+    db::{with_pool, Db},
+    repository::Status,
+};
+
+/// Everything `/metrics` shows. Counters and histograms only cover what
+/// happened in this process.
+static REGISTRY: Lazy<Registry> =
+    Lazy::new(|| Registry::new_custom(Some("occ".into()), None).unwrap());
+
+static QUEUE_LENGTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "judge_queue_length",
+        "Submissions waiting for a verdict",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static CLAIMED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "judge_claimed",
+        "Submissions currently held by a local or remote worker",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static RUNNING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "judge_running",
+        "Submissions being judged by this process",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static CONCURRENT_LIMIT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!(
+        "judge_concurrent_limit",
+        "executor.concurrent_limit",
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static JUDGE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "judge_duration_seconds",
+        "Time taken to judge one submission",
+        &["problem", "verdict"],
+        exponential_buckets(0.5, 2.0, 10).unwrap(),
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static VERDICTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec_with_registry!(
+        "judge_verdicts_total",
+        "Verdicts given",
+        &["problem", "verdict"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static HTTP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec_with_registry!(
+        "http_request_duration_seconds",
+        "Time taken to answer an HTTP request",
+        &["method", "route", "status"],
+        exponential_buckets(0.001, 4.0, 9).unwrap(),
+        REGISTRY
+    )
+    .unwrap()
+});
+
+static ACTIVE_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge_with_registry!("active_sessions", "Unexpired sessions", REGISTRY).unwrap()
+});
+
+static DB_POOL: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec_with_registry!(
+        "db_pool_connections",
+        "Database connections by state",
+        &["state"],
+        REGISTRY
+    )
+    .unwrap()
+});
+
+/// Counts a submission as being judged until dropped.
+pub struct RunningJudge(());
+
+impl RunningJudge {
+    pub fn start() -> Self {
+        RUNNING.inc();
+        RunningJudge(())
+    }
+}
+
+impl Drop for RunningJudge {
+    fn drop(&mut self) {
+        RUNNING.dec();
+    }
+}
+
+pub fn record_verdict(problem_id: &str, verdict: &str, duration: Duration) {
+    JUDGE_DURATION
+        .with_label_values(&[problem_id, verdict])
+        .observe(duration.as_secs_f64());
+    VERDICTS.with_label_values(&[problem_id, verdict]).inc();
+}
+
+/// What the HTTP histogram is labeled with, attached to the response by
+/// [`label_request`] for [`OnResponseMetrics`] to pick up.
+#[derive(Clone)]
+struct RequestLabels {
+    method: String,
+    route: String,
+}
+
+/// Remembers the route pattern rather than the path, so ids in URLs do not
+/// blow up the number of series.
+pub async fn label_request(request: Request, next: Next) -> impl IntoResponse {
+    let labels = RequestLabels {
+        method: request.method().to_string(),
+        route: request
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or("unmatched", |path| path.as_str())
+            .to_string(),
+    };
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(labels);
+    response
+}
+
+/// Logs like the default and records the latency the trace layer measured.
+#[derive(Clone)]
+pub struct OnResponseMetrics(DefaultOnResponse);
+
+impl OnResponseMetrics {
+    pub fn new(level: Level) -> Self {
+        OnResponseMetrics(DefaultOnResponse::new().level(level))
+    }
+}
+
+impl<B> OnResponse<B> for OnResponseMetrics {
+    fn on_response(self, response: &Response<B>, latency: Duration, span: &Span) {
+        if let Some(labels) = response.extensions().get::<RequestLabels>() {
+            HTTP_DURATION
+                .with_label_values(&[&labels.method, &labels.route, response.status().as_str()])
+                .observe(latency.as_secs_f64());
+        }
+        self.0.on_response(response, latency, span);
+    }
+}
+
+/// Reads the gauges that live in the database and renders everything in
+/// the Prometheus text format.
+pub async fn render(db: &Db) -> Result<String> {
+    let queue: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from solutions where status = $1",
+    )
+    .bind(Status::Pending)
+    .fetch_one(pool)
+    .await)?;
+    QUEUE_LENGTH.set(queue);
+    let claimed: i64 = with_pool!(db, pool => sqlx::query_scalar("select count(*) from judge_claims")
+        .fetch_one(pool)
+        .await)?;
+    CLAIMED.set(claimed);
+    // Each session store keeps its own table, with expiry in its own format.
+    let sessions: i64 = match db {
+        Db::Sqlite(pool) => {
+            sqlx::query_scalar(
+                "select count(*) from tower_sessions where expiry_date > unixepoch()",
+            )
+            .fetch_one(pool)
+            .await?
+        }
+        Db::Postgres(pool) => {
+            sqlx::query_scalar(
+                "select count(*) from tower_sessions.session where expiry_date > now()",
+            )
+            .fetch_one(pool)
+            .await?
+        }
+    };
+    ACTIVE_SESSIONS.set(sessions);
+    CONCURRENT_LIMIT.set(get_cached_config().await?.executor.concurrent_limit as i64);
+    let (size, idle) = db.connections();
+    DB_POOL.with_label_values(&["idle"]).set(idle as i64);
+    DB_POOL
+        .with_label_values(&["in_use"])
+        .set(i64::from(size) - idle as i64);
+    DB_POOL
+        .with_label_values(&["max"])
+        .set(i64::from(db.max_connections()));
+    // Registered on first use, touched here so they show up from the start.
+    Lazy::force(&RUNNING);
+    Lazy::force(&JUDGE_DURATION);
+    Lazy::force(&VERDICTS);
+    Lazy::force(&HTTP_DURATION);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,466 @@
+use axum_login::AuthnBackend;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use openidconnect::{
+    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+    reqwest,
+    url::Url,
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointMaybeSet, EndpointNotSet,
+    EndpointSet, IssuerUrl, Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::warn;
+
+use crate::{
+    audit,
+    config::OidcConfiguration,
+    db::with_pool,
+    groups::{Group, GroupError},
+    users::{
+        self, random_readable_string, validate_username, Backend, NewUser, RegisterUserError, User,
+    },
+};
+
+type DiscoveredClient = CoreClient<
+    EndpointSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointNotSet,
+    EndpointMaybeSet,
+    EndpointMaybeSet,
+>;
+
+#[derive(Debug, Error)]
+pub enum OidcError {
+    #[error("Could not reach the identity provider: {0}")]
+    Discovery(String),
+    #[error("The identity provider rejected the login: {0}")]
+    Exchange(String),
+    #[error("The ID token is invalid: {0}")]
+    Verification(String),
+    #[error("The ID token has no usable {0} claim")]
+    MissingClaim(&'static str),
+    #[error("There is no account for this login, ask the staff for one")]
+    NotProvisioned,
+    #[error("The account {0} can only be linked after logging in with its password")]
+    LinkRequired(String),
+    #[error("This login is already linked to another account")]
+    AlreadyLinked,
+    #[error("Could not create an account: {0}")]
+    Register(#[from] RegisterUserError),
+    #[error("Database access error")]
+    Database(#[from] sqlx::Error),
+    #[error("Database access error")]
+    Group(#[from] GroupError),
+    #[error("Database access error")]
+    Backend(#[from] users::Error),
+}
+
+/// What has to survive the round trip through the provider. Kept in the
+/// session between `/oidc/login` and `/oidc/callback`.
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,
+    nonce: String,
+    pkce_verifier: String,
+    pub next: Option<String>,
+}
+
+pub struct Provider {
+    client: DiscoveredClient,
+    http: reqwest::Client,
+}
+
+static PROVIDER: OnceCell<Provider> = OnceCell::const_new();
+
+impl Provider {
+    /// The provider is discovered on first use, so the server still starts
+    /// while the identity provider is down.
+    pub async fn get(config: &OidcConfiguration) -> Result<&'static Provider, OidcError> {
+        PROVIDER
+            .get_or_try_init(|| async {
+                // Following redirects would open the door to SSRF.
+                let http = reqwest::ClientBuilder::new()
+                    .redirect(reqwest::redirect::Policy::none())
+                    .build()
+                    .map_err(|e| OidcError::Discovery(e.to_string()))?;
+                let issuer = IssuerUrl::new(config.issuer_url.clone())
+                    .map_err(|e| OidcError::Discovery(e.to_string()))?;
+                let metadata = CoreProviderMetadata::discover_async(issuer, &http)
+                    .await
+                    .map_err(|e| OidcError::Discovery(e.to_string()))?;
+                let redirect_url = RedirectUrl::new(config.redirect_url.clone())
+                    .map_err(|e| OidcError::Discovery(e.to_string()))?;
+                let client = CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(config.client_id.clone()),
+                    Some(ClientSecret::new(config.client_secret.expose().to_string())),
+                )
+                .set_redirect_uri(redirect_url);
+                Ok(Provider { client, http })
+            })
+            .await
+    }
+
+    /// The URL to send the browser to, and what to remember until it comes
+    /// back.
+    pub fn authorize_url(&self, next: Option<String>) -> (Url, PendingLogin) {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, state, nonce) = self
+            .client
+            .authorize_url(
+                CoreAuthenticationFlow::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .add_scope(Scope::new("email".to_string()))
+            .add_scope(Scope::new("profile".to_string()))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+        let pending = PendingLogin {
+            state: state.secret().clone(),
+            nonce: nonce.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            next,
+        };
+        (url, pending)
+    }
+
+    /// Redeems `code` and returns the claims of the verified ID token.
+    pub async fn exchange(
+        &self,
+        code: String,
+        pending: PendingLogin,
+    ) -> Result<Map<String, Value>, OidcError> {
+        let token_response = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .map_err(|e| OidcError::Exchange(e.to_string()))?
+            .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+            .request_async(&self.http)
+            .await
+            .map_err(|e| OidcError::Exchange(e.to_string()))?;
+        let id_token = token_response
+            .id_token()
+            .ok_or_else(|| OidcError::Exchange("no ID token in the response".to_string()))?;
+        id_token
+            .claims(&self.client.id_token_verifier(), &Nonce::new(pending.nonce))
+            .map_err(|e| OidcError::Verification(e.to_string()))?;
+
+        // The typed claims only cover the standard ones. The signature was
+        // checked above, so the payload can be read as is for the configured
+        // user name and group claims.
+        let jwt = id_token.to_string();
+        let payload = jwt
+            .split('.')
+            .nth(1)
+            .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or_else(|| OidcError::Verification("malformed payload".to_string()))?;
+        Ok(payload)
+    }
+}
+
+pub fn string_claim<'a>(claims: &'a Map<String, Value>, name: &str) -> Option<&'a str> {
+    claims
+        .get(name)
+        .and_then(Value::as_str)
+        .filter(|value| !value.is_empty())
+}
+
+/// Turns a claim value into something [`validate_username`] accepts, or
+/// `None` if too little of it is left.
+fn sanitize_username(raw: &str) -> Option<String> {
+    let name: String = raw
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .skip_while(|c| !c.is_ascii_alphanumeric())
+        .take(28)
+        .collect();
+    validate_username(&name).ok().map(|_| name)
+}
+
+/// The user name for a new account: the configured claim, the local part of
+/// the email address, or a name derived from the subject, with a number
+/// appended if it is already taken.
+async fn pick_username(
+    backend: &Backend,
+    username_claim: &str,
+    claims: &Map<String, Value>,
+    subject: &str,
+) -> Result<String, OidcError> {
+    let base = string_claim(claims, username_claim)
+        .and_then(sanitize_username)
+        .or_else(|| {
+            string_claim(claims, "email")
+                .and_then(|email| email.split('@').next())
+                .and_then(sanitize_username)
+        })
+        .unwrap_or_else(|| format!("user-{:.8x}", Sha256::digest(subject.as_bytes())));
+    let mut username = base.clone();
+    let mut suffix = 2;
+    while backend.get_user_by_username(&username).await?.is_some() {
+        username = format!("{}-{}", base, suffix);
+        suffix += 1;
+    }
+    Ok(username)
+}
+
+/// How accounts are found for identities of one issuer. Shared by SSO and
+/// LTI launches.
+pub struct Provisioning<'a> {
+    pub issuer: &'a str,
+    pub username_claim: &'a str,
+    pub auto_provision: bool,
+    pub link_existing_users: bool,
+}
+
+/// The account the subject of `claims` belongs to. Unknown subjects are
+/// linked to an existing account or provisioned, depending on `provisioning`.
+pub async fn identity_user(
+    backend: &Backend,
+    provisioning: &Provisioning<'_>,
+    claims: &Map<String, Value>,
+) -> Result<User, OidcError> {
+    let subject = string_claim(claims, "sub").ok_or(OidcError::MissingClaim("sub"))?;
+    let email = string_claim(claims, "email");
+
+    let identity: Option<i64> = with_pool!(&backend.db, pool => sqlx::query_scalar(
+        "select user_id from user_identities where issuer = $1 and subject = $2",
+    )
+    .bind(provisioning.issuer)
+    .bind(subject)
+    .fetch_optional(pool)
+    .await)?;
+    if let Some(user_id) = identity {
+        if let Some(user) = backend.get_user(&user_id).await? {
+            return Ok(user);
+        }
+    }
+
+    let existing = match string_claim(claims, provisioning.username_claim) {
+        Some(username) if provisioning.link_existing_users => {
+            backend.get_user_by_username(username).await?
+        }
+        _ => None,
+    };
+    let user = match existing {
+        // A matching claim is not proof enough for accounts that have more
+        // to lose, those are linked by hand with `link_user`.
+        Some(user) if user.is_staff() || user.has_local_password() => {
+            return Err(OidcError::LinkRequired(user.username))
+        }
+        Some(user) => user,
+        None if provisioning.auto_provision => {
+            let username =
+                pick_username(backend, provisioning.username_claim, claims, subject).await?;
+            let user_id = backend
+                .create_user(NewUser {
+                    username,
+                    // Nobody knows this password, the account is only usable
+                    // through the provider until an admin issues a reset link.
+                    password: random_readable_string(32),
+                    display_name: string_claim(claims, "name").map(str::to_string),
+                    must_change_password: false,
+                })
+                .await?;
+            backend
+                .get_user(&user_id)
+                .await?
+                .ok_or(OidcError::NotProvisioned)?
+        }
+        None => return Err(OidcError::NotProvisioned),
+    };
+    link_identity(backend, provisioning.issuer, subject, email, &user).await?;
+    Ok(user)
+}
+
+/// Links the subject of `claims` to `user`, who is logged in already and
+/// so shown to own the account.
+pub async fn link_user(
+    backend: &Backend,
+    config: &OidcConfiguration,
+    claims: &Map<String, Value>,
+    user: &User,
+) -> Result<(), OidcError> {
+    let subject = string_claim(claims, "sub").ok_or(OidcError::MissingClaim("sub"))?;
+    let identity: Option<i64> = with_pool!(&backend.db, pool => sqlx::query_scalar(
+        "select user_id from user_identities where issuer = $1 and subject = $2",
+    )
+    .bind(&config.issuer_url)
+    .bind(subject)
+    .fetch_optional(pool)
+    .await)?;
+    match identity {
+        Some(user_id) if user_id == user.id => Ok(()),
+        Some(_) => Err(OidcError::AlreadyLinked),
+        None => {
+            let email = string_claim(claims, "email");
+            link_identity(backend, &config.issuer_url, subject, email, user).await
+        }
+    }
+}
+
+async fn link_identity(
+    backend: &Backend,
+    issuer: &str,
+    subject: &str,
+    email: Option<&str>,
+    user: &User,
+) -> Result<(), OidcError> {
+    with_pool!(&backend.db, pool => sqlx::query(
+        "insert into user_identities (issuer,subject,user_id,email,created_at) values ($1,$2,$3,$4,current_timestamp)
+        on conflict (issuer,subject) do update set user_id = excluded.user_id, email = excluded.email, created_at = excluded.created_at",
+    )
+    .bind(issuer)
+    .bind(subject)
+    .bind(user.id)
+    .bind(email)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    let detail = format!("{} {}", issuer, subject);
+    audit::record(
+        &backend.db,
+        "identity_linked",
+        Some(&user.username),
+        None,
+        Some(&detail),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The account an SSO login belongs to, added to the groups mapped from the
+/// provider's groups.
+pub async fn login_user(
+    backend: &Backend,
+    config: &OidcConfiguration,
+    claims: &Map<String, Value>,
+) -> Result<User, OidcError> {
+    let provisioning = Provisioning {
+        issuer: &config.issuer_url,
+        username_claim: &config.username_claim,
+        auto_provision: config.auto_provision,
+        link_existing_users: config.link_existing_users,
+    };
+    let user = identity_user(backend, &provisioning, claims).await?;
+    if let Some(ref groups_claim) = config.groups_claim {
+        join_mapped_groups(backend, config, claims, groups_claim, &user).await?;
+    }
+    Ok(user)
+}
+
+/// Adds `user` to the groups the provider's groups map to. Memberships are
+/// never removed here, instructors manage those by hand.
+async fn join_mapped_groups(
+    backend: &Backend,
+    config: &OidcConfiguration,
+    claims: &Map<String, Value>,
+    groups_claim: &str,
+    user: &User,
+) -> Result<(), OidcError> {
+    let provider_groups: Vec<&str> = match claims.get(groups_claim) {
+        Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+        Some(Value::String(value)) => vec![value.as_str()],
+        _ => Vec::new(),
+    };
+    for provider_group in provider_groups {
+        let Some(name) = config.group_mapping.get(provider_group) else {
+            continue;
+        };
+        match Group::by_name(&backend.db, name).await? {
+            Some(group) => group.add_user(&backend.db, user.id).await?,
+            None => warn!(group = name, "oidc group mapping points at a missing group"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{db::tests::each_database, users::Role};
+
+    async fn user(backend: &Backend, username: &str, role: Role, generated: bool) -> User {
+        let id = backend
+            .create_user(NewUser {
+                username: username.to_string(),
+                password: "correct horse".to_string(),
+                display_name: None,
+                must_change_password: generated,
+            })
+            .await
+            .unwrap();
+        backend.set_role(id, role).await.unwrap();
+        backend.get_user(&id).await.unwrap().unwrap()
+    }
+
+    fn claims(subject: &str, username: &str) -> Map<String, Value> {
+        let Value::Object(claims) = json!({ "sub": subject, "preferred_username": username })
+        else {
+            unreachable!()
+        };
+        claims
+    }
+
+    #[tokio::test]
+    async fn links_only_unclaimed_student_accounts() {
+        each_database(|db| async move {
+            let backend = Backend::new(db);
+            let config: OidcConfiguration = toml::from_str(
+                r#"
+                issuer_url = "https://idp.example.com"
+                client_id = "occ"
+                redirect_url = "https://occ.example.com/oidc/callback"
+                "#,
+            )
+            .unwrap();
+            let provisioning = Provisioning {
+                issuer: &config.issuer_url,
+                username_claim: "preferred_username",
+                auto_provision: false,
+                link_existing_users: true,
+            };
+            let imported = user(&backend, "s1001", Role::Student, true).await;
+            let teacher = user(&backend, "teacher", Role::Instructor, true).await;
+            let alice = user(&backend, "alice", Role::Student, false).await;
+
+            let linked = identity_user(&backend, &provisioning, &claims("1", "s1001"))
+                .await
+                .unwrap();
+            assert_eq!(linked.id, imported.id);
+            for (subject, username) in [("2", "teacher"), ("3", "alice")] {
+                assert!(matches!(
+                    identity_user(&backend, &provisioning, &claims(subject, username)).await,
+                    Err(OidcError::LinkRequired(_))
+                ));
+            }
+
+            link_user(&backend, &config, &claims("3", "alice"), &alice)
+                .await
+                .unwrap();
+            let linked = identity_user(&backend, &provisioning, &claims("3", "whatever"))
+                .await
+                .unwrap();
+            assert_eq!(linked.id, alice.id);
+            assert!(matches!(
+                link_user(&backend, &config, &claims("3", "alice"), &teacher).await,
+                Err(OidcError::AlreadyLinked)
+            ));
+        })
+        .await;
+    }
+}
@@ -1,17 +1,38 @@
-use std::path::PathBuf;
+use std::{collections::HashSet, path::PathBuf};
 
 use anyhow::{Context, Result};
 use serde::Deserialize;
-use tokio::{fs::read_to_string, sync::OnceCell};
+use tokio::{
+    fs::{read_to_string, try_exists},
+    sync::OnceCell,
+};
 use tracing::debug;
 
-use crate::config::get_cached_config;
+use crate::{
+    config::get_cached_config,
+    executor::Diagnostics,
+    repository::MAIN_SOURCE,
+    rules::{self, Rule},
+    submission,
+};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Problem {
     pub name: Option<String>,
     pub id: String,
     pub dir: PathBuf,
+    /// Files a submission has to contain, only `main.c` if not set.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Shell command building `main` from the submitted files, by default
+    /// `cc` with every `.c` file in `files`.
+    pub build: Option<String>,
+    /// Checked on the code before it is built.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Compiler warnings and sanitizer findings shown with the result.
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
 }
 
 impl Problem {
@@ -28,6 +49,34 @@ impl Problem {
         }
     }
 
+    /// Names of the files a submission has to contain.
+    pub fn source_names(&self) -> Vec<&str> {
+        if self.files.is_empty() {
+            vec![MAIN_SOURCE]
+        } else {
+            self.files.iter().map(String::as_str).collect()
+        }
+    }
+
+    /// Whether submissions carry several files, rather than `main.c` alone.
+    pub fn has_several_files(&self) -> bool {
+        self.source_names() != [MAIN_SOURCE]
+    }
+
+    pub fn build_command(&self) -> String {
+        match &self.build {
+            Some(build) => build.clone(),
+            None => {
+                let sources: Vec<&str> = self
+                    .source_names()
+                    .into_iter()
+                    .filter(|name| name.ends_with(".c"))
+                    .collect();
+                format!("cc -o main {}", sources.join(" "))
+            }
+        }
+    }
+
     pub async fn get_index_md(&self) -> Result<String> {
         let index_md_path = get_cached_config()
             .await?
@@ -63,4 +112,88 @@ impl ProblemsInfo {
             })
             .await
     }
+
+    /// Rules that do not compile. Submissions to their problems could not be
+    /// judged, so the judge does not start with any.
+    pub async fn rule_errors(&self) -> Result<Vec<String>> {
+        let base = &get_cached_config().await?.problems.dir;
+        let mut errors = Vec::new();
+        for problem in &self.problem {
+            let mut problem_rules = problem.rules.clone();
+            problem_rules.extend(rules::pattern_file(&base.join(&problem.id)).await?);
+            for (index, rule) in problem_rules.iter().enumerate() {
+                if let Err(e) = rule.compile() {
+                    errors.push(format!("{}: rule {}: {:#}", problem.id, index + 1, e));
+                }
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Everything wrong with the problems that would only show up once a
+    /// page is opened or a submission is judged.
+    pub async fn validate(&self) -> Result<Vec<String>> {
+        let base = &get_cached_config().await?.problems.dir;
+        let mut errors = Vec::new();
+        let mut ids = HashSet::new();
+        for problem in &self.problem {
+            if !ids.insert(&problem.id) {
+                errors.push(format!("{}: duplicate id", problem.id));
+            }
+            if !try_exists(base.join(&problem.dir).join("index.md")).await? {
+                errors.push(format!(
+                    "{}: {} has no index.md",
+                    problem.id,
+                    problem.dir.display()
+                ));
+            }
+            // The judge copies the directory named after the id.
+            let judge_dir = base.join(&problem.id);
+            let pairs = match read_to_string(judge_dir.join("pairs.txt")).await {
+                Ok(pairs) => pairs,
+                Err(e) => {
+                    errors.push(format!(
+                        "{}: reading {}/pairs.txt: {}",
+                        problem.id, problem.id, e
+                    ));
+                    continue;
+                }
+            };
+            for name in &problem.files {
+                if !submission::valid_name(name) {
+                    errors.push(format!("{}: {} is not a valid file name", problem.id, name));
+                } else if try_exists(judge_dir.join(name)).await? {
+                    errors.push(format!(
+                        "{}: {} is also a file of the problem and would replace the submitted one",
+                        problem.id, name
+                    ));
+                }
+            }
+            if problem
+                .build
+                .as_deref()
+                .is_some_and(|build| build.trim().is_empty())
+            {
+                errors.push(format!("{}: build is empty", problem.id));
+            }
+            if problem.diagnostics.strict && !problem.diagnostics.enabled() {
+                errors.push(format!(
+                    "{}: diagnostics are strict but no check is enabled",
+                    problem.id
+                ));
+            }
+            if pairs.split_whitespace().next().is_none() {
+                errors.push(format!("{}: pairs.txt lists no test cases", problem.id));
+            }
+            for case in pairs.split_whitespace() {
+                for file in [format!("input{}.txt", case), format!("output{}.txt", case)] {
+                    if !try_exists(judge_dir.join(&file)).await? {
+                        errors.push(format!("{}: {} is missing", problem.id, file));
+                    }
+                }
+            }
+        }
+        errors.extend(self.rule_errors().await?);
+        Ok(errors)
+    }
 }
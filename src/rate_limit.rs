@@ -0,0 +1,197 @@
+use thiserror::Error;
+use time::Duration;
+
+use crate::{
+    audit,
+    config::RateLimitConfiguration,
+    db::{utc_now, with_pool, Db},
+    repository::Status,
+};
+
+#[derive(Debug, Error)]
+pub enum SubmissionLimitError {
+    #[error("You already have {0} submissions waiting to be checked, please wait for the results")]
+    TooManyPending(i64),
+    #[error("Too many submissions, you can submit at most {0} times per minute")]
+    TooFrequent(i64),
+    #[error("Database access error")]
+    Database(#[from] sqlx::Error),
+}
+
+/// Whether logins for `username` or from `ip` are temporarily locked because
+/// of too many recent failures. Failures of an account are forgotten after
+/// a successful login.
+pub async fn login_locked(
+    db: &Db,
+    config: &RateLimitConfiguration,
+    username: &str,
+    ip: &str,
+) -> Result<bool, sqlx::Error> {
+    let since = utc_now() - Duration::seconds(config.login_lockout_secs);
+    let account: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from login_attempts where username = $1 and success = false and created_at > $2 and id > coalesce((select max(id) from login_attempts where username = $1 and success = true), 0)",
+    )
+    .bind(username)
+    .bind(since)
+    .fetch_one(pool)
+    .await)?;
+    if account >= config.login_failures_per_account {
+        return Ok(true);
+    }
+    let address: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from login_attempts where ip = $1 and success = false and created_at > $2",
+    )
+    .bind(ip)
+    .bind(since)
+    .fetch_one(pool)
+    .await)?;
+    Ok(address >= config.login_failures_per_ip)
+}
+
+/// Records a login attempt and writes an audit log entry when it locks the
+/// account or the address.
+pub async fn record_login(
+    db: &Db,
+    config: &RateLimitConfiguration,
+    username: &str,
+    ip: &str,
+    success: bool,
+) -> Result<(), sqlx::Error> {
+    with_pool!(db, pool => sqlx::query(
+        "insert into login_attempts (username,ip,success,created_at) values ($1,$2,$3,current_timestamp)",
+    )
+    .bind(username)
+    .bind(ip)
+    .bind(success)
+    .execute(pool)
+    .await
+    .map(|_| ()))?;
+    if !success && login_locked(db, config, username, ip).await? {
+        audit::record(
+            db,
+            "login_lockout",
+            Some(username),
+            Some(ip),
+            Some("too many failed login attempts"),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Checks whether `user_id` may submit another solution right now.
+pub async fn check_submission(
+    db: &Db,
+    config: &RateLimitConfiguration,
+    user_id: i64,
+) -> Result<(), SubmissionLimitError> {
+    let pending: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from solutions where userid = $1 and status = $2",
+    )
+    .bind(user_id)
+    .bind(Status::Pending)
+    .fetch_one(pool)
+    .await)?;
+    if pending >= config.max_pending_submissions {
+        return Err(SubmissionLimitError::TooManyPending(pending));
+    }
+    let since = utc_now() - Duration::minutes(1);
+    let recent: i64 = with_pool!(db, pool => sqlx::query_scalar(
+        "select count(*) from solutions where userid = $1 and created_at > $2",
+    )
+    .bind(user_id)
+    .bind(since)
+    .fetch_one(pool)
+    .await)?;
+    if recent >= config.submissions_per_minute {
+        return Err(SubmissionLimitError::TooFrequent(
+            config.submissions_per_minute,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::tests::{each_database, insert_solution},
+        users::{Backend, NewUser},
+    };
+
+    #[tokio::test]
+    async fn login_lockout() {
+        each_database(|db| async move {
+            let config = RateLimitConfiguration {
+                login_failures_per_account: 2,
+                login_failures_per_ip: 3,
+                ..Default::default()
+            };
+            record_login(&db, &config, "alice", "10.0.0.1", false)
+                .await
+                .unwrap();
+            assert!(!login_locked(&db, &config, "alice", "10.0.0.2")
+                .await
+                .unwrap());
+            record_login(&db, &config, "alice", "10.0.0.1", false)
+                .await
+                .unwrap();
+            assert!(login_locked(&db, &config, "alice", "10.0.0.2")
+                .await
+                .unwrap());
+
+            // A successful login forgets the failures of the account, not
+            // those of the address.
+            record_login(&db, &config, "alice", "10.0.0.3", true)
+                .await
+                .unwrap();
+            assert!(!login_locked(&db, &config, "alice", "10.0.0.2")
+                .await
+                .unwrap());
+            record_login(&db, &config, "bob", "10.0.0.1", false)
+                .await
+                .unwrap();
+            assert!(login_locked(&db, &config, "carol", "10.0.0.1")
+                .await
+                .unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn submission_limits() {
+        each_database(|db| async move {
+            let user_id = Backend::new(db.clone())
+                .create_user(NewUser {
+                    username: "alice".to_string(),
+                    password: "correct horse".to_string(),
+                    display_name: None,
+                    must_change_password: false,
+                })
+                .await
+                .unwrap();
+            let config = RateLimitConfiguration {
+                submissions_per_minute: 3,
+                max_pending_submissions: 2,
+                ..Default::default()
+            };
+            check_submission(&db, &config, user_id).await.unwrap();
+            insert_solution(&db, user_id, "hello", "Pending").await;
+            insert_solution(&db, user_id, "hello", "Pending").await;
+            assert!(matches!(
+                check_submission(&db, &config, user_id).await,
+                Err(SubmissionLimitError::TooManyPending(2))
+            ));
+            insert_solution(&db, user_id, "hello", "AC").await;
+            let config = RateLimitConfiguration {
+                max_pending_submissions: 10,
+                ..config
+            };
+            assert!(matches!(
+                check_submission(&db, &config, user_id).await,
+                Err(SubmissionLimitError::TooFrequent(3))
+            ));
+        })
+        .await;
+    }
+}
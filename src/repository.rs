@@ -0,0 +1,546 @@
+//! Typed access to submissions and accounts, for the web handlers, the judge
+//! and the command line.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+use crate::{
+    db::{with_pool, Db},
+    users::{Role, User},
+};
+
+/// Where a submission stands, stored by name in `solutions.status`, which
+/// only accepts these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "text")]
+pub enum Status {
+    Pending,
+    #[serde(rename = "AC")]
+    #[sqlx(rename = "AC")]
+    Accepted,
+    #[serde(rename = "WA")]
+    #[sqlx(rename = "WA")]
+    WrongAnswer,
+    /// Printed more than `executor.output_limit` for a test case.
+    #[serde(rename = "OLE")]
+    #[sqlx(rename = "OLE")]
+    OutputLimitExceeded,
+    /// Broke one of the problem's source rules, found before building.
+    #[serde(rename = "RV")]
+    #[sqlx(rename = "RV")]
+    RuleViolation,
+}
+
+impl Status {
+    pub const ALL: [Status; 5] = [
+        Status::Pending,
+        Status::Accepted,
+        Status::WrongAnswer,
+        Status::OutputLimitExceeded,
+        Status::RuleViolation,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Pending => "Pending",
+            Status::Accepted => "AC",
+            Status::WrongAnswer => "WA",
+            Status::OutputLimitExceeded => "OLE",
+            Status::RuleViolation => "RV",
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Status::ALL
+            .into_iter()
+            .find(|status| status.as_str() == value)
+            .ok_or_else(|| format!("unknown status {}, use Pending, AC, WA, OLE or RV", value))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Outcome of judging one submission.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verdict {
+    pub status: Status,
+    pub stdout: String,
+    pub stderr: String,
+    /// Compiler warnings and sanitizer reports, empty if there were none or
+    /// the problem does not ask for them.
+    #[serde(default)]
+    pub diagnostics: String,
+}
+
+/// A row of `solutions`.
+#[derive(Debug, Clone, FromRow)]
+pub struct Solution {
+    pub id: i64,
+    #[sqlx(rename = "userid")]
+    pub user_id: i64,
+    pub problem_id: String,
+    pub content: Option<String>,
+    /// Name of the uploaded file, `None` if the source was typed in.
+    pub filename: Option<String>,
+    pub status: Status,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub diagnostics: Option<String>,
+    pub created_at: Option<OffsetDateTime>,
+    pub executed_at: Option<OffsetDateTime>,
+}
+
+/// Name single file submissions are built from.
+pub const MAIN_SOURCE: &str = "main.c";
+
+/// One file of a submission, a row of `solution_files`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct SourceFile {
+    pub name: String,
+    pub content: String,
+}
+
+const SOLUTION_COLUMNS: &str =
+    "id, userid, problem_id, content, filename, status, stdout, stderr, diagnostics, created_at, executed_at";
+
+/// Stores a new pending submission and returns its id.
+pub async fn insert_solution(
+    db: &Db,
+    user_id: i64,
+    problem_id: &str,
+    content: &str,
+    filename: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    // `fetch_all` waits for the statement to be committed, see
+    // `insert_user`.
+    with_pool!(db, pool => sqlx::query_scalar(
+        "insert into solutions (content,filename,status,userid,problem_id,created_at) values ($1,$2,$3,$4,$5,current_timestamp) returning id",
+    )
+    .bind(content)
+    .bind(filename)
+    .bind(Status::Pending)
+    .bind(user_id)
+    .bind(problem_id)
+    .fetch_all(pool)
+    .await)?
+    .pop()
+    .ok_or(sqlx::Error::RowNotFound)
+}
+
+/// Stores a new pending submission of several files, in `solution_files`
+/// instead of `content`, and returns its id.
+pub async fn insert_solution_files(
+    db: &Db,
+    user_id: i64,
+    problem_id: &str,
+    files: &[SourceFile],
+    filename: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    with_pool!(db, pool => async {
+        let mut transaction = pool.begin().await?;
+        let id: i64 = sqlx::query_scalar(
+            "insert into solutions (filename,status,userid,problem_id,created_at) values ($1,$2,$3,$4,current_timestamp) returning id",
+        )
+        .bind(filename)
+        .bind(Status::Pending)
+        .bind(user_id)
+        .bind(problem_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+        for file in files {
+            sqlx::query("insert into solution_files (solution_id,name,content) values ($1,$2,$3)")
+                .bind(id)
+                .bind(&file.name)
+                .bind(&file.content)
+                .execute(&mut *transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(id)
+    }
+    .await)
+}
+
+/// The rows of `solution_files` for a submission, by name.
+pub async fn solution_files(db: &Db, solution_id: i64) -> Result<Vec<SourceFile>, sqlx::Error> {
+    with_pool!(db, pool => sqlx::query_as(
+        "select name, content from solution_files where solution_id = $1 order by name",
+    )
+    .bind(solution_id)
+    .fetch_all(pool)
+    .await)
+}
+
+/// The files a submission is built from, [`MAIN_SOURCE`] alone for single
+/// file submissions.
+pub async fn source_files(db: &Db, solution: &Solution) -> Result<Vec<SourceFile>, sqlx::Error> {
+    match &solution.content {
+        Some(content) => Ok(vec![SourceFile {
+            name: MAIN_SOURCE.to_string(),
+            content: content.clone(),
+        }]),
+        None => solution_files(db, solution.id).await,
+    }
+}
+
+pub async fn solution(db: &Db, id: i64) -> Result<Option<Solution>, sqlx::Error> {
+    let query = format!("select {} from solutions where id = $1", SOLUTION_COLUMNS);
+    with_pool!(db, pool => sqlx::query_as(&query)
+        .bind(id)
+        .fetch_optional(pool)
+        .await)
+}
+
+/// Every submission of a user for a problem, oldest first.
+pub async fn solutions_for(
+    db: &Db,
+    user_id: i64,
+    problem_id: &str,
+) -> Result<Vec<Solution>, sqlx::Error> {
+    let query = format!(
+        "select {} from solutions where userid = $1 and problem_id = $2 order by id",
+        SOLUTION_COLUMNS
+    );
+    with_pool!(db, pool => sqlx::query_as(&query)
+        .bind(user_id)
+        .bind(problem_id)
+        .fetch_all(pool)
+        .await)
+}
+
+/// Ids of the submissions waiting for the judge, oldest first.
+pub async fn pending_solution_ids(db: &Db) -> Result<Vec<i64>, sqlx::Error> {
+    with_pool!(db, pool => sqlx::query_scalar(
+        "select id from solutions where status = $1 order by id",
+    )
+    .bind(Status::Pending)
+    .fetch_all(pool)
+    .await)
+}
+
+pub async fn record_verdict(db: &Db, id: i64, verdict: &Verdict) -> Result<(), sqlx::Error> {
+    with_pool!(db, pool => sqlx::query(
+        "update solutions set status = $1, stdout = $2, stderr = $3, diagnostics = $4, executed_at = current_timestamp where id = $5",
+    )
+    .bind(verdict.status)
+    .bind(&verdict.stdout)
+    .bind(&verdict.stderr)
+    .bind(Some(&verdict.diagnostics).filter(|diagnostics| !diagnostics.is_empty()))
+    .bind(id)
+    .execute(pool)
+    .await
+    .map(|_| ()))
+}
+
+const USER_COLUMNS: &str =
+    "id, username, password, role, display_name, must_change_password, created_at";
+
+/// A row for `user`, with the password already hashed.
+pub struct NewUserRecord {
+    pub username: String,
+    pub password_hash: String,
+    pub display_name: Option<String>,
+    pub must_change_password: bool,
+}
+
+pub async fn user_by_id(db: &Db, id: i64) -> Result<Option<User>, sqlx::Error> {
+    let query = format!(r#"select {} from "user" where id = $1"#, USER_COLUMNS);
+    with_pool!(db, pool => sqlx::query_as(&query)
+        .bind(id)
+        .fetch_optional(pool)
+        .await)
+}
+
+pub async fn user_by_username(db: &Db, username: &str) -> Result<Option<User>, sqlx::Error> {
+    let query = format!(r#"select {} from "user" where username = $1"#, USER_COLUMNS);
+    with_pool!(db, pool => sqlx::query_as(&query)
+        .bind(username)
+        .fetch_optional(pool)
+        .await)
+}
+
+pub async fn list_users(db: &Db) -> Result<Vec<User>, sqlx::Error> {
+    let query = format!(r#"select {} from "user" order by id"#, USER_COLUMNS);
+    with_pool!(db, pool => sqlx::query_as(&query).fetch_all(pool).await)
+}
+
+pub async fn username_exists(db: &Db, username: &str) -> Result<bool, sqlx::Error> {
+    let id: Option<i64> = with_pool!(db, pool => sqlx::query_scalar(
+        r#"select id from "user" where username = $1"#,
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await)?;
+    Ok(id.is_some())
+}
+
+/// Inserts a user and returns its id.
+pub async fn insert_user(db: &Db, user: NewUserRecord) -> Result<i64, sqlx::Error> {
+    // `fetch_all` rather than `fetch_one`: sqlx hands out the first row
+    // before SQLite has finished and committed the statement, so reading
+    // the row right away from another connection could miss it.
+    with_pool!(db, pool => sqlx::query_scalar(
+        r#"insert into "user" (username,password,display_name,must_change_password,created_at) values ($1,$2,$3,$4,current_timestamp) returning id"#,
+    )
+    .bind(user.username)
+    .bind(user.password_hash)
+    .bind(user.display_name)
+    .bind(user.must_change_password)
+    .fetch_all(pool)
+    .await)?
+    .pop()
+    .ok_or(sqlx::Error::RowNotFound)
+}
+
+pub async fn update_password(
+    db: &Db,
+    id: i64,
+    password_hash: &str,
+    must_change_password: bool,
+) -> Result<(), sqlx::Error> {
+    with_pool!(db, pool => sqlx::query(
+        r#"update "user" set password = $1, must_change_password = $2 where id = $3"#,
+    )
+    .bind(password_hash)
+    .bind(must_change_password)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map(|_| ()))
+}
+
+/// Also keeps the old `super_user` flag in step with the role.
+pub async fn set_role(db: &Db, id: i64, role: Role) -> Result<(), sqlx::Error> {
+    with_pool!(db, pool => sqlx::query(
+        r#"update "user" set role = $1, super_user = $2 where id = $3"#,
+    )
+    .bind(role.as_str())
+    .bind(role == Role::Admin)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map(|_| ()))
+}
+
+/// Admins that can log in with a password. The seeded admin of old
+/// versions is locked with an empty one.
+pub async fn count_usable_admins(db: &Db) -> Result<i64, sqlx::Error> {
+    with_pool!(db, pool => sqlx::query_scalar(
+        r#"select count(*) from "user" where role = $1 and password != ''"#,
+    )
+    .bind(Role::Admin.as_str())
+    .fetch_one(pool)
+    .await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::each_database;
+
+    fn new_user(username: &str) -> NewUserRecord {
+        NewUserRecord {
+            username: username.to_string(),
+            password_hash: "hash".to_string(),
+            display_name: None,
+            must_change_password: false,
+        }
+    }
+
+    #[test]
+    fn status_names() {
+        for status in Status::ALL {
+            assert_eq!(status.as_str().parse(), Ok(status));
+        }
+        assert!("ac".parse::<Status>().is_err());
+        assert_eq!(
+            serde_json::to_string(&Status::WrongAnswer).unwrap(),
+            r#""WA""#
+        );
+    }
+
+    #[tokio::test]
+    async fn solutions() {
+        each_database(|db| async move {
+            let user_id = insert_user(&db, new_user("alice")).await.unwrap();
+            let first = insert_solution(&db, user_id, "hello", "int main;", Some("main.c"))
+                .await
+                .unwrap();
+            let second = insert_solution(&db, user_id, "hello", "int main(void);", None)
+                .await
+                .unwrap();
+            insert_solution(&db, user_id, "loop", "", None)
+                .await
+                .unwrap();
+            assert_eq!(pending_solution_ids(&db).await.unwrap().len(), 3);
+
+            let verdict = Verdict {
+                status: Status::WrongAnswer,
+                stdout: "out".to_string(),
+                stderr: "err".to_string(),
+                diagnostics: "main.c:1:5: warning: unused variable".to_string(),
+            };
+            record_verdict(&db, first, &verdict).await.unwrap();
+            assert!(!pending_solution_ids(&db).await.unwrap().contains(&first));
+
+            let judged = solution(&db, first).await.unwrap().unwrap();
+            assert_eq!(judged.user_id, user_id);
+            assert_eq!(judged.problem_id, "hello");
+            assert_eq!(judged.content.as_deref(), Some("int main;"));
+            assert_eq!(judged.filename.as_deref(), Some("main.c"));
+            assert_eq!(judged.status, Status::WrongAnswer);
+            assert_eq!(judged.stdout.as_deref(), Some("out"));
+            assert!(judged.diagnostics.is_some());
+            assert!(judged.created_at.is_some());
+            assert!(judged.executed_at.is_some());
+            assert!(solution(&db, 1000).await.unwrap().is_none());
+
+            let solutions = solutions_for(&db, user_id, "hello").await.unwrap();
+            let ids: Vec<i64> = solutions.iter().map(|s| s.id).collect();
+            assert_eq!(ids, [first, second]);
+            assert_eq!(solutions[1].status, Status::Pending);
+            assert!(solutions[1].executed_at.is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn solution_files() {
+        each_database(|db| async move {
+            let user_id = insert_user(&db, new_user("alice")).await.unwrap();
+            let single = insert_solution(&db, user_id, "hello", "int main;", None)
+                .await
+                .unwrap();
+            let single = solution(&db, single).await.unwrap().unwrap();
+            assert_eq!(
+                source_files(&db, &single).await.unwrap(),
+                [SourceFile {
+                    name: MAIN_SOURCE.to_string(),
+                    content: "int main;".to_string(),
+                }]
+            );
+
+            let files = [
+                SourceFile {
+                    name: "list.h".to_string(),
+                    content: "struct list;".to_string(),
+                },
+                SourceFile {
+                    name: "list.c".to_string(),
+                    content: "#include \"list.h\"".to_string(),
+                },
+            ];
+            let id = insert_solution_files(&db, user_id, "list", &files, Some("list.zip"))
+                .await
+                .unwrap();
+            let project = solution(&db, id).await.unwrap().unwrap();
+            assert!(project.content.is_none());
+            assert_eq!(project.status, Status::Pending);
+            let stored = source_files(&db, &project).await.unwrap();
+            let names: Vec<&str> = stored.iter().map(|file| file.name.as_str()).collect();
+            assert_eq!(names, ["list.c", "list.h"]);
+
+            // A duplicate name leaves no submission behind.
+            let duplicate = [files[0].clone(), files[0].clone()];
+            assert!(
+                insert_solution_files(&db, user_id, "list", &duplicate, None)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(solutions_for(&db, user_id, "list").await.unwrap().len(), 1);
+
+            with_pool!(&db, pool => sqlx::query(r#"delete from "user" where id = $1"#)
+                .bind(user_id)
+                .execute(pool)
+                .await
+                .map(|_| ()))
+            .unwrap();
+            let left: i64 = with_pool!(&db, pool => sqlx::query_scalar(
+                "select count(*) from solution_files",
+            )
+            .fetch_one(pool)
+            .await)
+            .unwrap();
+            assert_eq!(left, 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn solution_constraints() {
+        each_database(|db| async move {
+            let user_id = insert_user(&db, new_user("alice")).await.unwrap();
+            let rejected = with_pool!(&db, pool => sqlx::query(
+                "insert into solutions (content,status,userid,problem_id) values ('',$1,$2,'hello')",
+            )
+            .bind("ac")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map(|_| ()));
+            assert!(rejected.is_err());
+
+            let id = insert_solution(&db, user_id, "hello", "", None)
+                .await
+                .unwrap();
+            for status in Status::ALL {
+                let verdict = Verdict {
+                    status,
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    diagnostics: String::new(),
+                };
+                record_verdict(&db, id, &verdict).await.unwrap();
+            }
+            let judged = solution(&db, id).await.unwrap().unwrap();
+            assert!(judged.diagnostics.is_none());
+            with_pool!(&db, pool => sqlx::query(r#"delete from "user" where id = $1"#)
+                .bind(user_id)
+                .execute(pool)
+                .await
+                .map(|_| ()))
+            .unwrap();
+            assert!(solution(&db, id).await.unwrap().is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn users() {
+        each_database(|db| async move {
+            // Only the locked seeded admin exists.
+            assert_eq!(count_usable_admins(&db).await.unwrap(), 0);
+            let id = insert_user(&db, new_user("alice")).await.unwrap();
+            assert!(username_exists(&db, "alice").await.unwrap());
+            assert!(!username_exists(&db, "bob").await.unwrap());
+
+            set_role(&db, id, Role::Admin).await.unwrap();
+            update_password(&db, id, "other hash", true).await.unwrap();
+            assert_eq!(count_usable_admins(&db).await.unwrap(), 1);
+
+            let alice = user_by_username(&db, "alice").await.unwrap().unwrap();
+            assert_eq!(alice.id, id);
+            assert_eq!(alice.role, Role::Admin);
+            assert!(alice.must_change_password);
+            assert!(user_by_id(&db, id).await.unwrap().is_some());
+            assert!(user_by_id(&db, id + 1).await.unwrap().is_none());
+            assert!(list_users(&db)
+                .await
+                .unwrap()
+                .iter()
+                .any(|user| user.username == "alice"));
+        })
+        .await;
+    }
+}
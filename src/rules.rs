@@ -0,0 +1,271 @@
+//! Rules a problem sets on the submitted code, like functions that have to
+//! be used or headers that must not be included. They are checked before
+//! the code is built, on the code without comments and literals.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    config::get_cached_config,
+    problems::Problem,
+    repository::{SourceFile, Status, Verdict},
+};
+
+/// Written as `[[problem.rules]]` in `info.toml`, with either `require` or
+/// `forbid`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Rule {
+    /// A regular expression the code has to match somewhere.
+    pub require: Option<String>,
+    /// A regular expression the code must not match anywhere.
+    pub forbid: Option<String>,
+    /// Told to the student instead of the expression when the rule is broken.
+    pub message: Option<String>,
+    /// Files the rule looks at, every `.c` and `.h` file if not set.
+    #[serde(default)]
+    pub files: Vec<String>,
+}
+
+impl Rule {
+    /// The expression, and whether it is required rather than forbidden.
+    pub fn compile(&self) -> Result<(Regex, bool)> {
+        let (pattern, required) = match (&self.require, &self.forbid) {
+            (Some(pattern), None) => (pattern, true),
+            (None, Some(pattern)) => (pattern, false),
+            _ => bail!("a rule needs either require or forbid"),
+        };
+        let regex =
+            Regex::new(pattern).with_context(|| format!("invalid expression {}", pattern))?;
+        Ok((regex, required))
+    }
+
+    fn applies_to(&self, name: &str) -> bool {
+        if self.files.is_empty() {
+            name.ends_with(".c") || name.ends_with(".h")
+        } else {
+            self.files.iter().any(|file| file == name)
+        }
+    }
+}
+
+/// `source` with comments blanked out and string and character literals
+/// emptied, so that rules only see code. Lines stay where they were, and the
+/// header names of `#include` lines are kept.
+pub fn code_only(source: &str) -> String {
+    let mut code = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    // Only whitespace so far on the current line.
+    let mut line_start = true;
+    let mut include = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                code.push(c);
+                line_start = true;
+                include = false;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|&next| next != '\n').is_some() {}
+                code.push(' ');
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        code.push(next);
+                    }
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+                code.push(' ');
+            }
+            '"' | '\'' if !include => {
+                code.push(c);
+                while let Some(next) = chars.next_if(|&next| next != '\n') {
+                    if next == c {
+                        break;
+                    }
+                    if next == '\\' {
+                        chars.next_if(|&next| next != '\n');
+                    }
+                }
+                code.push(c);
+            }
+            '#' if line_start => {
+                let directive: String = chars.clone().take_while(|&next| next != '\n').collect();
+                include = directive.trim_start().starts_with("include");
+                code.push(c);
+            }
+            _ => code.push(c),
+        }
+        if !c.is_whitespace() {
+            line_start = false;
+        }
+    }
+    code
+}
+
+/// What the student is told for every rule `files` break, nothing if they
+/// keep all of them.
+pub fn check(rules: &[Rule], files: &[SourceFile]) -> Result<Vec<String>> {
+    let files: Vec<(&str, String)> = files
+        .iter()
+        .map(|file| (file.name.as_str(), code_only(&file.content)))
+        .collect();
+    let mut broken = Vec::new();
+    for rule in rules {
+        let (regex, required) = rule.compile()?;
+        let mut checked = files.iter().filter(|(name, _)| rule.applies_to(name));
+        if required {
+            if !checked.any(|(_, code)| regex.is_match(code)) {
+                broken.push(rule.message.clone().unwrap_or_else(|| {
+                    format!("`{}` に当てはまるコードがありません", regex.as_str())
+                }));
+            }
+            continue;
+        }
+        for (name, code) in checked {
+            if let Some(found) = regex.find(code) {
+                let line = code[..found.start()].matches('\n').count() + 1;
+                let message = rule
+                    .message
+                    .clone()
+                    .unwrap_or_else(|| format!("`{}` は使えません", found.as_str().trim()));
+                broken.push(format!("{}:{}: {}", name, line, message));
+            }
+        }
+    }
+    Ok(broken)
+}
+
+/// Rules from a `pattern.txt` in the judge directory, from before rules were
+/// written in `info.toml`: every line has to be matched.
+pub async fn pattern_file(problem_dir: &Path) -> Result<Vec<Rule>> {
+    let path = problem_dir.join("pattern.txt");
+    if !tokio::fs::try_exists(&path).await? {
+        return Ok(Vec::new());
+    }
+    let patterns = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("read {}", path.display()))?;
+    Ok(patterns
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| Rule {
+            require: Some(line.to_string()),
+            ..Rule::default()
+        })
+        .collect())
+}
+
+/// The verdict for a submission that breaks a rule of its problem, which is
+/// then not built at all.
+pub async fn verdict(problem_id: &str, files: &[SourceFile]) -> Result<Option<Verdict>> {
+    // Problems gone from `info.toml` are judged without rules.
+    let Ok(problem) = Problem::by_id(problem_id).await else {
+        return Ok(None);
+    };
+    let mut rules = problem.rules.clone();
+    let problem_dir = get_cached_config().await?.problems.dir.join(problem_id);
+    rules.extend(pattern_file(&problem_dir).await?);
+    let broken = check(&rules, files)?;
+    if broken.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Verdict {
+        status: Status::RuleViolation,
+        stdout: broken.join("\n") + "\n",
+        stderr: String::new(),
+        diagnostics: String::new(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_comments_and_literals() {
+        let source = r#"#include <string.h>
+#include "list.h"
+int main(void) { // system("ls");
+    /* malloc(
+    ) */ puts("system(\"ls\")"); char c = '\'';
+}
+"#;
+        let code = code_only(source);
+        let lines: Vec<&str> = code.lines().map(str::trim).collect();
+        assert_eq!(
+            lines,
+            [
+                "#include <string.h>",
+                r#"#include "list.h""#,
+                "int main(void) {",
+                "",
+                r#"puts(""); char c = '';"#,
+                "}",
+            ]
+        );
+    }
+
+    fn main_c(content: &str) -> Vec<SourceFile> {
+        vec![SourceFile {
+            name: "main.c".to_string(),
+            content: content.to_string(),
+        }]
+    }
+
+    fn require(pattern: &str) -> Rule {
+        Rule {
+            require: Some(pattern.to_string()),
+            ..Rule::default()
+        }
+    }
+
+    fn forbid(pattern: &str) -> Rule {
+        Rule {
+            forbid: Some(pattern.to_string()),
+            ..Rule::default()
+        }
+    }
+
+    #[test]
+    fn checks_rules() {
+        let rules = [
+            require(r"\bmalloc\s*\("),
+            forbid(r"\bsystem\s*\("),
+            Rule {
+                message: Some("string.h は使わずに書きましょう".to_string()),
+                ..forbid(r"#\s*include\s*<string\.h>")
+            },
+        ];
+        let good = "#include <stdlib.h>\nint main(void) { int *p = malloc(4); /* system() */ }\n";
+        assert!(check(&rules, &main_c(good)).unwrap().is_empty());
+
+        let bad = "#include <string.h>\n// malloc(4)\nint main(void) {\n  system(\"ls\");\n}\n";
+        assert_eq!(
+            check(&rules, &main_c(bad)).unwrap(),
+            [
+                r"`\bmalloc\s*\(` に当てはまるコードがありません",
+                "main.c:4: `system(` は使えません",
+                "main.c:1: string.h は使わずに書きましょう",
+            ]
+        );
+
+        let only_list = Rule {
+            files: vec!["list.c".to_string()],
+            ..require("malloc")
+        };
+        assert_eq!(check(&[only_list], &main_c(good)).unwrap().len(), 1);
+        assert!(check(&[Rule::default()], &main_c(good)).is_err());
+        assert!(check(&[forbid("(")], &main_c(good)).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    config::get_cached_config,
+    users::{random_readable_string, validate_password, Backend, RegisterUserError, SEEDED_ADMIN},
+};
+
+/// Token of the one-time setup link, while no admin can log in.
+static SETUP_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Makes sure an admin can log in: sets the password of `admin` from
+/// `server.admin_password` if it passes the password policy like one typed
+/// in on the setup page, or prints a one-time setup link.
+pub async fn bootstrap(backend: &Backend) -> Result<()> {
+    if !backend.needs_admin().await? {
+        return Ok(());
+    }
+    let server = &get_cached_config().await?.server;
+    let admin_password = server.admin_password.expose();
+    let min_length = server.registration.min_password_length;
+    if !admin_password.is_empty() {
+        match validate_password(SEEDED_ADMIN, admin_password, min_length) {
+            Err(e) => warn!("server.admin_password is not used: {}", e),
+            Ok(()) => match backend
+                .set_up_admin(SEEDED_ADMIN, admin_password.to_string(), true)
+                .await
+            {
+                Ok(_) => {
+                    warn!("no admin could log in, admin got server.admin_password and has to change it on the first login");
+                    return Ok(());
+                }
+                Err(RegisterUserError::UsernameAlreadyExists) => {
+                    warn!(
+                        "server.admin_password is not used, admin is an account somebody can log in to"
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+    let token = random_readable_string(32);
+    *SETUP_TOKEN.lock().unwrap() = Some(token.clone());
+    warn!(
+        "no admin can log in yet, open http://{}/setup/{} to set one up",
+        server.address, token
+    );
+    Ok(())
+}
+
+/// Compared through hashes, so the time taken says nothing about the token.
+pub fn token_is_valid(token: &str) -> bool {
+    match *SETUP_TOKEN.lock().unwrap() {
+        Some(ref expected) => Sha256::digest(expected) == Sha256::digest(token),
+        None => false,
+    }
+}
+
+/// Invalidates the setup link once it was used.
+pub fn finish() {
+    *SETUP_TOKEN.lock().unwrap() = None;
+}
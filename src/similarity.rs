@@ -0,0 +1,422 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    hash::{Hash, Hasher},
+    ops::Range,
+};
+
+use crate::{
+    db::{with_pool, Db},
+    repository,
+};
+
+/// Tokens per k-gram. Shorter matches are too common in C to mean anything.
+const K: usize = 5;
+/// Winnowing window, in k-grams.
+const WINDOW: usize = 4;
+
+const KEYWORDS: &[&str] = &[
+    "auto", "break", "case", "char", "const", "continue", "default", "do", "double", "else",
+    "enum", "extern", "float", "for", "goto", "if", "int", "long", "register", "return", "short",
+    "signed", "sizeof", "static", "struct", "switch", "typedef", "union", "unsigned", "void",
+    "volatile", "while",
+];
+
+/// A token with identifiers and literals replaced by their kind, so renaming
+/// variables does not hide a copy.
+struct Token {
+    text: String,
+    span: Range<usize>,
+}
+
+/// Splits C source into normalized tokens. Whitespace, comments and
+/// preprocessor lines are dropped.
+fn tokenize(source: &str) -> Vec<Token> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut line_start = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c == b'\n' {
+            line_start = true;
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if c == b'#' && line_start {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+        line_start = false;
+        let text = if bytes[i..].starts_with(b"//") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        } else if bytes[i..].starts_with(b"/*") {
+            i += 2;
+            while i < bytes.len() && !bytes[i..].starts_with(b"*/") {
+                i += 1;
+            }
+            i = (i + 2).min(bytes.len());
+            continue;
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            let word = &source[start..i];
+            if KEYWORDS.contains(&word) {
+                word.to_string()
+            } else {
+                "$id".to_string()
+            }
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                i += 1;
+            }
+            "$num".to_string()
+        } else if c == b'"' || c == b'\'' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != c && bytes[i] != b'\n' {
+                if bytes[i] == b'\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(bytes.len());
+            if c == b'"' { "$str" } else { "$chr" }.to_string()
+        } else {
+            // One token per character, except for the rest of a multi-byte
+            // character so spans stay on char boundaries.
+            i += 1;
+            while i < bytes.len() && !source.is_char_boundary(i) {
+                i += 1;
+            }
+            source[start..i].to_string()
+        };
+        tokens.push(Token {
+            text,
+            span: start..i,
+        });
+    }
+    tokens
+}
+
+fn kgram_hashes(tokens: &[Token]) -> Vec<u64> {
+    tokens
+        .windows(K)
+        .map(|kgram| {
+            let mut hasher = DefaultHasher::new();
+            for token in kgram {
+                token.text.hash(&mut hasher);
+            }
+            hasher.finish()
+        })
+        .collect()
+}
+
+/// Winnowing: the smallest hash of every window, so any match of at least
+/// `K + WINDOW - 1` tokens shares a fingerprint.
+fn winnow(hashes: &[u64]) -> HashSet<u64> {
+    if hashes.len() < WINDOW {
+        return hashes.iter().copied().collect();
+    }
+    hashes
+        .windows(WINDOW)
+        .filter_map(|window| window.iter().min().copied())
+        .collect()
+}
+
+/// A submission prepared for comparison.
+pub struct Fingerprinted {
+    pub solution_id: i64,
+    pub username: String,
+    fingerprints: HashSet<u64>,
+}
+
+impl Fingerprinted {
+    pub fn new(solution_id: i64, username: String, content: &str) -> Self {
+        let fingerprints = winnow(&kgram_hashes(&tokenize(content)));
+        Fingerprinted {
+            solution_id,
+            username,
+            fingerprints,
+        }
+    }
+
+    /// Share of the smaller submission's fingerprints found in the other one.
+    pub fn similarity(&self, other: &Fingerprinted) -> f64 {
+        let smaller = self.fingerprints.len().min(other.fingerprints.len());
+        if smaller == 0 {
+            return 0.0;
+        }
+        let shared = self.fingerprints.intersection(&other.fingerprints).count();
+        shared as f64 / smaller as f64
+    }
+}
+
+pub struct SuspiciousPair {
+    pub a: (i64, String),
+    pub b: (i64, String),
+    pub similarity: f64,
+}
+
+impl SuspiciousPair {
+    pub fn percent(&self) -> u32 {
+        (self.similarity * 100.0).round() as u32
+    }
+}
+
+/// The code of a submission as one text, its files one after another if it
+/// has several.
+async fn compared_text(db: &Db, id: i64, content: Option<String>) -> Result<String, sqlx::Error> {
+    if let Some(content) = content {
+        return Ok(content);
+    }
+    Ok(repository::solution_files(db, id)
+        .await?
+        .into_iter()
+        .map(|file| format!("// {}\n{}\n", file.name, file.content))
+        .collect())
+}
+
+/// The latest accepted submission of every user for `problem_id`.
+pub async fn accepted_submissions(
+    db: &Db,
+    problem_id: &str,
+) -> Result<Vec<Fingerprinted>, sqlx::Error> {
+    let rows: Vec<(i64, String, Option<String>)> = with_pool!(db, pool => sqlx::query_as(
+        r#"select s.id, u.username, s.content
+        from (select id, userid, content, row_number() over (partition by userid order by id desc) as rank from solutions where problem_id = $1 and status = 'AC') s
+        join "user" u on u.id = s.userid
+        where s.rank = 1"#,
+    )
+    .bind(problem_id)
+    .fetch_all(pool)
+    .await)?;
+    let mut submissions = Vec::new();
+    for (id, username, content) in rows {
+        let text = compared_text(db, id, content).await?;
+        submissions.push(Fingerprinted::new(id, username, &text));
+    }
+    Ok(submissions)
+}
+
+/// Every pair of users at or above `threshold`, most similar first.
+pub fn rank_pairs(submissions: &[Fingerprinted], threshold: f64) -> Vec<SuspiciousPair> {
+    let mut pairs = Vec::new();
+    for (i, a) in submissions.iter().enumerate() {
+        for b in &submissions[i + 1..] {
+            let similarity = a.similarity(b);
+            if similarity >= threshold {
+                pairs.push(SuspiciousPair {
+                    a: (a.solution_id, a.username.clone()),
+                    b: (b.solution_id, b.username.clone()),
+                    similarity,
+                });
+            }
+        }
+    }
+    pairs.sort_by(|x, y| y.similarity.total_cmp(&x.similarity));
+    pairs
+}
+
+/// A piece of source text, and whether it also occurs in the other file.
+pub struct Segment {
+    pub text: String,
+    pub matched: bool,
+}
+
+/// Cuts `source` into matching and non-matching segments. Every k-gram found
+/// in `other` is marked, not only the winnowed ones, so whole regions light
+/// up.
+fn segments(source: &str, tokens: &[Token], hashes: &[u64], other: &HashSet<u64>) -> Vec<Segment> {
+    let mut marked = vec![false; tokens.len()];
+    for (i, hash) in hashes.iter().enumerate() {
+        if other.contains(hash) {
+            marked[i..i + K].fill(true);
+        }
+    }
+
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut push = |text: &str, matched: bool| match segments.last_mut() {
+        Some(last) if last.matched == matched => last.text.push_str(text),
+        _ => segments.push(Segment {
+            text: text.to_string(),
+            matched,
+        }),
+    };
+    let mut position = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        // Text between two matched tokens belongs to the match.
+        let gap_matched = marked[i] && i > 0 && marked[i - 1];
+        push(&source[position..token.span.start], gap_matched);
+        push(&source[token.span.clone()], marked[i]);
+        position = token.span.end;
+    }
+    push(&source[position..], false);
+    segments
+}
+
+/// Both submissions split into segments, for a side by side view.
+pub fn compare(a: &str, b: &str) -> (Vec<Segment>, Vec<Segment>) {
+    let (tokens_a, tokens_b) = (tokenize(a), tokenize(b));
+    let (hashes_a, hashes_b) = (kgram_hashes(&tokens_a), kgram_hashes(&tokens_b));
+    let set_a: HashSet<u64> = hashes_a.iter().copied().collect();
+    let set_b: HashSet<u64> = hashes_b.iter().copied().collect();
+    (
+        segments(a, &tokens_a, &hashes_a, &set_b),
+        segments(b, &tokens_b, &hashes_b, &set_a),
+    )
+}
+
+pub struct Submission {
+    pub id: i64,
+    pub username: String,
+    pub problem_id: String,
+    pub content: String,
+}
+
+pub async fn submission(db: &Db, id: i64) -> Result<Option<Submission>, sqlx::Error> {
+    let row: Option<(i64, String, String, Option<String>)> = with_pool!(db, pool => sqlx::query_as(
+        r#"select s.id, u.username, s.problem_id, s.content from solutions s join "user" u on u.id = s.userid where s.id = $1"#,
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await)?;
+    let Some((id, username, problem_id, content)) = row else {
+        return Ok(None);
+    };
+    Ok(Some(Submission {
+        id,
+        username,
+        problem_id,
+        content: compared_text(db, id, content).await?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = r#"#include <stdio.h>
+int main(void) {
+    int sum = 0;
+    for (int i = 0; i < 10; i++) {
+        sum += i * 2; // double it
+    }
+    printf("%d\n", sum);
+    return 0;
+}
+"#;
+
+    const RENAMED: &str = r#"#include <stdio.h>
+/* my own work */
+int main(void) {
+    int total = 0;
+    for (int k = 0; k < 10; k++) { total += k * 2; }
+    printf("total: %d\n", total);
+    return 0;
+}
+"#;
+
+    const UNRELATED: &str = r#"#include <stdlib.h>
+struct node { struct node *next; char value; };
+void push(struct node **head, char value) {
+    struct node *node = malloc(sizeof *node);
+    node->value = value;
+    node->next = *head;
+    *head = node;
+}
+"#;
+
+    #[test]
+    fn tokenizes() {
+        let source = "#include <stdio.h>\nint x1 = 42; // note\nchar *s = \"a\\\"b\";/* c */ é";
+        let tokens = tokenize(source);
+        let texts: Vec<&str> = tokens.iter().map(|token| token.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            ["int", "$id", "=", "$num", ";", "char", "*", "$id", "=", "$str", ";", "é"]
+        );
+        let spans: Vec<&str> = tokens
+            .iter()
+            .map(|token| &source[token.span.clone()])
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                "int",
+                "x1",
+                "=",
+                "42",
+                ";",
+                "char",
+                "*",
+                "s",
+                "=",
+                "\"a\\\"b\"",
+                ";",
+                "é"
+            ]
+        );
+    }
+
+    #[test]
+    fn scores_copies() {
+        let submissions = [
+            Fingerprinted::new(1, "alice".to_string(), ORIGINAL),
+            Fingerprinted::new(2, "bob".to_string(), RENAMED),
+            Fingerprinted::new(3, "carol".to_string(), UNRELATED),
+        ];
+        assert_eq!(submissions[0].similarity(&submissions[1]), 1.0);
+        assert!(submissions[0].similarity(&submissions[2]) < 0.2);
+        assert_eq!(
+            Fingerprinted::new(4, "dave".to_string(), "").similarity(&submissions[0]),
+            0.0
+        );
+
+        let pairs = rank_pairs(&submissions, 0.0);
+        assert_eq!(pairs.len(), 3);
+        assert_eq!((pairs[0].a.0, pairs[0].b.0), (1, 2));
+        assert_eq!(pairs[0].percent(), 100);
+        assert!(pairs
+            .windows(2)
+            .all(|pair| pair[0].similarity >= pair[1].similarity));
+        let pairs = rank_pairs(&submissions, 0.5);
+        assert_eq!(pairs.len(), 1);
+    }
+
+    #[test]
+    fn highlights_matches() {
+        let copied = format!("{}int unrelated_tail(char c) {{ return c; }}\n", ORIGINAL);
+        let (a, b) = compare(&copied, RENAMED);
+        for (segments, source) in [(&a, copied.as_str()), (&b, RENAMED)] {
+            let text: String = segments
+                .iter()
+                .map(|segment| segment.text.as_str())
+                .collect();
+            assert_eq!(text, source);
+            assert!(segments
+                .windows(2)
+                .all(|pair| pair[0].matched != pair[1].matched));
+        }
+        let matched: String = a
+            .iter()
+            .filter(|segment| segment.matched)
+            .map(|segment| segment.text.as_str())
+            .collect();
+        assert!(matched.contains("sum += i * 2;"));
+        assert!(!matched.contains("#include"));
+        assert!(!matched.contains("unrelated_tail"));
+
+        let (a, _) = compare(ORIGINAL, UNRELATED);
+        assert!(a.iter().all(|segment| !segment.matched));
+    }
+}
@@ -0,0 +1,299 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+};
+
+use bytesize::ByteSize;
+use thiserror::Error;
+
+use crate::repository::SourceFile;
+
+/// Most files one submission may have.
+pub const MAX_FILES: usize = 32;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SourceError {
+    #[error("The source code is empty")]
+    Empty,
+    #[error("The source code is larger than {}", .0.to_string_as(true))]
+    TooLarge(ByteSize),
+    #[error("The source code is not text, please submit a C file saved as UTF-8")]
+    NotText,
+    #[error("{0} is not text, please save it as UTF-8")]
+    FileNotText(String),
+    #[error("{0} is not a valid file name, use letters, digits, '.', '-' and '_'")]
+    InvalidName(String),
+    #[error("{0} is missing")]
+    Missing(String),
+    #[error("A submission may have at most {MAX_FILES} files")]
+    TooManyFiles,
+    #[error("The zip file could not be read")]
+    InvalidZip,
+}
+
+/// Whether a submitted file may be called `name`. Keeps it inside the build
+/// directory and away from the build's own `default.nix`.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('.')
+        && name != "default.nix"
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+}
+
+/// The name of an uploaded file without the directories some browsers and
+/// zip archives put in front.
+fn base_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// Fails once `len` bytes are more than `max`, so an upload can be rejected
+/// before it is read to the end.
+pub fn check_size(len: usize, max: ByteSize) -> Result<(), SourceError> {
+    if len as u64 > max.as_u64() {
+        return Err(SourceError::TooLarge(max));
+    }
+    Ok(())
+}
+
+/// Turns a typed or uploaded source into text. NUL bytes are valid UTF-8
+/// but only show up in binaries.
+pub fn source_text(bytes: Vec<u8>, max: ByteSize) -> Result<String, SourceError> {
+    check_size(bytes.len(), max)?;
+    let text = String::from_utf8(bytes).map_err(|_| SourceError::NotText)?;
+    if text.contains('\0') {
+        return Err(SourceError::NotText);
+    }
+    if text.trim().is_empty() {
+        return Err(SourceError::Empty);
+    }
+    Ok(text)
+}
+
+/// The files in a zip archive, by their base name. Stops reading once they
+/// are larger than `max` together.
+fn unzip(bytes: Vec<u8>, max: ByteSize) -> Result<Vec<(String, Vec<u8>)>, SourceError> {
+    let mut archive =
+        zip::ZipArchive::new(Cursor::new(bytes)).map_err(|_| SourceError::InvalidZip)?;
+    let mut files = Vec::new();
+    let mut total = 0;
+    for index in 0..archive.len() {
+        let mut entry = archive
+            .by_index(index)
+            .map_err(|_| SourceError::InvalidZip)?;
+        // Archives made on macOS carry their metadata in `__MACOSX` and
+        // `.DS_Store`.
+        let name = base_name(entry.name()).to_string();
+        if entry.is_dir() || entry.name().starts_with("__MACOSX/") || name.starts_with('.') {
+            continue;
+        }
+        let mut content = Vec::new();
+        entry
+            .by_ref()
+            .take(max.as_u64() + 1)
+            .read_to_end(&mut content)
+            .map_err(|_| SourceError::InvalidZip)?;
+        total += content.len();
+        check_size(total, max)?;
+        files.push((name, content));
+    }
+    Ok(files)
+}
+
+/// Collects the files of a submission to a problem with several files.
+/// `typed` come from the editor by file name, `uploads` are uploaded files
+/// or zip archives of them and replace typed files of the same name. Empty
+/// ones are left out.
+pub fn source_files(
+    typed: Vec<(String, Vec<u8>)>,
+    uploads: Vec<(String, Vec<u8>)>,
+    required: &[&str],
+    max: ByteSize,
+) -> Result<Vec<SourceFile>, SourceError> {
+    let mut files = BTreeMap::new();
+    for (name, bytes) in typed {
+        files.insert(name, bytes);
+    }
+    for (name, bytes) in uploads {
+        if name.to_ascii_lowercase().ends_with(".zip") {
+            files.extend(unzip(bytes, max)?);
+        } else {
+            files.insert(base_name(&name).to_string(), bytes);
+        }
+    }
+    files.retain(|_, bytes| !bytes.trim_ascii().is_empty());
+
+    if files.len() > MAX_FILES {
+        return Err(SourceError::TooManyFiles);
+    }
+    check_size(files.values().map(Vec::len).sum(), max)?;
+    for name in required {
+        if !files.contains_key(*name) {
+            return Err(SourceError::Missing(name.to_string()));
+        }
+    }
+    files
+        .into_iter()
+        .map(|(name, bytes)| {
+            if !valid_name(&name) {
+                return Err(SourceError::InvalidName(name));
+            }
+            match String::from_utf8(bytes) {
+                Ok(content) if !content.contains('\0') => Ok(SourceFile { name, content }),
+                _ => Err(SourceError::FileNotText(name)),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        let max = ByteSize::b(32);
+        assert_eq!(
+            source_text(b"int main(void) { return 0; }".to_vec(), max).as_deref(),
+            Ok("int main(void) { return 0; }")
+        );
+        assert_eq!(
+            source_text("// こんにちは".as_bytes().to_vec(), max).as_deref(),
+            Ok("// こんにちは")
+        );
+        assert_eq!(source_text(b" \n".to_vec(), max), Err(SourceError::Empty));
+        assert_eq!(
+            source_text(vec![b'a'; 33], max),
+            Err(SourceError::TooLarge(max))
+        );
+        assert_eq!(
+            source_text(b"\x7fELF\x02\x01\x01\x00".to_vec(), max),
+            Err(SourceError::NotText)
+        );
+        assert_eq!(
+            source_text(vec![0xff, 0xfe, b'a'], max),
+            Err(SourceError::NotText)
+        );
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut writer, content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn file(name: &str, content: &str) -> (String, Vec<u8>) {
+        (name.to_string(), content.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn several_files() {
+        let max = ByteSize::kib(1);
+        let required = ["main.c", "list.h"];
+        let names = |files: Vec<SourceFile>| -> Vec<(String, String)> {
+            files.into_iter().map(|f| (f.name, f.content)).collect()
+        };
+
+        let files = source_files(
+            vec![
+                file("main.c", "typed"),
+                file("list.h", "typed"),
+                file("list.c", " "),
+            ],
+            vec![file("C:\\work\\main.c", "uploaded")],
+            &required,
+            max,
+        )
+        .unwrap();
+        assert_eq!(
+            names(files),
+            [
+                ("list.h".to_string(), "typed".to_string()),
+                ("main.c".to_string(), "uploaded".to_string()),
+            ]
+        );
+
+        let archive = zip(&[
+            ("list/main.c", b"main"),
+            ("list/list.h", b"header"),
+            ("__MACOSX/list/._main.c", b"\0\0"),
+            ("list/.DS_Store", b"\0\0"),
+        ]);
+        let files = source_files(
+            vec![],
+            vec![("list.zip".to_string(), archive)],
+            &required,
+            max,
+        );
+        assert_eq!(
+            names(files.unwrap()),
+            [
+                ("list.h".to_string(), "header".to_string()),
+                ("main.c".to_string(), "main".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            source_files(vec![file("main.c", "x")], vec![], &required, max),
+            Err(SourceError::Missing("list.h".to_string()))
+        );
+        assert_eq!(
+            source_files(
+                vec![],
+                vec![
+                    file("../main.c", "x"),
+                    file("list.h", "x"),
+                    file("a b.c", "x")
+                ],
+                &required,
+                max
+            ),
+            Err(SourceError::InvalidName("a b.c".to_string()))
+        );
+        assert_eq!(
+            source_files(
+                vec![],
+                vec![
+                    file("main.c", "x"),
+                    file("list.h", "x"),
+                    file("default.nix", "x")
+                ],
+                &required,
+                max
+            ),
+            Err(SourceError::InvalidName("default.nix".to_string()))
+        );
+        assert_eq!(
+            source_files(
+                vec![],
+                vec![file("main.c", "x"), file("list.h", "x\0")],
+                &required,
+                max
+            ),
+            Err(SourceError::FileNotText("list.h".to_string()))
+        );
+        let many = (0..=MAX_FILES)
+            .map(|i| file(&format!("{}.c", i), "x"))
+            .collect();
+        assert_eq!(
+            source_files(many, vec![], &[], max),
+            Err(SourceError::TooManyFiles)
+        );
+        let bomb = zip(&[("main.c", &[b'a'; 2048])]);
+        assert_eq!(
+            source_files(vec![], vec![("bomb.zip".to_string(), bomb)], &required, max),
+            Err(SourceError::TooLarge(max))
+        );
+        assert_eq!(
+            source_files(vec![], vec![file("list.zip", "not a zip")], &required, max),
+            Err(SourceError::InvalidZip)
+        );
+    }
+}
@@ -1,23 +1,91 @@
-use std::fmt;
+use std::{collections::HashSet, fmt};
 
 use async_trait::async_trait;
-use axum_login::{AuthUser, AuthnBackend, UserId};
+use axum_login::{AuthUser, AuthnBackend, AuthzBackend, UserId};
 use password_auth::verify_password;
+use rand::{
+    distributions::{Alphanumeric, Slice},
+    Rng,
+};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool};
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
 use thiserror::Error;
 use time::OffsetDateTime;
 use tokio::task;
 
+use crate::{
+    config::{RegistrationConfiguration, RegistrationMode},
+    db::{utc_now, with_pool, Db},
+    groups::InviteCode,
+    repository::{self, NewUserRecord},
+};
+
 #[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
     pub id: i64,
     pub username: String,
     password: String,
-    super_user: Option<bool>,
+    #[sqlx(try_from = "String")]
+    pub role: Role,
+    pub display_name: Option<String>,
+    pub must_change_password: bool,
     created_at: Option<OffsetDateTime>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Instructor,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Student, Role::Instructor, Role::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Student => "student",
+            Role::Instructor => "instructor",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn permissions(&self) -> HashSet<Permission> {
+        match self {
+            Role::Student => HashSet::new(),
+            Role::Instructor => HashSet::from([Permission::ManageGroups]),
+            Role::Admin => HashSet::from([Permission::ManageGroups, Permission::ManageUsers]),
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == value)
+            .ok_or_else(|| format!("unknown role {}", value))
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// Create groups and see the submissions of their members.
+    ManageGroups,
+    /// Change the role of any user.
+    ManageUsers,
+}
+
 // Here we've implemented `Debug` manually to avoid accidentally logging the
 // password hash.
 impl std::fmt::Debug for User {
@@ -26,10 +94,27 @@ impl std::fmt::Debug for User {
             .field("id", &self.id)
             .field("username", &self.username)
             .field("password", &"[redacted]")
+            .field("role", &self.role)
             .finish()
     }
 }
 
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    pub fn is_staff(&self) -> bool {
+        self.role.permissions().contains(&Permission::ManageGroups)
+    }
+
+    /// Whether the user can log in with a password of their own, rather than
+    /// a locked account or a generated password still waiting to be changed.
+    pub fn has_local_password(&self) -> bool {
+        !self.password.is_empty() && !self.must_change_password
+    }
+}
+
 impl AuthUser for User {
     type Id = i64;
 
@@ -56,11 +141,11 @@ pub struct Credentials {
 
 #[derive(Debug, Clone)]
 pub struct Backend {
-    pub db: SqlitePool,
+    pub db: Db,
 }
 
 impl Backend {
-    pub fn new(db: SqlitePool) -> Self {
+    pub fn new(db: Db) -> Self {
         Self { db }
     }
 }
@@ -84,13 +169,7 @@ impl AuthnBackend for Backend {
         &self,
         creds: Self::Credentials,
     ) -> Result<Option<Self::User>, Self::Error> {
-        let user: Option<Self::User> = sqlx::query_as!(
-            User,
-            "select * from user where username = ? ",
-            creds.username
-        )
-        .fetch_optional(&self.db)
-        .await?;
+        let user = repository::user_by_username(&self.db, &creds.username).await?;
 
         // Verifying the password is blocking and potentially slow, so we'll do so via
         // `spawn_blocking`.
@@ -103,11 +182,19 @@ impl AuthnBackend for Backend {
     }
 
     async fn get_user(&self, user_id: &UserId<Self>) -> Result<Option<Self::User>, Self::Error> {
-        let user = sqlx::query_as!(User, "select * from user where id = ?", user_id)
-            .fetch_optional(&self.db)
-            .await?;
+        Ok(repository::user_by_id(&self.db, *user_id).await?)
+    }
+}
 
-        Ok(user)
+#[async_trait]
+impl AuthzBackend for Backend {
+    type Permission = Permission;
+
+    async fn get_user_permissions(
+        &self,
+        user: &Self::User,
+    ) -> Result<HashSet<Self::Permission>, Self::Error> {
+        Ok(user.role.permissions())
     }
 }
 
@@ -115,14 +202,93 @@ impl AuthnBackend for Backend {
 pub enum RegisterUserError {
     #[error("The user name already exists in database")]
     UsernameAlreadyExists,
+    #[error("Invalid user name: {0}")]
+    InvalidUsername(&'static str),
+    #[error("Password is too weak: {0}")]
+    WeakPassword(String),
+    #[error("Registration is closed, ask the staff for an account")]
+    RegistrationClosed,
+    #[error("The invite code is invalid, used up or expired")]
+    InvalidInviteCode,
     #[error("Database access error")]
     DatabaseAccessError,
 }
 
+/// The admin account older versions seeded with a published password, now
+/// locked until `server.admin_password` or the setup link takes it over.
+pub const SEEDED_ADMIN: &str = "admin";
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+
+/// Usernames are 3 to 32 ASCII letters, digits, `_`, `-` or `.`, starting with
+/// a letter or digit.
+pub fn validate_username(username: &str) -> Result<(), RegisterUserError> {
+    if username.len() < USERNAME_MIN_LENGTH || username.len() > USERNAME_MAX_LENGTH {
+        return Err(RegisterUserError::InvalidUsername(
+            "it must be 3 to 32 characters long",
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err(RegisterUserError::InvalidUsername(
+            "only letters, digits, '_', '-' and '.' are allowed",
+        ));
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Err(RegisterUserError::InvalidUsername(
+            "it must start with a letter or a digit",
+        ));
+    }
+    Ok(())
+}
+
+/// Minimum requirements for a password the user picks themselves.
+pub fn validate_password(
+    username: &str,
+    password: &str,
+    min_length: usize,
+) -> Result<(), RegisterUserError> {
+    if password.chars().count() < min_length {
+        return Err(RegisterUserError::WeakPassword(format!(
+            "it must be at least {} characters long",
+            min_length
+        )));
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Err(RegisterUserError::WeakPassword(
+            "it must not be the same as the user name".to_string(),
+        ));
+    }
+    if !password.chars().any(|c| c.is_alphabetic()) || !password.chars().any(|c| c.is_numeric()) {
+        return Err(RegisterUserError::WeakPassword(
+            "it must contain both letters and digits".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Characters used for generated passwords and codes. Look-alikes such as
+/// `0`/`O` and `1`/`l`/`I` are left out since they get printed and typed in.
+const READABLE_CHARS: &[u8] = b"abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub fn random_readable_string(length: usize) -> String {
+    let chars = Slice::new(READABLE_CHARS).unwrap();
+    rand::thread_rng()
+        .sample_iter(chars)
+        .take(length)
+        .map(|&c| c as char)
+        .collect()
+}
+
 #[derive(Clone, Deserialize)]
 pub struct RegisterUserData {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub invite_code: Option<String>,
 }
 
 impl fmt::Debug for RegisterUserData {
@@ -130,33 +296,256 @@ impl fmt::Debug for RegisterUserData {
         f.debug_struct("RegisterUserData")
             .field("username", &self.username)
             .field("password", &"********")
+            .field("invite_code", &self.invite_code)
             .finish()
     }
 }
 
+/// A user to be created by [`Backend::create_user`].
+#[derive(Clone)]
+pub struct NewUser {
+    pub username: String,
+    pub password: String,
+    pub display_name: Option<String>,
+    /// Ask the user to pick their own password on the next login.
+    pub must_change_password: bool,
+}
+
+/// Hashing the password is blocking and potentially slow, so we'll do so via
+/// `spawn_blocking`.
+async fn hash_password(password: String) -> Result<String, task::JoinError> {
+    task::spawn_blocking(move || password_auth::generate_hash(password.as_bytes())).await
+}
+
 impl Backend {
-    pub async fn register_user(&self, data: RegisterUserData) -> Result<(), RegisterUserError> {
-        let user_with_same_username =
-            sqlx::query_as!(User, "select * from user where username = ?", data.username)
-                .fetch_optional(&self.db)
+    /// Self-service registration through `/register`, subject to `policy`.
+    pub async fn register_user(
+        &self,
+        data: RegisterUserData,
+        policy: &RegistrationConfiguration,
+    ) -> Result<(), RegisterUserError> {
+        if policy.mode == RegistrationMode::Disabled {
+            return Err(RegisterUserError::RegistrationClosed);
+        }
+        validate_username(&data.username)?;
+        validate_password(&data.username, &data.password, policy.min_password_length)?;
+        let invite_code = data.invite_code.filter(|code| !code.trim().is_empty());
+        if policy.mode == RegistrationMode::InviteCode && invite_code.is_none() {
+            return Err(RegisterUserError::InvalidInviteCode);
+        }
+
+        let user = self
+            .new_user_record(NewUser {
+                username: data.username,
+                password: data.password,
+                display_name: None,
+                must_change_password: false,
+            })
+            .await?;
+        match invite_code {
+            Some(code) => InviteCode::register(&self.db, code.trim(), user)
                 .await
-                .map_err(|_| RegisterUserError::DatabaseAccessError)?;
-        if user_with_same_username.is_some() {
+                .map_err(|_| RegisterUserError::DatabaseAccessError)?
+                .ok_or(RegisterUserError::InvalidInviteCode)
+                .map(|_| ()),
+            None => repository::insert_user(&self.db, user)
+                .await
+                .map(|_| ())
+                .map_err(|_| RegisterUserError::DatabaseAccessError),
+        }
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, RegisterUserError> {
+        repository::username_exists(&self.db, username)
+            .await
+            .map_err(|_| RegisterUserError::DatabaseAccessError)
+    }
+
+    /// Inserts a user and returns its id.
+    pub async fn create_user(&self, data: NewUser) -> Result<i64, RegisterUserError> {
+        let user = self.new_user_record(data).await?;
+        repository::insert_user(&self.db, user)
+            .await
+            .map_err(|_| RegisterUserError::DatabaseAccessError)
+    }
+
+    /// Checks the username of `data` and hashes its password.
+    async fn new_user_record(&self, data: NewUser) -> Result<NewUserRecord, RegisterUserError> {
+        validate_username(&data.username)?;
+        if self.username_exists(&data.username).await? {
             return Err(RegisterUserError::UsernameAlreadyExists);
         }
-        let password = tokio::task::spawn_blocking(move || {
-            password_auth::generate_hash(data.password.as_bytes())
+        let password = hash_password(data.password)
+            .await
+            .map_err(|_| RegisterUserError::DatabaseAccessError)?;
+        Ok(NewUserRecord {
+            username: data.username,
+            password_hash: password,
+            display_name: data.display_name,
+            must_change_password: data.must_change_password,
         })
+    }
+
+    /// Replaces the password of a user and returns the updated user. Since the
+    /// password hash is the session auth hash, every existing session of the
+    /// user is invalidated.
+    pub async fn change_password(
+        &self,
+        user_id: i64,
+        new_password: String,
+    ) -> Result<Option<User>, Error> {
+        let password = hash_password(new_password).await?;
+        repository::update_password(&self.db, user_id, &password, false).await?;
+        self.get_user(&user_id).await
+    }
+
+    /// Whether no admin can log in with a password, as after the seeded
+    /// admin was locked.
+    pub async fn needs_admin(&self) -> Result<bool, Error> {
+        Ok(repository::count_usable_admins(&self.db).await? == 0)
+    }
+
+    /// Gives `username` admin rights and `password`, creating the account if
+    /// it does not exist. Of the existing accounts only the locked seeded
+    /// admin is taken over, never one somebody can log in to.
+    pub async fn set_up_admin(
+        &self,
+        username: &str,
+        password: String,
+        must_change_password: bool,
+    ) -> Result<i64, RegisterUserError> {
+        let existing = self
+            .get_user_by_username(username)
+            .await
+            .map_err(|_| RegisterUserError::DatabaseAccessError)?;
+        let user_id = match existing {
+            Some(user) if user.username != SEEDED_ADMIN || !user.password.is_empty() => {
+                return Err(RegisterUserError::UsernameAlreadyExists)
+            }
+            Some(user) => {
+                let password = hash_password(password)
+                    .await
+                    .map_err(|_| RegisterUserError::DatabaseAccessError)?;
+                repository::update_password(&self.db, user.id, &password, must_change_password)
+                    .await
+                    .map_err(|_| RegisterUserError::DatabaseAccessError)?;
+                user.id
+            }
+            None => {
+                self.create_user(NewUser {
+                    username: username.to_string(),
+                    password,
+                    display_name: None,
+                    must_change_password,
+                })
+                .await?
+            }
+        };
+        self.set_role(user_id, Role::Admin)
+            .await
+            .map_err(|_| RegisterUserError::DatabaseAccessError)?;
+        Ok(user_id)
+    }
+
+    pub async fn set_role(&self, user_id: i64, role: Role) -> Result<(), Error> {
+        Ok(repository::set_role(&self.db, user_id, role).await?)
+    }
+
+    pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>, Error> {
+        Ok(repository::user_by_username(&self.db, username).await?)
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>, Error> {
+        Ok(repository::list_users(&self.db).await?)
+    }
+}
+
+/// How long a password reset link stays valid.
+const RESET_TOKEN_LIFETIME: time::Duration = time::Duration::DAY;
+
+/// Only the hash of a reset token is stored, so a leaked database does not
+/// leak usable reset links.
+fn hash_reset_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl Backend {
+    /// Issues a single-use token that lets `user_id` choose a new password
+    /// without knowing the old one.
+    pub async fn create_reset_token(&self, user_id: i64, created_by: i64) -> Result<String, Error> {
+        let token: String = rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let token_hash = hash_reset_token(&token);
+        let expires_at = utc_now() + RESET_TOKEN_LIFETIME;
+        with_pool!(&self.db, pool => sqlx::query(
+            "insert into password_reset_tokens (token_hash,user_id,created_by,created_at,expires_at) values ($1,$2,$3,current_timestamp,$4)",
+        )
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(created_by)
+        .bind(expires_at)
+        .execute(pool)
         .await
-        .unwrap();
-        let _query_result = sqlx::query!(
-            "insert into user (username,password,created_at) values(?,?,current_timestamp)",
-            data.username,
-            password,
+        .map(|_| ()))?;
+        Ok(token)
+    }
+
+    /// The user `token` resets the password of, if it can still be used.
+    pub async fn reset_token_user(&self, token: &str) -> Result<Option<User>, Error> {
+        let token_hash = hash_reset_token(token);
+        let record: Option<i64> = with_pool!(&self.db, pool => sqlx::query_scalar(
+            "select user_id from password_reset_tokens where token_hash = $1 and used_at is null and expires_at > $2",
         )
-        .execute(&self.db)
-        .await;
-        Ok(())
+        .bind(token_hash)
+        .bind(utc_now())
+        .fetch_optional(pool)
+        .await)?;
+        match record {
+            Some(user_id) => self.get_user(&user_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Consumes `token` and sets the password of its user, both or neither.
+    /// Returns `None` if the token is unknown, expired or already used.
+    pub async fn reset_password(
+        &self,
+        token: &str,
+        new_password: String,
+    ) -> Result<Option<User>, Error> {
+        let token_hash = hash_reset_token(token);
+        let password = hash_password(new_password).await?;
+        let record = with_pool!(&self.db, pool => async {
+            let mut transaction = pool.begin().await?;
+            let user_id: Option<i64> = sqlx::query_scalar(
+                "update password_reset_tokens set used_at = current_timestamp where token_hash = $1 and used_at is null and expires_at > $2 returning user_id",
+            )
+            .bind(token_hash)
+            .bind(utc_now())
+            .fetch_optional(&mut *transaction)
+            .await?;
+            let Some(user_id) = user_id else {
+                return Ok(None);
+            };
+            sqlx::query(
+                r#"update "user" set password = $1, must_change_password = $2 where id = $3"#,
+            )
+            .bind(password)
+            .bind(false)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+            transaction.commit().await?;
+            Ok::<_, sqlx::Error>(Some(user_id))
+        }
+        .await)?;
+        match record {
+            Some(user_id) => self.get_user(&user_id).await,
+            None => Ok(None),
+        }
     }
 }
 
@@ -164,3 +553,130 @@ impl Backend {
 //
 // Note that we've supplied our concrete backend here.
 pub type AuthSession = axum_login::AuthSession<Backend>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tests::each_database;
+
+    fn new_user(username: &str) -> NewUser {
+        NewUser {
+            username: username.to_string(),
+            password: "correct horse".to_string(),
+            display_name: Some(username.to_uppercase()),
+            must_change_password: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn users_round_trip() {
+        each_database(|db| async move {
+            let backend = Backend::new(db);
+            let id = backend.create_user(new_user("alice")).await.unwrap();
+            assert!(matches!(
+                backend.create_user(new_user("alice")).await,
+                Err(RegisterUserError::UsernameAlreadyExists)
+            ));
+
+            let alice = backend.get_user(&id).await.unwrap().unwrap();
+            assert_eq!(alice.username, "alice");
+            assert_eq!(alice.display_name.as_deref(), Some("ALICE"));
+            assert_eq!(alice.role, Role::Student);
+            assert!(alice.must_change_password);
+            assert!(alice.created_at.is_some());
+
+            let credentials = |password: &str| Credentials {
+                username: "alice".to_string(),
+                password: password.to_string(),
+                next: None,
+            };
+            assert!(backend
+                .authenticate(credentials("correct horse"))
+                .await
+                .unwrap()
+                .is_some());
+            assert!(backend
+                .authenticate(credentials("wrong"))
+                .await
+                .unwrap()
+                .is_none());
+
+            backend.set_role(id, Role::Instructor).await.unwrap();
+            let alice = backend.change_password(id, "battery staple".to_string());
+            let alice = alice.await.unwrap().unwrap();
+            assert_eq!(alice.role, Role::Instructor);
+            assert!(!alice.must_change_password);
+            assert!(backend
+                .authenticate(credentials("battery staple"))
+                .await
+                .unwrap()
+                .is_some());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn first_admin() {
+        each_database(|db| async move {
+            let backend = Backend::new(db);
+            // Only the locked seeded admin exists.
+            assert!(backend.needs_admin().await.unwrap());
+            let bob = backend.create_user(new_user("bob")).await.unwrap();
+            assert!(matches!(
+                backend
+                    .set_up_admin("bob", "taken over".to_string(), false)
+                    .await,
+                Err(RegisterUserError::UsernameAlreadyExists)
+            ));
+            let bob = backend.get_user(&bob).await.unwrap().unwrap();
+            assert!(!bob.is_admin());
+            assert!(backend.needs_admin().await.unwrap());
+
+            let seeded = backend
+                .set_up_admin(SEEDED_ADMIN, "seeded admin".to_string(), true)
+                .await
+                .unwrap();
+            assert!(!backend.needs_admin().await.unwrap());
+            assert!(matches!(
+                backend
+                    .set_up_admin(SEEDED_ADMIN, "again".to_string(), false)
+                    .await,
+                Err(RegisterUserError::UsernameAlreadyExists)
+            ));
+            let id = backend
+                .set_up_admin("root", "correct horse".to_string(), false)
+                .await
+                .unwrap();
+            assert_ne!(id, seeded);
+            let root = backend.get_user_by_username("root").await.unwrap().unwrap();
+            assert_eq!(root.id, id);
+            assert!(root.is_admin());
+            assert!(backend
+                .list_users()
+                .await
+                .unwrap()
+                .iter()
+                .any(|user| user.id == id));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn reset_tokens_are_single_use() {
+        each_database(|db| async move {
+            let backend = Backend::new(db);
+            let id = backend.create_user(new_user("bob")).await.unwrap();
+            let token = backend.create_reset_token(id, id).await.unwrap();
+            assert!(backend.reset_token_user("unknown").await.unwrap().is_none());
+            let bob = backend.reset_token_user(&token).await.unwrap().unwrap();
+            assert_eq!(bob.id, id);
+
+            let reset = backend.reset_password(&token, "new password".to_string());
+            assert!(reset.await.unwrap().is_some());
+            assert!(backend.reset_token_user(&token).await.unwrap().is_none());
+            let reset = backend.reset_password(&token, "again".to_string());
+            assert!(reset.await.unwrap().is_none());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,137 @@
+use askama::Template;
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_messages::{Message, Messages};
+use serde::Deserialize;
+
+use crate::{
+    config::get_cached_config,
+    users::{validate_password, AuthSession, Credentials},
+};
+
+#[derive(Template)]
+#[template(path = "password.html")]
+struct PasswordTemplate {
+    messages: Vec<Message>,
+    username: String,
+    forced: bool,
+    /// Label of the SSO button, to link the account to the provider.
+    sso_label: Option<String>,
+}
+
+pub fn router() -> Router<()> {
+    Router::new()
+        .route("/password", get(self::get::password))
+        .route("/password", post(self::post::password))
+}
+
+/// Sends users that still have a generated one-time password to the password
+/// change page before they can use anything else.
+pub async fn require_password_change(
+    auth_session: AuthSession,
+    request: Request,
+    next: Next,
+) -> Response {
+    match auth_session.user {
+        Some(user) if user.must_change_password && request.uri().path() != "/password" => {
+            Redirect::to("/password").into_response()
+        }
+        _ => next.run(request).await,
+    }
+}
+
+mod get {
+    use super::*;
+
+    pub async fn password(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => PasswordTemplate {
+                messages: messages.into_iter().collect(),
+                forced: user.must_change_password,
+                username: user.username,
+                sso_label: get_cached_config()
+                    .await
+                    .unwrap()
+                    .oidc
+                    .as_ref()
+                    .map(|oidc| oidc.button_label.clone()),
+            }
+            .into_response(),
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+mod post {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct PasswordForm {
+        current_password: String,
+        new_password: String,
+        new_password_confirm: String,
+    }
+
+    pub async fn password(
+        mut auth_session: AuthSession,
+        messages: Messages,
+        Form(form): Form<PasswordForm>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user.clone() else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+
+        if form.new_password != form.new_password_confirm {
+            messages.error("The new passwords do not match");
+            return Redirect::to("/password").into_response();
+        }
+        let min_length = get_cached_config()
+            .await
+            .unwrap()
+            .server
+            .registration
+            .min_password_length;
+        if let Err(e) = validate_password(&user.username, &form.new_password, min_length) {
+            messages.error(e.to_string());
+            return Redirect::to("/password").into_response();
+        }
+
+        let creds = Credentials {
+            username: user.username.clone(),
+            password: form.current_password,
+            next: None,
+        };
+        match auth_session.authenticate(creds).await {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                messages.error("The current password is wrong");
+                return Redirect::to("/password").into_response();
+            }
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+
+        let user = match auth_session
+            .backend
+            .change_password(user.id, form.new_password)
+            .await
+        {
+            Ok(Some(user)) => user,
+            _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        // The session auth hash changed with the password, so the current
+        // session has to be renewed to stay logged in.
+        if auth_session.login(&user).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+
+        messages.success("Password changed");
+        Redirect::to("/").into_response()
+    }
+}
@@ -0,0 +1,803 @@
+use askama::Template;
+use axum::{
+    body::Body,
+    extract::{Multipart, Path, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_login::{permission_required, AuthnBackend};
+use axum_messages::{Message, Messages};
+use serde::Deserialize;
+
+use crate::{
+    archive::{submissions_tar, Selection},
+    db::Db,
+    grades::{grade_report, parse_date, GradeFilter},
+    groups::{Group, InviteCode, Member, MemberSolution},
+    import::{credentials_csv, import_users},
+    problems::{Problem, ProblemsInfo},
+    similarity::{self, Fingerprinted, Segment, Submission, SuspiciousPair},
+    users::{AuthSession, Backend, Permission, Role, User},
+};
+
+#[derive(Template)]
+#[template(path = "admin/groups.html")]
+struct GroupsTemplate {
+    messages: Vec<Message>,
+    username: String,
+    groups: Vec<Group>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/group.html")]
+struct GroupTemplate {
+    messages: Vec<Message>,
+    username: String,
+    group: Group,
+    members: Vec<Member>,
+    assigned: Vec<String>,
+    problems: Vec<Problem>,
+    invites: Vec<InviteCode>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/group_solutions.html")]
+struct GroupSolutionsTemplate {
+    messages: Vec<Message>,
+    username: String,
+    group: Group,
+    solutions: Vec<MemberSolution>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/grades.html")]
+struct GradesTemplate {
+    messages: Vec<Message>,
+    username: String,
+    groups: Vec<Group>,
+    is_admin: bool,
+}
+
+/// Empty fields come from the form as empty strings.
+#[derive(Deserialize)]
+pub struct GradeExportQuery {
+    #[serde(default)]
+    group: String,
+    #[serde(default)]
+    from: String,
+    #[serde(default)]
+    to: String,
+    #[serde(default)]
+    format: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/archive.html")]
+struct ArchiveTemplate {
+    messages: Vec<Message>,
+    username: String,
+    groups: Vec<Group>,
+}
+
+#[derive(Deserialize)]
+pub struct ArchiveQuery {
+    #[serde(default)]
+    group: String,
+    #[serde(default)]
+    select: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/similarity.html")]
+struct SimilarityTemplate {
+    messages: Vec<Message>,
+    username: String,
+    problems: Vec<Problem>,
+    problem_id: String,
+    min: u32,
+    submissions: usize,
+    pairs: Vec<SuspiciousPair>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/similarity_compare.html")]
+struct SimilarityCompareTemplate {
+    messages: Vec<Message>,
+    username: String,
+    problem_id: String,
+    percent: u32,
+    a: Submission,
+    b: Submission,
+    segments_a: Vec<Segment>,
+    segments_b: Vec<Segment>,
+}
+
+#[derive(Deserialize)]
+pub struct SimilarityQuery {
+    #[serde(default)]
+    problem: String,
+    min: Option<u32>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/users.html")]
+struct UsersTemplate {
+    messages: Vec<Message>,
+    username: String,
+    users: Vec<User>,
+    roles: [Role; 3],
+}
+
+#[derive(Template)]
+#[template(path = "admin/import.html")]
+struct ImportTemplate {
+    messages: Vec<Message>,
+    username: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin/reset.html")]
+struct ResetLinkTemplate {
+    messages: Vec<Message>,
+    username: String,
+    target: String,
+    token: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct AdminState {
+    db: Db,
+}
+
+pub fn router(db: Db) -> Router<()> {
+    let groups = Router::new()
+        .route("/admin/groups", get(self::get::groups))
+        .route("/admin/groups", post(self::post::create_group))
+        .route("/admin/group/:id", get(self::get::group))
+        .route(
+            "/admin/group/:id/solutions",
+            get(self::get::group_solutions),
+        )
+        .route("/admin/group/:id/members", post(self::post::add_member))
+        .route(
+            "/admin/group/:id/members/:user_id/remove",
+            post(self::post::remove_member),
+        )
+        .route(
+            "/admin/group/:id/problems",
+            post(self::post::assign_problem),
+        )
+        .route(
+            "/admin/group/:id/problems/:problem_id/remove",
+            post(self::post::unassign_problem),
+        )
+        .route("/admin/grades", get(self::get::grades))
+        .route("/admin/grades/export", get(self::get::export_grades))
+        .route("/admin/group/:id/invites", post(self::post::create_invite))
+        .route(
+            "/admin/group/:id/invites/:invite_id/remove",
+            post(self::post::delete_invite),
+        )
+        .route_layer(permission_required!(Backend, Permission::ManageGroups));
+
+    let users = Router::new()
+        .route("/admin/users", get(self::get::users))
+        .route("/admin/user/:id/role", post(self::post::set_role))
+        .route("/admin/user/:id/reset", post(self::post::reset_password))
+        .route("/admin/users/import", get(self::get::import))
+        .route("/admin/users/import", post(self::post::import))
+        .route("/admin/submissions/archive", get(self::get::archive))
+        .route("/admin/similarity", get(self::get::similarity))
+        .route(
+            "/admin/similarity/compare/:a/:b",
+            get(self::get::similarity_compare),
+        )
+        .route(
+            "/admin/submissions/archive.tar",
+            get(self::get::download_archive),
+        )
+        .route_layer(permission_required!(Backend, Permission::ManageUsers));
+
+    groups.merge(users).with_state(AdminState { db })
+}
+
+/// Loads a group and checks that the current user may manage it. Instructors
+/// only get access to the groups they own.
+async fn managed_group(db: &Db, user: &User, id: i64) -> Result<Group, StatusCode> {
+    match Group::by_id(db, id).await {
+        Ok(Some(group)) if group.can_manage(user) => Ok(group),
+        Ok(Some(_)) => Err(StatusCode::FORBIDDEN),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+mod get {
+    use super::*;
+
+    pub async fn grades(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+    ) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match Group::list_for(&state.db, &user).await {
+                Ok(groups) => GradesTemplate {
+                    messages: messages.into_iter().collect(),
+                    is_admin: user.is_admin(),
+                    username: user.username,
+                    groups,
+                }
+                .into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn similarity(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Form(query): Form<SimilarityQuery>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let min = query.min.unwrap_or(50).min(100);
+        let (submissions, pairs) = if query.problem.is_empty() {
+            (0, Vec::new())
+        } else {
+            match similarity::accepted_submissions(&state.db, &query.problem).await {
+                Ok(submissions) => (
+                    submissions.len(),
+                    similarity::rank_pairs(&submissions, min as f64 / 100.0),
+                ),
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            }
+        };
+        SimilarityTemplate {
+            messages: messages.into_iter().collect(),
+            username: user.username,
+            problems: ProblemsInfo::get_cached_problems_info()
+                .await
+                .unwrap()
+                .problem
+                .clone(),
+            problem_id: query.problem,
+            min,
+            submissions,
+            pairs,
+        }
+        .into_response()
+    }
+
+    pub async fn similarity_compare(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Path((a, b)): Path<(i64, i64)>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let (a, b) = match (
+            similarity::submission(&state.db, a).await,
+            similarity::submission(&state.db, b).await,
+        ) {
+            (Ok(Some(a)), Ok(Some(b))) => (a, b),
+            (Ok(_), Ok(_)) => return StatusCode::NOT_FOUND.into_response(),
+            _ => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let percent = Fingerprinted::new(a.id, a.username.clone(), &a.content)
+            .similarity(&Fingerprinted::new(b.id, b.username.clone(), &b.content));
+        let (segments_a, segments_b) = similarity::compare(&a.content, &b.content);
+        SimilarityCompareTemplate {
+            messages: messages.into_iter().collect(),
+            username: user.username,
+            problem_id: a.problem_id.clone(),
+            percent: (percent * 100.0).round() as u32,
+            a,
+            b,
+            segments_a,
+            segments_b,
+        }
+        .into_response()
+    }
+
+    pub async fn archive(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+    ) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match Group::list_for(&state.db, &user).await {
+                Ok(groups) => ArchiveTemplate {
+                    messages: messages.into_iter().collect(),
+                    username: user.username,
+                    groups,
+                }
+                .into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn download_archive(
+        State(state): State<AdminState>,
+        Form(query): Form<ArchiveQuery>,
+    ) -> impl IntoResponse {
+        let selection = match query.select.as_str() {
+            "" => Selection::All,
+            select => match Selection::parse(select) {
+                Some(selection) => selection,
+                None => return StatusCode::BAD_REQUEST.into_response(),
+            },
+        };
+        let group_id = match query.group.as_str() {
+            "" => None,
+            group => match group.parse::<i64>() {
+                Ok(id) => Some(id),
+                Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+            },
+        };
+        (
+            [
+                (header::CONTENT_TYPE, "application/x-tar"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"submissions.tar\"",
+                ),
+            ],
+            Body::from_stream(submissions_tar(state.db, selection, group_id)),
+        )
+            .into_response()
+    }
+
+    pub async fn export_grades(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Form(query): Form<GradeExportQuery>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        // Instructors only see the groups they own, admins may export
+        // everyone at once.
+        let group_id = match query.group.parse::<i64>() {
+            Ok(id) => match managed_group(&state.db, &user, id).await {
+                Ok(group) => Some(group.id),
+                Err(status) => return status.into_response(),
+            },
+            Err(_) if query.group.is_empty() && user.is_admin() => None,
+            Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        };
+        let date = |date: &str| match date {
+            "" => Ok(None),
+            date => parse_date(date).map(Some),
+        };
+        let filter = match (date(&query.from), date(&query.to)) {
+            (Ok(from), Ok(to)) => GradeFilter { group_id, from, to },
+            (Err(e), _) | (_, Err(e)) => {
+                messages.error(format!("{:#}", e));
+                return Redirect::to("/admin/grades").into_response();
+            }
+        };
+
+        let report = match grade_report(&state.db, &filter).await {
+            Ok(report) => report,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let (body, content_type, filename) = match query.format.as_str() {
+            "json" => (report.to_json(), "application/json", "grades.json"),
+            _ => (report.to_csv(), "text/csv; charset=utf-8", "grades.csv"),
+        };
+        match body {
+            Ok(body) => (
+                [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}\"", filename),
+                    ),
+                ],
+                body,
+            )
+                .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn groups(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+    ) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => match Group::list_for(&state.db, &user).await {
+                Ok(groups) => GroupsTemplate {
+                    messages: messages.into_iter().collect(),
+                    username: user.username,
+                    groups,
+                }
+                .into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn group(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Path(id): Path<i64>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        let (Ok(members), Ok(assigned), Ok(problems_info), Ok(invites)) = (
+            group.members(&state.db).await,
+            group.problem_ids(&state.db).await,
+            ProblemsInfo::get_cached_problems_info().await,
+            InviteCode::list_for_group(&state.db, &group).await,
+        ) else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        GroupTemplate {
+            messages: messages.into_iter().collect(),
+            username: user.username,
+            group,
+            members,
+            assigned,
+            problems: problems_info.problem.clone(),
+            invites,
+        }
+        .into_response()
+    }
+
+    pub async fn group_solutions(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Path(id): Path<i64>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        match group.solutions(&state.db).await {
+            Ok(solutions) => GroupSolutionsTemplate {
+                messages: messages.into_iter().collect(),
+                username: user.username,
+                group,
+                solutions,
+            }
+            .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn users(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(ref user) => match auth_session.backend.list_users().await {
+                Ok(users) => UsersTemplate {
+                    messages: messages.into_iter().collect(),
+                    username: user.username.clone(),
+                    users,
+                    roles: Role::ALL,
+                }
+                .into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn import(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => ImportTemplate {
+                messages: messages.into_iter().collect(),
+                username: user.username,
+            }
+            .into_response(),
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+mod post {
+    use super::*;
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct CreateGroupForm {
+        name: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct AddMemberForm {
+        username: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct AssignProblemForm {
+        problem_id: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct CreateInviteForm {
+        max_uses: i64,
+        /// Left empty for codes that never expire.
+        valid_days: String,
+    }
+
+    #[derive(Debug, Clone, Deserialize)]
+    pub struct SetRoleForm {
+        role: Role,
+    }
+
+    pub async fn create_group(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Form(form): Form<CreateGroupForm>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let name = form.name.trim();
+        if name.is_empty() {
+            messages.error("Group name must not be empty");
+            return Redirect::to("/admin/groups").into_response();
+        }
+        match Group::create(&state.db, name, &user).await {
+            Ok(group) => Redirect::to(&format!("/admin/group/{}", group.id)).into_response(),
+            Err(e) => {
+                messages.error(e.to_string());
+                Redirect::to("/admin/groups").into_response()
+            }
+        }
+    }
+
+    pub async fn add_member(
+        auth_session: AuthSession,
+        mut messages: Messages,
+        State(state): State<AdminState>,
+        Path(id): Path<i64>,
+        Form(form): Form<AddMemberForm>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        for username in form
+            .username
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+        {
+            if let Err(e) = group.add_member(&state.db, username).await {
+                messages = messages.error(e.to_string());
+            }
+        }
+        Redirect::to(&format!("/admin/group/{}", group.id)).into_response()
+    }
+
+    pub async fn remove_member(
+        auth_session: AuthSession,
+        State(state): State<AdminState>,
+        Path((id, user_id)): Path<(i64, i64)>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        match group.remove_member(&state.db, user_id).await {
+            Ok(_) => Redirect::to(&format!("/admin/group/{}", group.id)).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn assign_problem(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Path(id): Path<i64>,
+        Form(form): Form<AssignProblemForm>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        if Problem::by_id(&form.problem_id).await.is_err() {
+            messages.error(format!("Unknown problem {}", form.problem_id));
+        } else if let Err(e) = group.assign_problem(&state.db, &form.problem_id).await {
+            messages.error(e.to_string());
+        }
+        Redirect::to(&format!("/admin/group/{}", group.id)).into_response()
+    }
+
+    pub async fn unassign_problem(
+        auth_session: AuthSession,
+        State(state): State<AdminState>,
+        Path((id, problem_id)): Path<(i64, String)>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        match group.unassign_problem(&state.db, &problem_id).await {
+            Ok(_) => Redirect::to(&format!("/admin/group/{}", group.id)).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn create_invite(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<AdminState>,
+        Path(id): Path<i64>,
+        Form(form): Form<CreateInviteForm>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        let valid_days = match form.valid_days.trim() {
+            "" => None,
+            days => days
+                .parse::<i64>()
+                .ok()
+                .filter(|&days| days >= 1)
+                .or(Some(0)),
+        };
+        if form.max_uses < 1 || valid_days == Some(0) {
+            messages.error("Uses and validity must be at least 1");
+        } else if let Err(e) =
+            InviteCode::create(&state.db, &group, &user, form.max_uses, valid_days).await
+        {
+            messages.error(e.to_string());
+        }
+        Redirect::to(&format!("/admin/group/{}", group.id)).into_response()
+    }
+
+    pub async fn delete_invite(
+        auth_session: AuthSession,
+        State(state): State<AdminState>,
+        Path((id, invite_id)): Path<(i64, i64)>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let group = match managed_group(&state.db, &user, id).await {
+            Ok(group) => group,
+            Err(status) => return status.into_response(),
+        };
+        match InviteCode::delete(&state.db, &group, invite_id).await {
+            Ok(_) => Redirect::to(&format!("/admin/group/{}", group.id)).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn set_role(
+        auth_session: AuthSession,
+        messages: Messages,
+        Path(id): Path<i64>,
+        Form(form): Form<SetRoleForm>,
+    ) -> impl IntoResponse {
+        match auth_session.user {
+            Some(ref user) if user.id == id && form.role != Role::Admin => {
+                messages.error("You cannot remove your own admin role");
+                Redirect::to("/admin/users").into_response()
+            }
+            Some(_) => match auth_session.backend.set_role(id, form.role).await {
+                Ok(_) => Redirect::to("/admin/users").into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn reset_password(
+        auth_session: AuthSession,
+        messages: Messages,
+        Path(id): Path<i64>,
+    ) -> impl IntoResponse {
+        let Some(ref user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+        let target = match auth_session.backend.get_user(&id).await {
+            Ok(Some(target)) => target,
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        match auth_session
+            .backend
+            .create_reset_token(target.id, user.id)
+            .await
+        {
+            Ok(token) => ResetLinkTemplate {
+                messages: messages.into_iter().collect(),
+                username: user.username.clone(),
+                target: target.username,
+                token,
+            }
+            .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    pub async fn import(
+        auth_session: AuthSession,
+        messages: Messages,
+        mut multipart: Multipart,
+    ) -> impl IntoResponse {
+        let Some(ref user) = auth_session.user else {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        };
+
+        let mut csv = None;
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some("csv") {
+                csv = field.bytes().await.ok();
+            }
+        }
+        let Some(csv) = csv else {
+            messages.error("No CSV file was uploaded");
+            return Redirect::to("/admin/users/import").into_response();
+        };
+
+        let credentials = match import_users(&auth_session.backend, csv.as_ref(), user).await {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                messages.error(format!("{:#}", e));
+                return Redirect::to("/admin/users/import").into_response();
+            }
+        };
+        match credentials_csv(&credentials) {
+            Ok(body) => (
+                [
+                    (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"credentials.csv\"",
+                    ),
+                ],
+                body,
+            )
+                .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
@@ -1,32 +1,110 @@
+use std::net::SocketAddr;
+
 use anyhow::Result;
+use async_trait::async_trait;
+use axum::middleware;
 use axum_login::{
     login_required,
     tower_sessions::{ExpiredDeletion, Expiry, SessionManagerLayer},
     AuthManagerLayerBuilder,
 };
 use axum_messages::MessagesManagerLayer;
-use sqlx::SqlitePool;
 use time::Duration;
 use tokio::{signal, sync::mpsc::Sender, task::AbortHandle};
 use tower_http::trace::{self, TraceLayer};
-use tower_sessions::cookie::Key;
-use tower_sessions_sqlx_store::SqliteStore;
+use tower_sessions::{
+    cookie::Key,
+    session::{Id, Record},
+    session_store, SessionStore,
+};
+use tower_sessions_sqlx_store::{PostgresStore, SqliteStore};
 use tracing::Level;
 
 use crate::{
     config::{get_cached_config, Task},
+    db::Db,
     users::Backend,
-    web::{auth, protected},
+    web::{account, admin, auth, health, judge, lti, metrics, protected, setup},
 };
 
+/// Sessions are kept in the same database as everything else.
+#[derive(Debug, Clone)]
+enum Sessions {
+    Sqlite(SqliteStore),
+    Postgres(PostgresStore),
+}
+
+impl Sessions {
+    fn new(db: &Db) -> Self {
+        match db {
+            Db::Sqlite(pool) => Sessions::Sqlite(SqliteStore::new(pool.clone())),
+            Db::Postgres(pool) => Sessions::Postgres(PostgresStore::new(pool.clone())),
+        }
+    }
+
+    async fn migrate(&self) -> sqlx::Result<()> {
+        match self {
+            Sessions::Sqlite(store) => store.migrate().await,
+            Sessions::Postgres(store) => store.migrate().await,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for Sessions {
+    async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+        match self {
+            Sessions::Sqlite(store) => store.create(record).await,
+            Sessions::Postgres(store) => store.create(record).await,
+        }
+    }
+
+    async fn save(&self, record: &Record) -> session_store::Result<()> {
+        match self {
+            Sessions::Sqlite(store) => store.save(record).await,
+            Sessions::Postgres(store) => store.save(record).await,
+        }
+    }
+
+    async fn load(&self, session_id: &Id) -> session_store::Result<Option<Record>> {
+        match self {
+            Sessions::Sqlite(store) => store.load(session_id).await,
+            Sessions::Postgres(store) => store.load(session_id).await,
+        }
+    }
+
+    async fn delete(&self, session_id: &Id) -> session_store::Result<()> {
+        match self {
+            Sessions::Sqlite(store) => store.delete(session_id).await,
+            Sessions::Postgres(store) => store.delete(session_id).await,
+        }
+    }
+}
+
+#[async_trait]
+impl ExpiredDeletion for Sessions {
+    async fn delete_expired(&self) -> session_store::Result<()> {
+        match self {
+            Sessions::Sqlite(store) => store.delete_expired().await,
+            Sessions::Postgres(store) => store.delete_expired().await,
+        }
+    }
+}
+
 pub struct App {
-    db: SqlitePool,
+    db: Db,
     tx: Sender<Task>,
+    /// Whether this process judges too, which `/readyz` then checks.
+    local_executor: bool,
 }
 
 impl App {
-    pub async fn new(db: SqlitePool, tx: Sender<Task>) -> Result<Self> {
-        Ok(Self { db, tx })
+    pub async fn new(db: Db, tx: Sender<Task>, local_executor: bool) -> Result<Self> {
+        Ok(Self {
+            db,
+            tx,
+            local_executor,
+        })
     }
 
     pub async fn serve(self) -> Result<()> {
@@ -34,7 +112,7 @@ impl App {
         //
         // This uses `tower-sessions` to establish a layer that will provide the session
         // as a request extension.
-        let session_store = SqliteStore::new(self.db.clone());
+        let session_store = Sessions::new(&self.db);
         session_store.migrate().await?;
 
         let deletion_task = tokio::task::spawn(
@@ -44,7 +122,14 @@ impl App {
         );
 
         // Generate a cryptographic key to sign the session cookie.
-        let key = Key::from(get_cached_config().await?.server.secret_key.as_bytes());
+        let key = Key::from(
+            get_cached_config()
+                .await?
+                .server
+                .secret_key
+                .expose()
+                .as_bytes(),
+        );
 
         let session_layer = SessionManagerLayer::new(session_store)
             .with_secure(false)
@@ -58,15 +143,25 @@ impl App {
         let backend = Backend::new(self.db.clone());
         let auth_layer = AuthManagerLayerBuilder::new(backend, session_layer).build();
 
-        let app = protected::router(self.db, self.tx)
+        let app = protected::router(self.db.clone(), self.tx)
+            .merge(admin::router(self.db.clone()))
+            .merge(account::router())
+            .merge(lti::deep_link_router())
+            .route_layer(middleware::from_fn(account::require_password_change))
             .route_layer(login_required!(Backend, login_url = "/login"))
             .merge(auth::router())
+            .merge(lti::router())
+            .merge(setup::router())
+            .merge(judge::router(self.db.clone()))
+            .merge(metrics::router(self.db.clone()))
+            .merge(health::router(self.db, self.local_executor))
             .layer(MessagesManagerLayer)
             .layer(auth_layer)
+            .layer(middleware::from_fn(crate::metrics::label_request))
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                    .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
+                    .on_response(crate::metrics::OnResponseMetrics::new(Level::INFO)),
             );
 
         let listener = tokio::net::TcpListener::bind(&get_cached_config().await?.server.address)
@@ -74,9 +169,13 @@ impl App {
             .unwrap();
 
         // Ensure we use a shutdown signal to abort the deletion task.
-        axum::serve(listener, app.into_make_service())
-            .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
-            .await?;
+        // The peer address is needed to throttle logins per address.
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal(deletion_task.abort_handle()))
+        .await?;
 
         deletion_task.await??;
 
@@ -1,15 +1,24 @@
 use askama::Template;
+use std::net::SocketAddr;
+
 use axum::{
-    extract::Query,
-    http::StatusCode,
+    extract::{ConnectInfo, Query},
+    http::{HeaderMap, StatusCode},
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Form, Router,
 };
 use axum_messages::{Message, Messages};
+use openidconnect::url::form_urlencoded;
 use serde::Deserialize;
+use tower_sessions::Session;
 
-use crate::users::{AuthSession, Credentials};
+use crate::{
+    config::{get_cached_config, RegistrationMode},
+    oidc::{self, PendingLogin, Provider},
+    rate_limit,
+    users::{validate_password, AuthSession, Credentials},
+};
 
 #[derive(Template)]
 #[template(path = "login.html")]
@@ -17,6 +26,7 @@ pub struct LoginTemplate {
     messages: Vec<Message>,
     next: Option<String>,
     username: String,
+    sso_label: Option<String>,
 }
 
 #[derive(Template)]
@@ -25,6 +35,18 @@ pub struct RegisterTemplate {
     messages: Vec<Message>,
     next: Option<String>,
     username: String,
+    closed: bool,
+    invite_required: bool,
+    invite_code: Option<String>,
+    min_password_length: usize,
+}
+
+#[derive(Template)]
+#[template(path = "reset.html")]
+pub struct ResetTemplate {
+    messages: Vec<Message>,
+    username: String,
+    valid: bool,
 }
 
 // This allows us to extract the "next" field from the query string. We use this
@@ -34,6 +56,56 @@ pub struct NextUrl {
     next: Option<String>,
 }
 
+// What the identity provider sends back to `/oidc/callback`.
+#[derive(Debug, Deserialize)]
+pub struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Session key of the [`PendingLogin`] while the browser is at the provider.
+const OIDC_PENDING_KEY: &str = "oidc.pending";
+
+// Invite links point at `/register?code=...` so the code is filled in.
+#[derive(Debug, Deserialize)]
+pub struct RegisterQuery {
+    next: Option<String>,
+    code: Option<String>,
+}
+
+/// `next` if it is a path on this site. Anything else is dropped, so a link
+/// to the login page cannot send people to another site after they log in:
+/// browsers read `//host` and `/\host` as other hosts and skip tabs and
+/// newlines in between.
+fn local_next(next: Option<String>) -> Option<String> {
+    next.filter(|next| {
+        next.starts_with('/')
+            && !next.starts_with("//")
+            && !next.chars().any(|c| c == '\\' || c.is_control())
+    })
+}
+
+/// `value` encoded for use in a query string.
+fn query_value(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// The address logins are throttled by. Behind a reverse proxy every request
+/// comes from the proxy, so the first `X-Forwarded-For` entry is used instead.
+fn client_ip(addr: SocketAddr, headers: &HeaderMap, behind_proxy: bool) -> String {
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|ip| ip.trim().to_string());
+    match forwarded {
+        Some(ip) if behind_proxy && !ip.is_empty() => ip,
+        _ => addr.ip().to_string(),
+    }
+}
+
 pub fn router() -> Router<()> {
     Router::new()
         .route("/login", post(self::post::login))
@@ -41,40 +113,85 @@ pub fn router() -> Router<()> {
         .route("/logout", get(self::get::logout))
         .route("/register", get(self::get::register))
         .route("/register", post(self::post::register))
+        .route("/reset/:token", get(self::get::reset))
+        .route("/reset/:token", post(self::post::reset))
+        .route("/oidc/login", get(self::get::oidc_login))
+        .route("/oidc/callback", get(self::get::oidc_callback))
 }
 
 mod post {
+    use axum::extract::Path;
+
     use crate::users::RegisterUserData;
 
     use super::*;
 
+    #[derive(Deserialize)]
+    pub struct ResetForm {
+        new_password: String,
+        new_password_confirm: String,
+    }
+
     pub async fn login(
         mut auth_session: AuthSession,
         messages: Messages,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        headers: HeaderMap,
         Form(creds): Form<Credentials>,
     ) -> impl IntoResponse {
+        let limits = &get_cached_config().await.unwrap().server.rate_limit;
+        let ip = client_ip(addr, &headers, limits.behind_proxy);
+        let db = auth_session.backend.db.clone();
+
+        let next = local_next(creds.next.clone());
+        let mut login_url = "/login".to_string();
+        if let Some(ref next) = next {
+            login_url = format!("{}?next={}", login_url, query_value(next));
+        };
+
+        match rate_limit::login_locked(&db, limits, &creds.username, &ip).await {
+            Ok(false) => {}
+            Ok(true) => {
+                messages.error(format!(
+                    "Too many failed login attempts, try again in {} minutes",
+                    (limits.login_lockout_secs + 59) / 60
+                ));
+                return Redirect::to(&login_url).into_response();
+            }
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+
         let user = match auth_session.authenticate(creds.clone()).await {
             Ok(Some(user)) => user,
             Ok(None) => {
+                if rate_limit::record_login(&db, limits, &creds.username, &ip, false)
+                    .await
+                    .is_err()
+                {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
                 messages.error("Invalid credentials");
-
-                let mut login_url = "/login".to_string();
-                if let Some(next) = creds.next {
-                    login_url = format!("{}?next={}", login_url, next);
-                };
-
                 return Redirect::to(&login_url).into_response();
             }
             Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         };
 
+        if rate_limit::record_login(&db, limits, &creds.username, &ip, true)
+            .await
+            .is_err()
+        {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+
         if auth_session.login(&user).await.is_err() {
             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
 
         messages.success(format!("Successfully logged in as {}", user.username));
 
-        if let Some(ref next) = creds.next {
+        if user.must_change_password {
+            Redirect::to("/password")
+        } else if let Some(ref next) = next {
             Redirect::to(next)
         } else {
             Redirect::to("/")
@@ -87,28 +204,180 @@ mod post {
         messages: Messages,
         Form(user_data): Form<RegisterUserData>,
     ) -> impl IntoResponse {
-        match auth_session.backend.register_user(user_data).await {
-            Ok(_) => Redirect::to("login"),
+        let policy = &get_cached_config().await.unwrap().server.registration;
+        let register_url = match user_data.invite_code {
+            Some(ref code) if !code.is_empty() => format!("register?code={}", query_value(code)),
+            _ => "register".to_string(),
+        };
+        match auth_session.backend.register_user(user_data, policy).await {
+            Ok(_) => {
+                messages.success("Registered, please log in");
+                Redirect::to("login")
+            }
             Err(e) => {
                 messages.error(e.to_string());
-                Redirect::to("register")
+                Redirect::to(&register_url)
             }
         }
     }
+
+    pub async fn reset(
+        auth_session: AuthSession,
+        messages: Messages,
+        Path(token): Path<String>,
+        Form(form): Form<ResetForm>,
+    ) -> impl IntoResponse {
+        let reset_url = format!("/reset/{}", token);
+        if form.new_password != form.new_password_confirm {
+            messages.error("The new passwords do not match");
+            return Redirect::to(&reset_url).into_response();
+        }
+        let user = match auth_session.backend.reset_token_user(&token).await {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                messages.error("This reset link is invalid or has expired");
+                return Redirect::to(&reset_url).into_response();
+            }
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let min_length = get_cached_config()
+            .await
+            .unwrap()
+            .server
+            .registration
+            .min_password_length;
+        if let Err(e) = validate_password(&user.username, &form.new_password, min_length) {
+            messages.error(e.to_string());
+            return Redirect::to(&reset_url).into_response();
+        }
+        match auth_session
+            .backend
+            .reset_password(&token, form.new_password)
+            .await
+        {
+            Ok(Some(user)) => {
+                messages.success(format!(
+                    "Password of {} was reset, please log in",
+                    user.username
+                ));
+                Redirect::to("/login").into_response()
+            }
+            Ok(None) => {
+                messages.error("This reset link is invalid or has expired");
+                Redirect::to(&reset_url).into_response()
+            }
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
 }
 
 mod get {
+    use axum::extract::Path;
+
     use super::*;
 
     pub async fn login(
         messages: Messages,
         Query(NextUrl { next }): Query<NextUrl>,
     ) -> LoginTemplate {
+        let oidc = &get_cached_config().await.unwrap().oidc;
         LoginTemplate {
             messages: messages.into_iter().collect(),
-            next,
+            next: local_next(next),
             username: String::from("No User"),
+            sso_label: oidc.as_ref().map(|oidc| oidc.button_label.clone()),
+        }
+    }
+
+    pub async fn oidc_login(
+        session: Session,
+        messages: Messages,
+        Query(NextUrl { next }): Query<NextUrl>,
+    ) -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().oidc else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let provider = match Provider::get(config).await {
+            Ok(provider) => provider,
+            Err(e) => {
+                messages.error(e.to_string());
+                return Redirect::to("/login").into_response();
+            }
+        };
+        let (url, pending) = provider.authorize_url(local_next(next));
+        if session.insert(OIDC_PENDING_KEY, pending).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+        Redirect::to(url.as_str()).into_response()
+    }
+
+    pub async fn oidc_callback(
+        mut auth_session: AuthSession,
+        session: Session,
+        messages: Messages,
+        Query(callback): Query<OidcCallback>,
+    ) -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().oidc else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let pending: PendingLogin = match session.remove(OIDC_PENDING_KEY).await {
+            Ok(Some(pending)) => pending,
+            Ok(None) => {
+                messages.error("The SSO login expired, please try again");
+                return Redirect::to("/login").into_response();
+            }
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        if let Some(error) = callback.error {
+            messages.error(format!(
+                "SSO login failed: {}",
+                callback.error_description.unwrap_or(error)
+            ));
+            return Redirect::to("/login").into_response();
+        }
+        let (Some(code), Some(state)) = (callback.code, callback.state) else {
+            return StatusCode::BAD_REQUEST.into_response();
+        };
+        if state != pending.state {
+            messages.error("The SSO login expired, please try again");
+            return Redirect::to("/login").into_response();
+        }
+
+        let next = local_next(pending.next.clone());
+        let result = match Provider::get(config).await {
+            Ok(provider) => provider.exchange(code, pending).await,
+            Err(e) => Err(e),
+        };
+        // Logged in already: the login is linked to the current account.
+        if let Some(ref current) = auth_session.user {
+            match result {
+                Ok(claims) => {
+                    match oidc::link_user(&auth_session.backend, config, &claims, current).await {
+                        Ok(()) => messages.success("SSO linked to this account"),
+                        Err(e) => messages.error(e.to_string()),
+                    }
+                }
+                Err(e) => messages.error(e.to_string()),
+            };
+            return Redirect::to("/password").into_response();
+        }
+        let user = match result {
+            Ok(claims) => oidc::login_user(&auth_session.backend, config, &claims).await,
+            Err(e) => Err(e),
+        };
+        let user = match user {
+            Ok(user) => user,
+            Err(e) => {
+                messages.error(e.to_string());
+                return Redirect::to("/login").into_response();
+            }
+        };
+
+        if auth_session.login(&user).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
+        messages.success(format!("Successfully logged in as {}", user.username));
+        Redirect::to(next.as_deref().unwrap_or("/")).into_response()
     }
 
     pub async fn logout(mut auth_session: AuthSession) -> impl IntoResponse {
@@ -120,12 +389,33 @@ mod get {
 
     pub async fn register(
         messages: Messages,
-        Query(NextUrl { next }): Query<NextUrl>,
+        Query(RegisterQuery { next, code }): Query<RegisterQuery>,
     ) -> RegisterTemplate {
+        let policy = &get_cached_config().await.unwrap().server.registration;
         RegisterTemplate {
             messages: messages.into_iter().collect(),
-            next,
+            next: local_next(next),
             username: String::from("No User"),
+            closed: policy.mode == RegistrationMode::Disabled,
+            invite_required: policy.mode == RegistrationMode::InviteCode,
+            invite_code: code,
+            min_password_length: policy.min_password_length,
+        }
+    }
+
+    pub async fn reset(
+        auth_session: AuthSession,
+        messages: Messages,
+        Path(token): Path<String>,
+    ) -> impl IntoResponse {
+        match auth_session.backend.reset_token_user(&token).await {
+            Ok(user) => ResetTemplate {
+                messages: messages.into_iter().collect(),
+                username: String::from("No User"),
+                valid: user.is_some(),
+            }
+            .into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
 }
@@ -0,0 +1,38 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, routing::get, Json, Router};
+use serde_json::json;
+
+use crate::{db::Db, health};
+
+#[derive(Clone)]
+struct HealthState {
+    db: Db,
+    local_executor: bool,
+}
+
+/// Probes for load balancers and orchestrators, without a login.
+pub fn router(db: Db, local_executor: bool) -> Router<()> {
+    Router::new()
+        .route("/healthz", get(self::get::healthz))
+        .route("/readyz", get(self::get::readyz))
+        .with_state(HealthState { db, local_executor })
+}
+
+mod get {
+    use super::*;
+
+    /// The process is up and answering.
+    pub async fn healthz() -> impl IntoResponse {
+        Json(json!({ "status": "ok" }))
+    }
+
+    /// 503 while any component fails, with the details in the body.
+    pub async fn readyz(State(state): State<HealthState>) -> impl IntoResponse {
+        let readiness = health::readiness(&state.db, state.local_executor).await;
+        let status = if readiness.is_ready() {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(readiness))
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use tracing::{error, info};
+
+use super::bearer_matches;
+use crate::{
+    config::{get_cached_config, JudgeConfiguration, MAX_LOG_LIMIT},
+    db::Db,
+    executor::{self, OutputLimits},
+    judge::{self, ClaimRequest, JobResult, RenewRequest},
+    problems::ProblemsInfo,
+    repository::Status,
+};
+
+/// All three logs at `MAX_LOG_LIMIT`, with room for escaping them in JSON.
+const RESULT_BODY_LIMIT: usize = 24 * MAX_LOG_LIMIT.0 as usize;
+
+/// Endpoints for `worker --server`, authenticated with
+/// `judge.worker_secret` instead of a login.
+pub fn router(db: Db) -> Router<()> {
+    Router::new()
+        .route("/judge/claim", post(self::post::claim))
+        .route("/judge/renew", post(self::post::renew))
+        .route("/judge/bundle/:problem_id", get(self::get::bundle))
+        .route(
+            "/judge/result",
+            post(self::post::result).layer(DefaultBodyLimit::max(RESULT_BODY_LIMIT)),
+        )
+        .with_state(db)
+}
+
+/// The judge section, if the request carries its worker secret.
+async fn authorize(headers: &HeaderMap) -> Result<&'static JudgeConfiguration, StatusCode> {
+    let judge = get_cached_config()
+        .await
+        .unwrap()
+        .judge
+        .as_ref()
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if !bearer_matches(headers, &judge.worker_secret) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(judge)
+}
+
+mod get {
+    use super::*;
+
+    pub async fn bundle(
+        headers: HeaderMap,
+        Path(problem_id): Path<String>,
+    ) -> Result<Response, StatusCode> {
+        authorize(&headers).await?;
+        let problems = ProblemsInfo::get_cached_problems_info().await.unwrap();
+        if !problems.problem.iter().any(|p| p.id == problem_id) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let dir = get_cached_config()
+            .await
+            .unwrap()
+            .problems
+            .dir
+            .join(&problem_id);
+        match judge::bundle(&dir).await {
+            Ok(bundle) => Ok((
+                [
+                    (header::CONTENT_TYPE, "application/x-tar".to_string()),
+                    (
+                        header::HeaderName::from_static("x-bundle-hash"),
+                        bundle.hash,
+                    ),
+                ],
+                bundle.tar,
+            )
+                .into_response()),
+            Err(e) => {
+                error!(problem_id, error = %e, "could not bundle problem");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+mod post {
+    use super::*;
+
+    /// Waits up to `judge.long_poll_secs` for a pending submission.
+    pub async fn claim(
+        State(db): State<Db>,
+        headers: HeaderMap,
+        Json(request): Json<ClaimRequest>,
+    ) -> Result<Response, StatusCode> {
+        let judge = authorize(&headers).await?;
+        let deadline = Instant::now() + Duration::from_secs(judge.long_poll_secs);
+        loop {
+            match judge::claim_next(&db, &request.worker).await {
+                Ok(Some(job)) => {
+                    info!(
+                        solution_id = job.solution_id,
+                        worker = request.worker,
+                        "handed out submission"
+                    );
+                    return Ok(Json(job).into_response());
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    error!(error = %e, "could not claim a submission");
+                    return Err(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Ok(StatusCode::NO_CONTENT.into_response());
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Keeps the claim of a worker that is still judging. Conflict if it
+    /// lost the claim already.
+    pub async fn renew(
+        State(db): State<Db>,
+        headers: HeaderMap,
+        Json(request): Json<RenewRequest>,
+    ) -> Result<StatusCode, StatusCode> {
+        authorize(&headers).await?;
+        match judge::renew_claim(&db, request.solution_id, &request.worker).await {
+            Ok(true) => Ok(StatusCode::NO_CONTENT),
+            Ok(false) => Err(StatusCode::CONFLICT),
+            Err(e) => {
+                error!(error = %e, "could not renew a claim");
+                Err(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+
+    pub async fn result(
+        State(db): State<Db>,
+        headers: HeaderMap,
+        Json(mut result): Json<JobResult>,
+    ) -> Result<StatusCode, StatusCode> {
+        authorize(&headers).await?;
+        // Workers cut the logs already, unless they are older.
+        let limit = OutputLimits::configured().await.unwrap().log;
+        executor::truncate_log(&mut result.verdict.stdout, limit);
+        executor::truncate_log(&mut result.verdict.stderr, limit);
+        executor::truncate_log(&mut result.verdict.diagnostics, limit);
+        if result.verdict.status == Status::Pending {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        // The lease ran out and someone else took over, or it was judged.
+        if !judge::holds_claim(&db, result.solution_id, &result.worker)
+            .await
+            .unwrap()
+        {
+            return Err(StatusCode::CONFLICT);
+        }
+        let duration = Duration::try_from_secs_f64(result.duration_secs).unwrap_or_default();
+        judge::finish(&db, result.solution_id, &result.verdict, duration)
+            .await
+            .unwrap();
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
@@ -0,0 +1,207 @@
+use askama::Template;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use axum_messages::{Message, Messages};
+use serde::Deserialize;
+use tower_sessions::Session;
+
+use crate::{
+    config::get_cached_config,
+    groups::Group,
+    lti::{self, DeepLinkRequest, LaunchTarget, LoginInitiation, LtiError, ToolKey},
+    problems::{Problem, ProblemsInfo},
+    users::AuthSession,
+};
+
+#[derive(Template)]
+#[template(path = "lti/redirect.html")]
+struct RedirectTemplate {
+    url: String,
+}
+
+#[derive(Template)]
+#[template(path = "lti/deep_link.html")]
+struct DeepLinkTemplate {
+    messages: Vec<Message>,
+    username: String,
+    problems: Vec<Problem>,
+}
+
+#[derive(Template)]
+#[template(path = "lti/deep_link_return.html")]
+struct DeepLinkReturnTemplate {
+    return_url: String,
+    jwt: String,
+}
+
+#[derive(Deserialize)]
+pub struct LaunchForm {
+    id_token: String,
+    state: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeepLinkForm {
+    problem_id: String,
+}
+
+/// Session key of the [`DeepLinkRequest`] while the instructor picks a
+/// problem.
+const DEEP_LINK_KEY: &str = "lti.deep_link";
+
+fn error_response(e: LtiError) -> Response {
+    match e {
+        LtiError::Database(_) | LtiError::Group(_) | LtiError::Backend(_) | LtiError::Key(_) => {
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        e => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+/// Endpoints the LMS talks to. They work without a session.
+pub fn router() -> Router<()> {
+    Router::new()
+        .route("/lti/login", get(self::post::login))
+        .route("/lti/login", post(self::post::login))
+        .route("/lti/launch", post(self::post::launch))
+        .route("/lti/jwks", get(self::get::jwks))
+}
+
+/// Picking problems for a deep linking request, after the launch logged the
+/// instructor in.
+pub fn deep_link_router() -> Router<()> {
+    Router::new()
+        .route("/lti/deep-link", get(self::get::deep_link))
+        .route("/lti/deep-link", post(self::post::deep_link))
+}
+
+mod get {
+    use super::*;
+
+    pub async fn jwks() -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().lti else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        match ToolKey::get(config).await {
+            Ok(key) => Json(key.jwks()).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    pub async fn deep_link(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => DeepLinkTemplate {
+                messages: messages.into_iter().collect(),
+                username: user.username,
+                problems: ProblemsInfo::get_cached_problems_info()
+                    .await
+                    .unwrap()
+                    .problem
+                    .clone(),
+            }
+            .into_response(),
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+mod post {
+    use axum::response::Redirect;
+
+    use super::*;
+
+    // Platforms may start the login with either GET or POST, `Form` reads
+    // the query string for the former.
+    pub async fn login(
+        auth_session: AuthSession,
+        Form(initiation): Form<LoginInitiation>,
+    ) -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().lti else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        match lti::begin_launch(&auth_session.backend.db, config, initiation).await {
+            Ok(url) => Redirect::to(url.as_str()).into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+
+    pub async fn launch(
+        mut auth_session: AuthSession,
+        session: Session,
+        Form(form): Form<LaunchForm>,
+    ) -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().lti else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let backend = auth_session.backend.clone();
+        let launch =
+            match lti::verify_launch(&backend.db, config, &form.id_token, &form.state).await {
+                Ok((platform, claims)) => lti::handle_launch(&backend, platform, &claims).await,
+                Err(e) => Err(e),
+            };
+        let (user, target) = match launch {
+            Ok(launch) => launch,
+            Err(e) => return error_response(e),
+        };
+        if auth_session.login(&user).await.is_err() {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+
+        match target {
+            LaunchTarget::Problem(problem_id) => RedirectTemplate {
+                url: format!("/problem/{}/solve", problem_id),
+            }
+            .into_response(),
+            LaunchTarget::DeepLinking(request) => {
+                if session.insert(DEEP_LINK_KEY, request).await.is_err() {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+                RedirectTemplate {
+                    url: "/lti/deep-link".to_string(),
+                }
+                .into_response()
+            }
+        }
+    }
+
+    pub async fn deep_link(
+        auth_session: AuthSession,
+        session: Session,
+        Form(form): Form<DeepLinkForm>,
+    ) -> impl IntoResponse {
+        let Some(ref config) = get_cached_config().await.unwrap().lti else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let request: DeepLinkRequest = match session.remove(DEEP_LINK_KEY).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return StatusCode::BAD_REQUEST.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let Ok(problem) = Problem::by_id(&form.problem_id).await else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if let Some(group_id) = request.group_id {
+            let db = &auth_session.backend.db;
+            if let Some(group) = Group::by_id(db, group_id).await.unwrap() {
+                group.assign_problem(db, &problem.id).await.unwrap();
+            }
+        }
+        let jwt = match ToolKey::get(config).await {
+            Ok(key) => lti::deep_link_response(config, key, &request, &problem),
+            Err(e) => Err(e),
+        };
+        match jwt {
+            Ok(jwt) => DeepLinkReturnTemplate {
+                return_url: request.return_url,
+                jwt,
+            }
+            .into_response(),
+            Err(e) => error_response(e),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use tracing::error;
+
+use super::bearer_matches;
+use crate::{config::get_cached_config, db::Db, metrics};
+
+/// Prometheus scrape endpoint, behind `server.metrics_token`. Not found
+/// while no token is set.
+pub fn router(db: Db) -> Router<()> {
+    Router::new()
+        .route("/metrics", get(self::get::metrics))
+        .with_state(db)
+}
+
+mod get {
+    use super::*;
+
+    pub async fn metrics(State(db): State<Db>, headers: HeaderMap) -> impl IntoResponse {
+        let token = &get_cached_config().await.unwrap().server.metrics_token;
+        // Queue activity and latencies are nobody's business by default.
+        if token.is_empty() {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        if !bearer_matches(&headers, token) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        match metrics::render(&db).await {
+            Ok(body) => {
+                ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+            }
+            Err(e) => {
+                error!(error = %e, "could not render metrics");
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
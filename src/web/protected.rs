@@ -1,14 +1,21 @@
-use crate::{config::Task, problems::ProblemsInfo, users::AuthSession};
+use crate::{
+    config::{Task, MAX_SOURCE_SIZE},
+    db::Db,
+    executor,
+    groups::{assigned_problem_ids, can_solve, can_view_submissions_of},
+    problems::ProblemsInfo,
+    repository::{self, Status},
+    users::AuthSession,
+};
 use askama::Template;
 use axum::{
-    extract::Path,
-    http::StatusCode,
+    extract::{DefaultBodyLimit, Path},
+    http::{header, StatusCode},
     response::{IntoResponse, Redirect},
     routing::{get, post},
     Router,
 };
 use axum_messages::{Message, Messages};
-use sqlx::SqlitePool;
 use time::OffsetDateTime;
 use tokio::sync::mpsc::Sender;
 use tower_http::services::ServeDir;
@@ -18,6 +25,8 @@ use tower_http::services::ServeDir;
 struct IndexTemplate<'a> {
     messages: Vec<Message>,
     username: &'a str,
+    is_staff: bool,
+    is_admin: bool,
 }
 
 #[derive(Template)]
@@ -34,6 +43,8 @@ struct SolveTemplate<'a> {
     messages: Vec<Message>,
     index_html: String,
     username: &'a str,
+    /// One editor tab each, none for a single file problem.
+    files: Vec<String>,
 }
 
 #[derive(Template)]
@@ -51,15 +62,26 @@ struct SolutionBadgeTemplate {
     text: String,
     color: String,
     should_refresh: bool,
-    solution_id: String,
+    solution_id: i64,
 }
 
+/// How much of a log the page shows, the rest is in the download.
+const OUTPUT_PREVIEW_BYTES: usize = 64 * 1024;
+
+/// Both the textarea and the file at `MAX_SOURCE_SIZE`, with room for the
+/// rest of the form.
+const SOLVE_BODY_LIMIT: usize = 2 * MAX_SOURCE_SIZE.0 as usize + 64 * 1024;
+
 #[derive(Template)]
 #[template(path = "embed/solution_output.html")]
 struct SolutionOutputTemplate {
+    pending: bool,
     stdout: Option<String>,
     stderr: Option<String>,
+    /// Compiler warnings and sanitizer findings, if the problem asks for them.
+    diagnostics: Option<String>,
     solution_id: i64,
+    filename: Option<String>,
     created_at: Option<OffsetDateTime>,
     executed_at: Option<OffsetDateTime>,
 }
@@ -75,20 +97,24 @@ struct StatusTemplate {
 
 #[derive(Clone, Debug)]
 pub struct ServerState {
-    db: SqlitePool,
+    db: Db,
     tx: Sender<Task>,
 }
 
-pub fn router(db: SqlitePool, tx: Sender<Task>) -> Router<()> {
+pub fn router(db: Db, tx: Sender<Task>) -> Router<()> {
     Router::new()
         .route("/", get(self::get::index))
         .route("/problems", get(self::get::problems))
         .route("/problem/:id/solve", get(self::get::solve))
-        .route("/problem/:id/solve", post(self::post::solve))
+        .route(
+            "/problem/:id/solve",
+            post(self::post::solve).layer(DefaultBodyLimit::max(SOLVE_BODY_LIMIT)),
+        )
         .route("/problem/:id/status", get(self::get::status))
         .route("/problem/:id/badge", get(self::get::badge))
         .route("/solution/:id/badge", get(self::get::solution_badge))
         .route("/solution/:id/output", get(self::get::solution_output))
+        .route("/solution/:id/log/:stream", get(self::get::solution_log))
         .nest_service("/public", ServeDir::new("public"))
         .with_state(ServerState { db, tx })
         .fallback(|| async { Redirect::to("/") })
@@ -106,6 +132,8 @@ mod get {
             Some(user) => IndexTemplate {
                 messages: messages.into_iter().collect(),
                 username: &user.username,
+                is_staff: user.is_staff(),
+                is_admin: user.is_admin(),
             }
             .into_response(),
 
@@ -113,17 +141,29 @@ mod get {
         }
     }
 
-    pub async fn problems(auth_session: AuthSession, messages: Messages) -> impl IntoResponse {
+    pub async fn problems(
+        auth_session: AuthSession,
+        messages: Messages,
+        State(state): State<ServerState>,
+    ) -> impl IntoResponse {
         match auth_session.user {
-            Some(user) => ProblemsTemplate {
-                messages: messages.into_iter().collect(),
-                problems_info: ProblemsInfo::get_cached_problems_info()
+            Some(user) => {
+                let mut problems_info = ProblemsInfo::get_cached_problems_info()
                     .await
                     .unwrap()
-                    .clone(),
-                username: &user.username,
+                    .clone();
+                // Students in a group only see the problems assigned to it.
+                let assigned = assigned_problem_ids(&state.db, user.id).await.unwrap();
+                if !assigned.is_empty() {
+                    problems_info.problem.retain(|p| assigned.contains(&p.id));
+                }
+                ProblemsTemplate {
+                    messages: messages.into_iter().collect(),
+                    problems_info,
+                    username: &user.username,
+                }
+                .into_response()
             }
-            .into_response(),
 
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -132,22 +172,31 @@ mod get {
     pub async fn solve(
         auth_session: AuthSession,
         messages: Messages,
+        State(state): State<ServerState>,
         Path(id): Path<String>,
     ) -> impl IntoResponse {
         match auth_session.user {
-            Some(user) => SolveTemplate {
-                messages: messages.into_iter().collect(),
-                username: &user.username,
-                index_html: markdown::to_html(
-                    &Problem::by_id(&id)
-                        .await
-                        .unwrap()
-                        .get_index_md()
-                        .await
-                        .unwrap(),
-                ),
+            Some(user) => {
+                let Ok(problem) = Problem::by_id(&id).await else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                // Problems outside the group's set are hidden from the list,
+                // and not reachable by URL either.
+                if !can_solve(&state.db, user.id, &id).await.unwrap() {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                SolveTemplate {
+                    messages: messages.into_iter().collect(),
+                    username: &user.username,
+                    index_html: markdown::to_html(&problem.get_index_md().await.unwrap()),
+                    files: if problem.has_several_files() {
+                        problem.files.clone()
+                    } else {
+                        Vec::new()
+                    },
+                }
+                .into_response()
             }
-            .into_response(),
 
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -160,61 +209,44 @@ mod get {
     ) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
-                let texts = sqlx::query!(
-                    "select status from solutions where userid = ? and problem_id = ?",
-                    user.id,
-                    problem_id
-                )
-                .fetch_all(&state.db)
-                .await
-                .unwrap();
-                if texts.is_empty() {
+                if !can_solve(&state.db, user.id, &problem_id).await.unwrap() {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                let statuses: Vec<Status> =
+                    repository::solutions_for(&state.db, user.id, &problem_id)
+                        .await
+                        .unwrap()
+                        .into_iter()
+                        .map(|solution| solution.status)
+                        .collect();
+                if statuses.is_empty() {
                     BadgeTemplate {
                         text: "未完了".to_string(),
                         color: "text-bg-light".to_string(),
                         should_refresh: false,
                         problem_id,
                     }
-                } else if texts
-                    .iter()
-                    .flat_map(|s| s.status.clone())
-                    .any(|s| s.to_lowercase() == "ac")
-                {
+                } else if statuses.contains(&Status::Accepted) {
                     BadgeTemplate {
                         text: "完了".to_string(),
                         color: "text-bg-success".to_string(),
                         should_refresh: false,
                         problem_id,
                     }
-                } else if texts
-                    .iter()
-                    .flat_map(|s| s.status.clone())
-                    .any(|s| s.to_lowercase() == "pending")
-                {
+                } else if statuses.contains(&Status::Pending) {
                     BadgeTemplate {
                         text: "チェック".to_string(),
                         color: "text-bg-warning".to_string(),
                         should_refresh: true,
                         problem_id,
                     }
-                } else if texts
-                    .iter()
-                    .flat_map(|s| s.status.clone())
-                    .any(|s| s.to_lowercase() == "wa")
-                {
+                } else {
                     BadgeTemplate {
                         text: "失敗".to_string(),
                         color: "text-bg-danger".to_string(),
                         should_refresh: false,
                         problem_id,
                     }
-                } else {
-                    BadgeTemplate {
-                        text: "不明".to_string(),
-                        color: "badge text-bg-secondary".to_string(),
-                        should_refresh: false,
-                        problem_id,
-                    }
                 }
                 .into_response()
             }
@@ -226,47 +258,51 @@ mod get {
     pub async fn solution_badge(
         auth_session: AuthSession,
         State(state): State<ServerState>,
-        Path(solution_id): Path<String>,
+        Path(solution_id): Path<i64>,
     ) -> impl IntoResponse {
         match auth_session.user {
-            Some(_) => {
-                let texts = sqlx::query!("select status from solutions where id = ?", solution_id)
-                    .fetch_one(&state.db)
+            Some(user) => {
+                let Some(solution) = repository::solution(&state.db, solution_id).await.unwrap()
+                else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                if !can_view_submissions_of(&state.db, &user, solution.user_id)
                     .await
-                    .unwrap();
-                match texts.status {
-                    Some(status) => match status.to_lowercase().as_str() {
-                        "ac" => SolutionBadgeTemplate {
-                            text: "完了".to_string(),
-                            color: "text-bg-success".to_string(),
-                            should_refresh: false,
-                            solution_id,
-                        },
-                        "wa" => SolutionBadgeTemplate {
-                            text: "失敗".to_string(),
-                            color: "text-bg-danger".to_string(),
-                            should_refresh: false,
-                            solution_id,
-                        },
-                        "pending" => SolutionBadgeTemplate {
-                            text: "チェック".to_string(),
-                            color: "text-bg-warning".to_string(),
-                            should_refresh: true,
-                            solution_id,
-                        },
-                        _ => SolutionBadgeTemplate {
-                            text: "不明".to_string(),
-                            color: "badge text-bg-secondary".to_string(),
-                            should_refresh: false,
-                            solution_id,
-                        },
+                    .unwrap()
+                {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                match solution.status {
+                    Status::Accepted => SolutionBadgeTemplate {
+                        text: "完了".to_string(),
+                        color: "text-bg-success".to_string(),
+                        should_refresh: false,
+                        solution_id,
                     },
-                    None => SolutionBadgeTemplate {
-                        text: "未完了".to_string(),
-                        color: "text-bg-light".to_string(),
+                    Status::WrongAnswer => SolutionBadgeTemplate {
+                        text: "失敗".to_string(),
+                        color: "text-bg-danger".to_string(),
+                        should_refresh: false,
+                        solution_id,
+                    },
+                    Status::OutputLimitExceeded => SolutionBadgeTemplate {
+                        text: "出力超過".to_string(),
+                        color: "text-bg-danger".to_string(),
+                        should_refresh: false,
+                        solution_id,
+                    },
+                    Status::RuleViolation => SolutionBadgeTemplate {
+                        text: "規則違反".to_string(),
+                        color: "text-bg-danger".to_string(),
                         should_refresh: false,
                         solution_id,
                     },
+                    Status::Pending => SolutionBadgeTemplate {
+                        text: "チェック".to_string(),
+                        color: "text-bg-warning".to_string(),
+                        should_refresh: true,
+                        solution_id,
+                    },
                 }
                 .into_response()
             }
@@ -278,23 +314,33 @@ mod get {
     pub async fn solution_output(
         auth_session: AuthSession,
         State(state): State<ServerState>,
-        Path(solution_id): Path<String>,
+        Path(solution_id): Path<i64>,
     ) -> impl IntoResponse {
         match auth_session.user {
-            Some(_) => {
-                let record = sqlx::query!(
-                    "select id,stdout,stderr,created_at,executed_at from solutions where id = ?",
-                    solution_id
-                )
-                .fetch_one(&state.db)
-                .await
-                .unwrap();
+            Some(user) => {
+                let Some(solution) = repository::solution(&state.db, solution_id).await.unwrap()
+                else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                if !can_view_submissions_of(&state.db, &user, solution.user_id)
+                    .await
+                    .unwrap()
+                {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                let preview = |mut log: String| {
+                    executor::truncate_log(&mut log, OUTPUT_PREVIEW_BYTES);
+                    log
+                };
                 SolutionOutputTemplate {
-                    stdout: record.stdout,
-                    stderr: record.stderr,
-                    solution_id: record.id,
-                    created_at: record.created_at,
-                    executed_at: record.executed_at,
+                    pending: solution.status == Status::Pending,
+                    stdout: solution.stdout.map(preview),
+                    stderr: solution.stderr.map(preview),
+                    diagnostics: solution.diagnostics.map(preview),
+                    solution_id,
+                    filename: solution.filename,
+                    created_at: solution.created_at,
+                    executed_at: solution.executed_at,
                 }
                 .into_response()
             }
@@ -303,6 +349,56 @@ mod get {
         }
     }
 
+    /// The whole stored stdout, stderr or diagnostics, as a file.
+    pub async fn solution_log(
+        auth_session: AuthSession,
+        State(state): State<ServerState>,
+        Path((solution_id, stream)): Path<(i64, String)>,
+    ) -> impl IntoResponse {
+        match auth_session.user {
+            Some(user) => {
+                let Some(solution) = repository::solution(&state.db, solution_id).await.unwrap()
+                else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                if !can_view_submissions_of(&state.db, &user, solution.user_id)
+                    .await
+                    .unwrap()
+                {
+                    return StatusCode::FORBIDDEN.into_response();
+                }
+                let log = match stream.as_str() {
+                    "stdout" => solution.stdout,
+                    "stderr" => solution.stderr,
+                    "diagnostics" => solution.diagnostics,
+                    _ => None,
+                };
+                let Some(log) = log else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                (
+                    [
+                        (
+                            header::CONTENT_TYPE,
+                            "text/plain; charset=utf-8".to_string(),
+                        ),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!(
+                                "attachment; filename=\"solution-{}-{}.txt\"",
+                                solution_id, stream
+                            ),
+                        ),
+                    ],
+                    log,
+                )
+                    .into_response()
+            }
+
+            None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
     pub async fn status(
         auth_session: AuthSession,
         messages: Messages,
@@ -311,22 +407,25 @@ mod get {
     ) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
-                let record = sqlx::query!(
-                    "select id from solutions where userid = ? and problem_id = ?",
-                    user.id,
-                    problem_id
-                )
-                .fetch_all(&state.db)
-                .await
-                .unwrap();
+                // Like the solve page, for problems the group no longer has.
+                if !can_solve(&state.db, user.id, &problem_id).await.unwrap() {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                let solutions: Vec<i64> =
+                    repository::solutions_for(&state.db, user.id, &problem_id)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|solution| solution.id)
+                        .collect();
                 StatusTemplate {
                     messages: messages.into_iter().collect(),
                     username: user.username,
-                    solutions: record.iter().map(|r| r.id).collect(),
+                    solutions,
                     problem_id,
                 }
+                .into_response()
             }
-            .into_response(),
 
             None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
@@ -336,37 +435,187 @@ mod get {
 mod post {
     use askama_axum::IntoResponse;
     use axum::{
-        extract::{Path, State},
+        extract::{multipart::Field, Multipart, Path, State},
         http::StatusCode,
         response::Redirect,
-        Form,
     };
-    use serde::Deserialize;
-
-    use crate::{config::Task, users::AuthSession};
+    use axum_messages::Messages;
+    use bytesize::ByteSize;
+
+    use crate::{
+        config::{get_cached_config, Task},
+        groups::can_solve,
+        problems::Problem,
+        rate_limit::{check_submission, SubmissionLimitError},
+        repository::{self, SourceFile, MAIN_SOURCE},
+        submission::{self, SourceError},
+        users::AuthSession,
+    };
 
     use super::ServerState;
 
-    #[derive(Debug, Clone, Deserialize)]
-    pub struct SolvePost {
-        answer: String,
+    /// Reads a form field, giving up as soon as it is longer than `max`.
+    /// `None` if the request itself is broken.
+    async fn read_field(
+        field: &mut Field<'_>,
+        max: ByteSize,
+    ) -> Option<Result<Vec<u8>, SourceError>> {
+        let mut bytes = Vec::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    bytes.extend_from_slice(&chunk);
+                    if let Err(e) = submission::check_size(bytes.len(), max) {
+                        return Some(Err(e));
+                    }
+                }
+                Ok(None) => return Some(Ok(bytes)),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// The solve form: the textarea or editor tabs by field name, and the
+    /// uploaded files by file name.
+    #[derive(Default)]
+    struct SolveForm {
+        typed: Vec<(String, Vec<u8>)>,
+        uploads: Vec<(String, Vec<u8>)>,
+    }
+
+    impl SolveForm {
+        /// `None` if the request itself is broken.
+        async fn read(
+            multipart: &mut Multipart,
+            max: ByteSize,
+        ) -> Option<Result<SolveForm, SourceError>> {
+            let mut form = SolveForm::default();
+            loop {
+                let mut field = match multipart.next_field().await {
+                    Ok(Some(field)) => field,
+                    Ok(None) => return Some(Ok(form)),
+                    Err(_) => return None,
+                };
+                let name = field.name().unwrap_or_default().to_string();
+                let filename = field.file_name().unwrap_or_default().to_string();
+                let bytes = match read_field(&mut field, max).await? {
+                    Ok(bytes) => bytes,
+                    Err(e) => return Some(Err(e)),
+                };
+                if name == "file" {
+                    // Browsers send an empty file if none was chosen.
+                    if !bytes.is_empty() {
+                        form.uploads.push((filename, bytes));
+                    }
+                } else {
+                    form.typed.push((name, bytes));
+                }
+            }
+        }
+
+        /// The files to judge, and the name of the uploaded file if there
+        /// was one.
+        fn into_sources(
+            mut self,
+            problem: &Problem,
+            max: ByteSize,
+        ) -> Result<(Vec<SourceFile>, Option<String>), SourceError> {
+            let filename = match self.uploads.as_slice() {
+                [(name, _)] => Some(name.clone()),
+                _ => None,
+            };
+            if problem.has_several_files() {
+                let typed = self
+                    .typed
+                    .into_iter()
+                    .filter_map(|(field, bytes)| {
+                        Some((field.strip_prefix("source.")?.to_string(), bytes))
+                    })
+                    .collect();
+                let files =
+                    submission::source_files(typed, self.uploads, &problem.source_names(), max)?;
+                return Ok((files, filename));
+            }
+            // An uploaded file wins over the textarea.
+            let bytes = match self.uploads.pop() {
+                Some((_, bytes)) => bytes,
+                None => self
+                    .typed
+                    .into_iter()
+                    .find(|(field, _)| field == "answer")
+                    .map(|(_, bytes)| bytes)
+                    .unwrap_or_default(),
+            };
+            let file = SourceFile {
+                name: MAIN_SOURCE.to_string(),
+                content: submission::source_text(bytes, max)?,
+            };
+            Ok((vec![file], filename))
+        }
     }
 
     pub async fn solve(
         auth_session: AuthSession,
+        messages: Messages,
         Path(id): Path<String>,
         State(state): State<ServerState>,
-        Form(form): Form<SolvePost>,
+        mut multipart: Multipart,
     ) -> impl IntoResponse {
         match auth_session.user {
             Some(user) => {
-                sqlx::query!(
-                    "insert into solutions (content,status,userid,problem_id,created_at) values (?,?,?,?,current_timestamp)",
-                    form.answer,
-                    "Pending",
-                    user.id,
-                    id,
-                ).execute(&state.db).await.unwrap();
+                let Ok(problem) = Problem::by_id(&id).await else {
+                    return StatusCode::NOT_FOUND.into_response();
+                };
+                if !can_solve(&state.db, user.id, &id).await.unwrap() {
+                    return StatusCode::NOT_FOUND.into_response();
+                }
+                let config = &get_cached_config().await.unwrap().server;
+                match check_submission(&state.db, &config.rate_limit, user.id).await {
+                    Ok(()) => {}
+                    Err(SubmissionLimitError::Database(_)) => {
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                    }
+                    Err(e) => {
+                        messages.error(e.to_string());
+                        return Redirect::to(&format!("/problem/{}/solve", id)).into_response();
+                    }
+                }
+
+                let Some(form) = SolveForm::read(&mut multipart, config.max_source_size).await
+                else {
+                    return StatusCode::BAD_REQUEST.into_response();
+                };
+                let (files, filename) = match form
+                    .and_then(|form| form.into_sources(&problem, config.max_source_size))
+                {
+                    Ok(sources) => sources,
+                    Err(e) => {
+                        messages.error(e.to_string());
+                        return Redirect::to(&format!("/problem/{}/solve", id)).into_response();
+                    }
+                };
+
+                if problem.has_several_files() {
+                    repository::insert_solution_files(
+                        &state.db,
+                        user.id,
+                        &id,
+                        &files,
+                        filename.as_deref(),
+                    )
+                    .await
+                    .unwrap();
+                } else {
+                    repository::insert_solution(
+                        &state.db,
+                        user.id,
+                        &id,
+                        &files[0].content,
+                        filename.as_deref(),
+                    )
+                    .await
+                    .unwrap();
+                }
                 state.tx.send(Task).await.unwrap();
                 Redirect::to("/problems").into_response()
             }
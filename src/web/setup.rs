@@ -0,0 +1,98 @@
+use askama::Template;
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+    routing::{get, post},
+    Form, Router,
+};
+use axum_messages::{Message, Messages};
+use serde::Deserialize;
+
+use crate::{
+    config::get_cached_config,
+    setup,
+    users::{validate_password, validate_username, AuthSession, RegisterUserError},
+};
+
+#[derive(Template)]
+#[template(path = "setup.html")]
+struct SetupTemplate {
+    messages: Vec<Message>,
+    username: String,
+    valid: bool,
+}
+
+#[derive(Deserialize)]
+pub struct SetupForm {
+    username: String,
+    new_password: String,
+    new_password_confirm: String,
+}
+
+/// The one-time link printed on start while no admin can log in.
+pub fn router() -> Router<()> {
+    Router::new()
+        .route("/setup/:token", get(self::get::setup))
+        .route("/setup/:token", post(self::post::setup))
+}
+
+mod get {
+    use super::*;
+
+    pub async fn setup(messages: Messages, Path(token): Path<String>) -> impl IntoResponse {
+        SetupTemplate {
+            messages: messages.into_iter().collect(),
+            username: String::from("No User"),
+            valid: setup::token_is_valid(&token),
+        }
+    }
+}
+
+mod post {
+    use super::*;
+
+    pub async fn setup(
+        auth_session: AuthSession,
+        messages: Messages,
+        Path(token): Path<String>,
+        Form(form): Form<SetupForm>,
+    ) -> impl IntoResponse {
+        let setup_url = format!("/setup/{}", token);
+        if !setup::token_is_valid(&token) {
+            return Redirect::to(&setup_url).into_response();
+        }
+        if form.new_password != form.new_password_confirm {
+            messages.error("The new passwords do not match");
+            return Redirect::to(&setup_url).into_response();
+        }
+        let min_length = get_cached_config()
+            .await
+            .unwrap()
+            .server
+            .registration
+            .min_password_length;
+        if let Err(e) = validate_username(&form.username)
+            .and_then(|_| validate_password(&form.username, &form.new_password, min_length))
+        {
+            messages.error(e.to_string());
+            return Redirect::to(&setup_url).into_response();
+        }
+        match auth_session
+            .backend
+            .set_up_admin(&form.username, form.new_password, false)
+            .await
+        {
+            Ok(_) => {
+                setup::finish();
+                messages.success(format!("{} is now an admin, please log in", form.username));
+                Redirect::to("/login").into_response()
+            }
+            Err(RegisterUserError::UsernameAlreadyExists) => {
+                messages.error(RegisterUserError::UsernameAlreadyExists.to_string());
+                Redirect::to(&setup_url).into_response()
+            }
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}